@@ -0,0 +1,149 @@
+//! Latency of key-value repository operations at different sizes of the chain,
+//! next to the in-memory repository, whose speed it's meant to approach.
+//!
+//! Sizes are given in transitions and default to 10^5 and 10^6. Larger chains
+//! take a while to build, so they're opt-in:
+//!
+//! ```shell
+//! MARKOV_BENCH_TRANSITIONS=100000,1000000,10000000 cargo bench --bench kv
+//! ```
+
+use std::env;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use markov::adapters::kv::repository::KvRepository;
+use markov::adapters::memory::repository::MemoryRepository;
+use markov::adapters::rand::choose::RandChoose;
+use markov::markov::repository::Repository;
+use markov::markov::types::Link;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const ORDER: usize = 2;
+const DEFAULT_TRANSITIONS: &[usize] = &[100_000, 1_000_000];
+const BATCH_SIZE: usize = 10_000;
+// Successors of a single state, like the ones following "the" in English.
+const HIGH_BRANCHING: usize = 10_000;
+
+fn sizes() -> Vec<usize> {
+    match env::var("MARKOV_BENCH_TRANSITIONS") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| {
+                size.trim()
+                    .parse()
+                    .expect("invalid MARKOV_BENCH_TRANSITIONS")
+            })
+            .collect(),
+        Err(_) => DEFAULT_TRANSITIONS.to_vec(),
+    }
+}
+
+/// Words with a skewed distribution, so that some states are much more
+/// common than others, like in real conversations.
+fn word(rng: &mut StdRng, vocabulary: usize) -> String {
+    let x: f64 = rng.gen();
+    format!("w{}", (x * x * vocabulary as f64) as usize)
+}
+
+fn random_link(rng: &mut StdRng, vocabulary: usize) -> Link<String, ORDER> {
+    let from = [word(rng, vocabulary), word(rng, vocabulary)];
+    Link::new(from, word(rng, vocabulary))
+}
+
+/// Fills a repository up to at least `transitions` distinct transitions and
+/// returns the size of the vocabulary used.
+fn fill<R>(repository: &R, transitions: usize, rng: &mut StdRng) -> usize
+where
+    R: Repository<String, ORDER>,
+{
+    // Large enough for most of the generated links to be distinct.
+    let vocabulary = ((transitions * 16) as f64).cbrt() as usize + 1;
+    loop {
+        let size = repository.size().unwrap();
+        let missing = transitions.saturating_sub(size.transitions as usize);
+        if missing == 0 {
+            return vocabulary;
+        }
+        let batch: Vec<_> = (0..BATCH_SIZE.min(missing))
+            .map(|_| (random_link(rng, vocabulary), 1))
+            .collect();
+        repository.add_weights(&batch).unwrap();
+    }
+}
+
+fn bench_backend<R>(c: &mut Criterion, name: &str, transitions: usize, repository: R)
+where
+    R: Repository<String, ORDER>,
+{
+    // The same chain and queries for every backend.
+    let mut rng = StdRng::seed_from_u64(0);
+    let vocabulary = fill(&repository, transitions, &mut rng);
+    let hub = ["the".to_string(), "the".to_string()];
+    let links: Vec<_> = (0..HIGH_BRANCHING)
+        .map(|i| (Link::new(hub.clone(), format!("w{}", i)), 1))
+        .collect();
+    repository.add_weights(&links).unwrap();
+    let chooser = RandChoose::new();
+    let mut group = c.benchmark_group(name);
+
+    group.bench_function(BenchmarkId::new("get", transitions), |b| {
+        b.iter_batched(
+            || random_link(&mut rng, vocabulary).from,
+            |from| repository.get(&from).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("sample", transitions), |b| {
+        b.iter_batched(
+            || random_link(&mut rng, vocabulary).from,
+            |from| repository.sample(&from, &chooser).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("get_high_branching", transitions), |b| {
+        b.iter(|| repository.get(&hub).unwrap())
+    });
+    group.bench_function(
+        BenchmarkId::new("sample_high_branching", transitions),
+        |b| b.iter(|| repository.sample(&hub, &chooser).unwrap()),
+    );
+    group.bench_function(BenchmarkId::new("random", transitions), |b| {
+        b.iter(|| repository.random().unwrap())
+    });
+    group.bench_function(BenchmarkId::new("random_starting_with", transitions), |b| {
+        b.iter_batched(
+            || word(&mut rng, vocabulary),
+            |word| repository.random_starting_with(&word).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("ingest_message", transitions), |b| {
+        // A typical message of 16 words.
+        b.iter_batched(
+            || {
+                (0..16)
+                    .map(|_| (random_link(&mut rng, vocabulary), 1))
+                    .collect::<Vec<_>>()
+            },
+            |links| repository.add_weights(&links).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench(c: &mut Criterion) {
+    for transitions in sizes() {
+        let memory: MemoryRepository<String, ORDER> = MemoryRepository::new();
+        bench_backend(c, "memory", transitions, memory);
+
+        let dir = tempfile::tempdir().unwrap();
+        let kv = KvRepository::open::<ORDER>(dir.path().join("chain.redb")).unwrap();
+        bench_backend(c, "kv", transitions, kv);
+    }
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
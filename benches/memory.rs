@@ -0,0 +1,135 @@
+//! Memory used by the in-memory backend compared to a plain map of states to
+//! successor maps, i.e. how it stored chains before words were interned.
+//! Memory is measured by counting the bytes held by live allocations, so the
+//! numbers don't depend on the allocator. The cache column is what sampling
+//! every state adds: tables of cumulative weights of high-branching states.
+//!
+//! ```shell
+//! MARKOV_BENCH_TRANSITIONS=10000000 cargo bench --bench memory
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use markov::adapters::memory::repository::MemoryRepository;
+use markov::adapters::rand::choose::RandChoose;
+use markov::markov::repository::Repository;
+use markov::markov::types::Link;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+const ORDER: usize = 2;
+const DEFAULT_TRANSITIONS: &[usize] = &[100_000, 1_000_000];
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn sizes() -> Vec<usize> {
+    match env::var("MARKOV_BENCH_TRANSITIONS") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| {
+                size.trim()
+                    .parse()
+                    .expect("invalid MARKOV_BENCH_TRANSITIONS")
+            })
+            .collect(),
+        Err(_) => DEFAULT_TRANSITIONS.to_vec(),
+    }
+}
+
+/// Words with a skewed distribution and a length typical for English.
+fn word(rng: &mut StdRng, vocabulary: usize) -> String {
+    let x: f64 = rng.gen();
+    format!("word{}", (x * x * vocabulary as f64) as usize)
+}
+
+fn links(transitions: usize) -> Vec<Link<String, ORDER>> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let vocabulary = ((transitions * 16) as f64).cbrt() as usize + 1;
+    (0..transitions)
+        .map(|_| {
+            let from = [word(&mut rng, vocabulary), word(&mut rng, vocabulary)];
+            Link::new(from, word(&mut rng, vocabulary))
+        })
+        .collect()
+}
+
+/// Bytes still allocated after `f` returns, excluding what it freed.
+fn measure<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let value = f();
+    (value, ALLOCATED.load(Ordering::Relaxed) - before)
+}
+
+fn main() {
+    println!(
+        "{:>12} {:>12} {:>12} {:>8} {:>12}",
+        "transitions", "plain", "interned", "ratio", "cache"
+    );
+    // Its generator is allocated on first use, which shouldn't count as cache.
+    thread_rng();
+    let chooser = RandChoose::new();
+    for transitions in sizes() {
+        let links = links(transitions);
+
+        let (plain, plain_bytes) = measure(|| {
+            let mut chain: HashMap<[String; ORDER], HashMap<String, u32>> = HashMap::new();
+            for link in &links {
+                *chain
+                    .entry(link.from.clone())
+                    .or_default()
+                    .entry(link.to.clone())
+                    .or_default() += 1;
+            }
+            chain
+        });
+        drop(plain);
+
+        let (interned, interned_bytes) = measure(|| {
+            let repository: MemoryRepository<String, ORDER> = MemoryRepository::new();
+            for link in &links {
+                repository
+                    .increment_weight(Link::new(link.from.clone(), link.to.clone()))
+                    .unwrap();
+            }
+            repository
+        });
+        let ((), cache_bytes) = measure(|| {
+            for link in &links {
+                interned.sample(&link.from, &chooser).unwrap();
+            }
+        });
+        drop(interned);
+
+        println!(
+            "{:>12} {:>10.1}MB {:>10.1}MB {:>7.1}x {:>10.1}MB",
+            transitions,
+            plain_bytes as f64 / 1e6,
+            interned_bytes as f64 / 1e6,
+            plain_bytes as f64 / interned_bytes as f64,
+            cache_bytes as f64 / 1e6
+        );
+    }
+}
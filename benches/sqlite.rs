@@ -0,0 +1,112 @@
+//! Latency of SQLite repository operations at different sizes of the chain.
+//!
+//! Sizes are given in transitions and default to 10^5 and 10^6. Larger chains
+//! take a while to build, so they're opt-in:
+//!
+//! ```shell
+//! MARKOV_BENCH_TRANSITIONS=100000,1000000,10000000 cargo bench --bench sqlite
+//! ```
+
+use std::env;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use markov::adapters::sqlite::repository::SqliteRepository;
+use markov::markov::repository::Repository;
+use markov::markov::types::Link;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rusqlite::Connection;
+
+const ORDER: usize = 2;
+const DEFAULT_TRANSITIONS: &[usize] = &[100_000, 1_000_000];
+const BATCH_SIZE: usize = 10_000;
+
+fn sizes() -> Vec<usize> {
+    match env::var("MARKOV_BENCH_TRANSITIONS") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| {
+                size.trim()
+                    .parse()
+                    .expect("invalid MARKOV_BENCH_TRANSITIONS")
+            })
+            .collect(),
+        Err(_) => DEFAULT_TRANSITIONS.to_vec(),
+    }
+}
+
+/// Words with a skewed distribution, so that some states are much more
+/// common than others, like in real conversations.
+fn word(rng: &mut StdRng, vocabulary: usize) -> String {
+    let x: f64 = rng.gen();
+    format!("w{}", (x * x * vocabulary as f64) as usize)
+}
+
+fn random_link(rng: &mut StdRng, vocabulary: usize) -> Link<String, ORDER> {
+    let from = [word(rng, vocabulary), word(rng, vocabulary)];
+    Link::new(from, word(rng, vocabulary))
+}
+
+/// Builds a repository with at least `transitions` distinct transitions.
+fn repository(transitions: usize, rng: &mut StdRng) -> (SqliteRepository, usize) {
+    let connection = Connection::open_in_memory().unwrap();
+    let repository = SqliteRepository::new::<ORDER>(connection).unwrap();
+    // Large enough for most of the generated links to be distinct.
+    let vocabulary = ((transitions * 16) as f64).cbrt() as usize + 1;
+    loop {
+        let size = Repository::<String, ORDER>::size(&repository).unwrap();
+        let missing = transitions.saturating_sub(size.transitions as usize);
+        if missing == 0 {
+            return (repository, vocabulary);
+        }
+        let batch: Vec<_> = (0..BATCH_SIZE.min(missing))
+            .map(|_| (random_link(rng, vocabulary), 1))
+            .collect();
+        repository.add_weights(&batch).unwrap();
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    for transitions in sizes() {
+        let (repository, vocabulary) = repository(transitions, &mut rng);
+        let mut group = c.benchmark_group("sqlite");
+
+        group.bench_function(BenchmarkId::new("get", transitions), |b| {
+            b.iter_batched(
+                || random_link(&mut rng, vocabulary).from,
+                |from| Repository::<String, ORDER>::get(&repository, &from).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("random", transitions), |b| {
+            b.iter(|| Repository::<String, ORDER>::random(&repository).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("random_starting_with", transitions), |b| {
+            b.iter_batched(
+                || word(&mut rng, vocabulary),
+                |word| {
+                    Repository::<String, ORDER>::random_starting_with(&repository, &word).unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("ingest_message", transitions), |b| {
+            // A typical message of 16 words.
+            b.iter_batched(
+                || {
+                    (0..16)
+                        .map(|_| (random_link(&mut rng, vocabulary), 1))
+                        .collect::<Vec<_>>()
+                },
+                |links| repository.add_weights(&links).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
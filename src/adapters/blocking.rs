@@ -0,0 +1,115 @@
+//! Moves blocking storage work to tokio's blocking thread pool, so that it
+//! doesn't stall the tasks driving the Discord gateway.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::task;
+
+/// Shared handle to a value used by blocking code, e.g. a synchronous
+/// repository. Clones refer to the same value, which may be used by several
+/// threads at once.
+pub struct Blocking<T> {
+    inner: Arc<T>,
+}
+
+impl<T> Blocking<T>
+where
+    T: Send + Sync + 'static,
+{
+    pub fn new(inner: T) -> Blocking<T> {
+        Blocking {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool and waits for its result without
+    /// blocking the runtime. A panic in `f` is returned as an error.
+    pub async fn run<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&T) -> Result<O> + Send + 'static,
+        O: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        task::spawn_blocking(move || f(&inner)).await?
+    }
+}
+
+impl<T> Clone for Blocking<T> {
+    fn clone(&self) -> Blocking<T> {
+        Blocking {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::Blocking;
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::markov::repository::Repository;
+    use crate::markov::types::Link;
+
+    fn link(from: [&str; 2], to: &str) -> Link<String, 2> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    #[tokio::test]
+    async fn wraps_sync_repository() {
+        let repository = Blocking::new(MemoryRepository::<String, 2>::new());
+        repository
+            .run(|repository| repository.increment_weight(link(["a", "b"], "c")))
+            .await
+            .unwrap();
+        repository
+            .run(|repository| {
+                repository.add_weights(&[(link(["a", "b"], "c"), 2), (link(["b", "c"], "d"), 1)])
+            })
+            .await
+            .unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        let weights = repository
+            .run(move |repository| repository.get(&from))
+            .await
+            .unwrap();
+        assert_eq!(weights["c"], 3);
+        let size = repository
+            .run(|repository| repository.size())
+            .await
+            .unwrap();
+        assert_eq!(size.transitions, 2);
+    }
+
+    // With a single worker thread, a timer can only fire while the blocking
+    // work runs elsewhere.
+    #[tokio::test(flavor = "current_thread")]
+    async fn does_not_block_runtime() {
+        let blocking = Blocking::new(());
+        let work = blocking.run(|_| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        });
+        let timer = time::sleep(Duration::from_millis(10));
+
+        tokio::select! {
+            _ = work => panic!("blocking work finished before the timer"),
+            _ = timer => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_from_panic() {
+        let blocking = Blocking::new(1);
+
+        assert!(blocking
+            .run(|_| -> anyhow::Result<()> { panic!("oops") })
+            .await
+            .is_err());
+        assert_eq!(blocking.run(|value| Ok(*value)).await.unwrap(), 1);
+    }
+}
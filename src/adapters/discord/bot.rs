@@ -1,64 +1,313 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
 use anyhow::Result;
 use serenity::Client;
-use tokio::sync::mpsc;
+use tokio::signal;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{error, info, warn};
 
-use super::command::MessageCommand;
+use super::command::{MessageCommand, Request};
 use super::handler::Handler;
+use super::health::{Health, Status};
+use crate::adapters::blocking::Blocking;
+use crate::adapters::metrics::Metrics;
 use crate::markov::bot::Bot;
 use crate::markov::choose::Choose;
-use crate::markov::repository::Repository;
+use crate::markov::decay::DecayPolicy;
+use crate::markov::repository::{ReadOnly, Repository};
 use crate::markov::shuffle::Shuffle;
+use crate::markov::types::Token;
+
+const SIZE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const STATS_TOP: usize = 5;
+// Messages handled at once. Storage backends serialize writes themselves, so
+// this mostly bounds how many replies are generated in parallel.
+const MAX_CONCURRENT_MESSAGES: usize = 8;
 
 pub struct DiscordBot<'a> {
     token: &'a str,
     verbosity: f64,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    autosave_interval: Option<Duration>,
+    decay: Option<(DecayPolicy, Duration)>,
 }
 
 impl<'a> DiscordBot<'a> {
-    pub fn new(token: &'a str, verbosity: f64) -> DiscordBot<'a> {
-        DiscordBot { token, verbosity }
+    pub fn new(token: &'a str, verbosity: f64, metrics: Arc<Metrics>) -> DiscordBot<'a> {
+        DiscordBot {
+            token,
+            verbosity,
+            health: Arc::new(Health::new()),
+            metrics,
+            autosave_interval: None,
+            decay: None,
+        }
+    }
+
+    /// Periodically flushes the repository while running.
+    pub fn autosave(mut self, interval: Duration) -> DiscordBot<'a> {
+        self.autosave_interval = Some(interval);
+        self
+    }
+
+    /// Periodically decays weights by `policy` while running.
+    pub fn decay(mut self, policy: DecayPolicy, interval: Duration) -> DiscordBot<'a> {
+        self.decay = Some((policy, interval));
+        self
     }
 
     pub async fn run<const N: usize>(
         &self,
         bot: Bot<
-            impl Repository<String, N> + Send + 'static,
-            impl Choose<String> + Send + 'static,
-            impl Shuffle<String> + Send + 'static,
+            String,
+            impl Repository<Token<String>, N> + Send + Sync + 'static,
+            impl Choose<Token<String>> + Send + Sync + 'static,
+            impl Shuffle<String> + Send + Sync + 'static,
             N,
         >,
     ) -> Result<()> {
         let (sender, receiver): (mpsc::Sender<MessageCommand>, mpsc::Receiver<MessageCommand>) =
             mpsc::channel(32);
-        let handler = Handler::new(self.verbosity, sender);
-        let mut client = Client::builder(&self.token).event_handler(handler).await?;
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let handler = Handler::new(
+            self.verbosity,
+            sender,
+            self.health.clone(),
+            self.metrics.clone(),
+        );
+        let mut client = Client::builder(self.token).event_handler(handler).await?;
+        let shard_manager = client.shard_manager.clone();
+
+        let messages = tokio::spawn(handle_messages(
+            Blocking::new(bot),
+            receiver,
+            shutdown_receiver,
+            self.health.clone(),
+            self.metrics.clone(),
+            self.autosave_interval,
+            self.decay,
+        ));
+
+        let result = tokio::select! {
+            result = client.start() => result.map_err(Into::into),
+            result = shutdown_signal() => {
+                info!("Shutting down");
+                shard_manager.lock().await.shutdown_all().await;
+                result
+            }
+        };
+
+        // Dropping the client releases the handler and with it the last sender,
+        // so the message task can drain whatever is still queued and stop.
+        drop(client);
+        let _ = shutdown_sender.send(());
+        let drained = messages.await?;
+
+        result.and(drained)
+    }
+}
+
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+// Storage is accessed by blocking calls, so the bot is only used on the
+// blocking thread pool to keep the gateway responsive. Messages are handled
+// concurrently, e.g. replies for several channels are generated while other
+// messages are being learned.
+async fn handle_messages<R, C, S, const N: usize>(
+    bot: Blocking<Bot<String, R, C, S, N>>,
+    mut receiver: mpsc::Receiver<MessageCommand>,
+    mut shutdown: oneshot::Receiver<()>,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    autosave_interval: Option<Duration>,
+    decay: Option<(DecayPolicy, Duration)>,
+) -> Result<()>
+where
+    R: Repository<Token<String>, N> + Send + Sync + 'static,
+    C: Choose<Token<String>> + Send + Sync + 'static,
+    S: Shuffle<String> + Send + Sync + 'static,
+{
+    let mut closed = false;
+    let mut tasks = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_MESSAGES));
+    let mut size_refresh = time::interval(SIZE_REFRESH_INTERVAL);
+    // The intervals are only polled when autosave and decay are enabled.
+    let mut autosave = time::interval(autosave_interval.unwrap_or(SIZE_REFRESH_INTERVAL));
+    let mut decay_timer = time::interval(decay.map_or(SIZE_REFRESH_INTERVAL, |(_, every)| every));
+    // The first tick of an interval completes immediately.
+    autosave.tick().await;
+    decay_timer.tick().await;
 
-        tokio::spawn(handle_messages(bot, receiver));
-        client.start().await.map_err(Into::into)
+    loop {
+        let cmd = tokio::select! {
+            cmd = receiver.recv() => cmd,
+            Some(result) = tasks.join_next() => {
+                if let Err(e) = result {
+                    error!(error = %e, "Message task failed");
+                }
+                continue;
+            }
+            _ = size_refresh.tick(), if metrics.is_enabled() => {
+                match bot.run(|bot| bot.size()).await {
+                    Ok(size) => metrics.set_repository_size(size),
+                    Err(e) => {
+                        metrics.storage_error("size");
+                        warn!(error = %format!("{:#}", e), "Failed to read repository size");
+                    }
+                }
+                continue;
+            }
+            _ = autosave.tick(), if autosave_interval.is_some() => {
+                if let Err(e) = bot.run(|bot| bot.flush()).await {
+                    metrics.storage_error("flush");
+                    error!(error = %format!("{:#}", e), "Failed to save repository");
+                }
+                continue;
+            }
+            _ = decay_timer.tick(), if decay.is_some() => {
+                let (policy, _) = decay.unwrap();
+                match bot.run(move |bot| bot.decay(&policy, SystemTime::now())).await {
+                    Ok(decayed) => info!(
+                        decayed = decayed.decayed,
+                        removed = decayed.removed,
+                        "Decayed weights"
+                    ),
+                    Err(e) => {
+                        metrics.storage_error("decay");
+                        error!(error = %format!("{:#}", e), "Failed to decay weights");
+                    }
+                }
+                continue;
+            }
+            _ = &mut shutdown, if !closed => {
+                // Stop accepting new commands, but keep processing the buffered ones.
+                receiver.close();
+                closed = true;
+                info!("Draining message queue");
+                continue;
+            }
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => break,
+        };
+        metrics.message_dequeued();
+
+        // Waiting for a permit stops dequeuing, so the queue fills up and
+        // applies backpressure to the gateway.
+        let permit = permits.clone().acquire_owned().await?;
+        let span = cmd.span;
+        let (bot, health, metrics) = (bot.clone(), health.clone(), metrics.clone());
+        tasks.spawn(async move {
+            let reply = bot
+                .run(move |bot| {
+                    let _span = span.enter();
+                    Ok(match cmd.request {
+                        Request::Message {
+                            content,
+                            should_reply,
+                        } => learn_and_reply(bot, &content, should_reply, &health, &metrics),
+                        Request::Stats => stats(bot),
+                    })
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    error!(error = %format!("{:#}", e), "Failed to handle message");
+                    None
+                });
+            drop(permit);
+            let _ = cmd.sender.send(reply);
+        });
     }
+
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result {
+            error!(error = %e, "Message task failed");
+        }
+    }
+    bot.run(|bot| bot.flush()).await
 }
 
-async fn handle_messages<const N: usize>(
-    mut bot: Bot<
-        impl Repository<String, N> + Send,
-        impl Choose<String> + Send,
-        impl Shuffle<String> + Send,
+fn learn_and_reply<const N: usize>(
+    bot: &Bot<
+        String,
+        impl Repository<Token<String>, N>,
+        impl Choose<Token<String>>,
+        impl Shuffle<String>,
         N,
     >,
-    mut receiver: mpsc::Receiver<MessageCommand>,
-) -> Result<()> {
-    while let Some(cmd) = receiver.recv().await {
-        let content = &cmd.content;
+    content: &str,
+    should_reply: bool,
+    health: &Health,
+    metrics: &Metrics,
+) -> Option<String> {
+    // A failure to learn must not stop the task, otherwise every later
+    // message would be silently dropped.
+    let started = Instant::now();
+    match bot.learn(content) {
+        Ok(()) => {
+            metrics.message_learned(started.elapsed());
+            health.record_success();
+        }
+        // Frozen chains are served as they were compiled, whatever context
+        // the error is wrapped in.
+        Err(e) if e.chain().any(|cause| cause.is::<ReadOnly>()) => {}
+        Err(e) => {
+            metrics.storage_error("learn");
+            health.record_failure(&e);
+        }
+    }
+    metrics.set_healthy(health.status() != Status::Unhealthy);
 
-        bot.learn(content)?;
+    if !should_reply {
+        return None;
+    }
 
-        if cmd.should_reply {
-            let reply = bot.reply(content);
-            if let Ok(reply) = reply {
-                let _ = cmd.sender.send(Some(reply));
-            }
+    let started = Instant::now();
+    match bot.reply(content) {
+        Ok(reply) => {
+            metrics.reply_generated(started.elapsed());
+            Some(reply)
+        }
+        Err(e) => {
+            metrics.storage_error("reply");
+            error!(error = %format!("{:#}", e), "Failed to build reply");
+            None
         }
     }
+}
 
-    Ok(())
+fn stats<const N: usize>(
+    bot: &Bot<
+        String,
+        impl Repository<Token<String>, N>,
+        impl Choose<Token<String>>,
+        impl Shuffle<String>,
+        N,
+    >,
+) -> Option<String> {
+    match bot.stats(STATS_TOP) {
+        Ok(stats) => Some(format!("```\n{}```", stats)),
+        Err(e) => {
+            error!(error = %format!("{:#}", e), "Failed to compute stats");
+            None
+        }
+    }
 }
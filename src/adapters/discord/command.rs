@@ -1,7 +1,15 @@
 use tokio::sync::oneshot;
+use tracing::Span;
+
+pub const STATS_COMMAND: &str = "!stats";
+
+pub enum Request {
+    Message { content: String, should_reply: bool },
+    Stats,
+}
 
 pub struct MessageCommand {
-    pub content: String,
-    pub should_reply: bool,
+    pub request: Request,
     pub sender: oneshot::Sender<Option<String>>,
+    pub span: Span,
 }
@@ -1,22 +1,43 @@
+use std::sync::Arc;
+
 use rand::{thread_rng, Rng};
 use serenity::async_trait;
 use serenity::client::{Context, EventHandler};
 use serenity::model::channel::Message;
 use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info_span, warn, Instrument, Span};
 
-use crate::adapters::discord::command::MessageCommand;
+use crate::adapters::discord::command::{MessageCommand, Request, STATS_COMMAND};
+use crate::adapters::discord::health::{Health, Status};
+use crate::adapters::metrics::Metrics;
 
 pub struct Handler {
     verbosity: f64,
     sender: mpsc::Sender<MessageCommand>,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
 }
 
 impl Handler {
-    pub fn new(verbosity: f64, sender: mpsc::Sender<MessageCommand>) -> Handler {
-        Handler { verbosity, sender }
+    pub fn new(
+        verbosity: f64,
+        sender: mpsc::Sender<MessageCommand>,
+        health: Arc<Health>,
+        metrics: Arc<Metrics>,
+    ) -> Handler {
+        Handler {
+            verbosity,
+            sender,
+            health,
+            metrics,
+        }
     }
 
     async fn should_reply(&self, ctx: &Context, msg: &Message) -> bool {
+        // Keep learning, but don't make users wait for replies from a failing backend.
+        if self.health.status() == Status::Unhealthy {
+            return false;
+        }
         if msg.mentions_user_id(ctx.cache.current_user_id().await) {
             return true;
         }
@@ -24,15 +45,8 @@ impl Handler {
         let random: f64 = rng.gen();
         random < self.verbosity
     }
-}
-
-#[async_trait]
-impl EventHandler for Handler {
-    async fn message(&self, ctx: Context, msg: Message) {
-        if msg.author.id == ctx.cache.current_user_id().await {
-            return;
-        }
 
+    async fn handle(&self, ctx: Context, msg: Message) {
         let (reply_sender, reply_receiver) = oneshot::channel();
         let should_reply = self.should_reply(&ctx, &msg).await;
         let content = msg
@@ -40,20 +54,60 @@ impl EventHandler for Handler {
             .strip_prefix(&format!("<@!{}>", &ctx.cache.current_user_id().await))
             .map(str::to_string)
             .unwrap_or(msg.content);
+        let (request, should_reply) = if content.trim() == STATS_COMMAND {
+            (Request::Stats, true)
+        } else {
+            let request = Request::Message {
+                content,
+                should_reply,
+            };
+            (request, should_reply)
+        };
         let command = MessageCommand {
-            content,
-            should_reply,
+            request,
             sender: reply_sender,
+            span: Span::current(),
         };
 
-        let _ = self.sender.send(command).await;
+        self.metrics.message_queued();
+        if self.sender.send(command).await.is_err() {
+            self.metrics.message_dequeued();
+            warn!("Message queue is closed, dropping message");
+            return;
+        }
 
         if should_reply {
-            let reply = reply_receiver.await.unwrap();
+            let reply = match reply_receiver.await {
+                Ok(reply) => reply,
+                Err(_) => {
+                    warn!("Message task stopped before replying");
+                    return;
+                }
+            };
 
             if let Some(reply) = reply {
-                msg.channel_id.say(&ctx.http, reply).await.unwrap();
+                match msg.channel_id.say(&ctx.http, reply).await {
+                    Ok(_) => self.metrics.reply_sent(),
+                    Err(e) => error!(error = %e, "Failed to send reply"),
+                }
             }
         }
     }
 }
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn message(&self, ctx: Context, msg: Message) {
+        if msg.author.id == ctx.cache.current_user_id().await {
+            return;
+        }
+
+        let span = info_span!(
+            "message",
+            id = %msg.id,
+            channel = %msg.channel_id,
+            author = %msg.author.id,
+        );
+        self.handle(ctx, msg).instrument(span).await
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use tracing::{error, info, warn};
+
+const UNHEALTHY_THRESHOLD: u32 = 5;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// Tracks consecutive failures of the message processing task.
+#[derive(Default)]
+pub struct Health {
+    consecutive_failures: AtomicU32,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            consecutive_failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self.consecutive_failures.load(Ordering::Relaxed) {
+            0 => Status::Healthy,
+            n if n < UNHEALTHY_THRESHOLD => Status::Degraded,
+            _ => Status::Unhealthy,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let failures = self.consecutive_failures.swap(0, Ordering::Relaxed);
+        if failures > 0 {
+            info!(failures, "Recovered after consecutive failures");
+        }
+    }
+
+    pub fn record_failure(&self, error: &anyhow::Error) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(failures, error = %format!("{:#}", error), "Failed to process message");
+        if failures == UNHEALTHY_THRESHOLD {
+            error!(failures, "Marking bot as unhealthy");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::{Health, Status, UNHEALTHY_THRESHOLD};
+
+    #[test]
+    fn starts_healthy() {
+        assert_eq!(Health::new().status(), Status::Healthy);
+    }
+
+    #[test]
+    fn degrades_and_recovers() {
+        let health = Health::new();
+        health.record_failure(&anyhow!("busy"));
+
+        assert_eq!(health.status(), Status::Degraded);
+
+        health.record_success();
+
+        assert_eq!(health.status(), Status::Healthy);
+    }
+
+    #[test]
+    fn becomes_unhealthy_after_threshold() {
+        let health = Health::new();
+        for _ in 0..UNHEALTHY_THRESHOLD {
+            health.record_failure(&anyhow!("busy"));
+        }
+
+        assert_eq!(health.status(), Status::Unhealthy);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod bot;
 mod command;
 mod handler;
+pub mod health;
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::{info, instrument};
+
+use super::layout::Header;
+use crate::markov::repository::Repository;
+use crate::markov::tokenizer::Tokenizer;
+use crate::markov::types::Size;
+
+/// Writes every transition of `repository` to a frozen chain at `path`,
+/// returning its size. The whole chain is held in memory while compiling.
+///
+/// The file is written next to `path` and moved into place, so a chain being
+/// served from `path` is replaced instead of modified.
+#[instrument(skip_all, fields(path = %path.as_ref().display()))]
+pub fn compile<R, const N: usize>(
+    repository: &R,
+    tokenizer: Tokenizer,
+    path: impl AsRef<Path>,
+) -> Result<Size>
+where
+    R: Repository<String, N> + ?Sized,
+{
+    let path = path.as_ref();
+    // Words get ids in the order they're seen first, and are renumbered once
+    // all of them are known.
+    let mut ids: HashMap<String, u32> = HashMap::new();
+    let mut intern = |word: String| -> Result<u32> {
+        let next = u32::try_from(ids.len()).context("Too many distinct words")?;
+        Ok(*ids.entry(word).or_insert(next))
+    };
+    let mut transitions = Vec::new();
+    repository.for_each_transition(&mut |link, weight| {
+        let mut from = [0; N];
+        for (id, word) in from.iter_mut().zip(link.from) {
+            *id = intern(word)?;
+        }
+        transitions.push((from, intern(link.to)?, weight));
+        Ok(())
+    })?;
+
+    let mut words: Vec<(String, u32)> = ids.into_iter().collect();
+    words.sort_unstable();
+    let mut renumbered = vec![0; words.len()];
+    for (id, (_, first_seen)) in words.iter().enumerate() {
+        renumbered[*first_seen as usize] = id as u32;
+    }
+    for (from, to, _) in &mut transitions {
+        *from = from.map(|id| renumbered[id as usize]);
+        *to = renumbered[*to as usize];
+    }
+    transitions.sort_unstable_by_key(|&(from, to, _)| (from, to));
+    transitions.dedup_by(|next, previous| {
+        let duplicate = (next.0, next.1) == (previous.0, previous.1);
+        if duplicate {
+            previous.2 = previous.2.saturating_add(next.2);
+        }
+        duplicate
+    });
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", Path::new(&tmp_path).display()))?;
+    let mut writer = BufWriter::new(file);
+    let size = write(&mut writer, tokenizer, &words, &transitions)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    info!(
+        words = size.words,
+        states = size.states,
+        transitions = size.transitions,
+        "Compiled chain"
+    );
+    Ok(size)
+}
+
+fn write<const N: usize>(
+    writer: &mut impl Write,
+    tokenizer: Tokenizer,
+    words: &[(String, u32)],
+    transitions: &[([u32; N], u32, u32)],
+) -> Result<Size> {
+    let mut states: Vec<([u32; N], u64)> = Vec::new();
+    for (i, (from, _, _)) in transitions.iter().enumerate() {
+        match states.last_mut() {
+            Some((last, end)) if last == from => *end = i as u64 + 1,
+            _ => states.push((*from, i as u64 + 1)),
+        }
+    }
+    let strings: usize = words.iter().map(|(word, _)| word.len()).sum();
+    let header = Header {
+        order: N as u32,
+        tokenizer: tokenizer.name().len() as u32,
+        words: words.len() as u32,
+        states: states.len() as u64,
+        transitions: transitions.len() as u64,
+        strings: strings as u64,
+    };
+    header.write(writer)?;
+    writer.write_all(tokenizer.name().as_bytes())?;
+
+    let mut end = 0u64;
+    for (word, _) in words {
+        end += word.len() as u64;
+        writer.write_all(&end.to_le_bytes())?;
+    }
+    for (word, _) in words {
+        writer.write_all(word.as_bytes())?;
+    }
+    for (from, _) in &states {
+        for id in from {
+            writer.write_all(&id.to_le_bytes())?;
+        }
+    }
+    for (_, end) in &states {
+        writer.write_all(&end.to_le_bytes())?;
+    }
+    for (_, to, _) in transitions {
+        writer.write_all(&to.to_le_bytes())?;
+    }
+    let mut cumulative = 0u64;
+    for (i, (_, _, weight)) in transitions.iter().enumerate() {
+        if i == 0 || transitions[i - 1].0 != transitions[i].0 {
+            cumulative = 0;
+        }
+        cumulative += u64::from(*weight);
+        writer.write_all(&cumulative.to_le_bytes())?;
+    }
+
+    Ok(Size {
+        words: header.words.into(),
+        states: header.states,
+        transitions: header.transitions,
+    })
+}
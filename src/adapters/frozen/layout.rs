@@ -0,0 +1,143 @@
+//! Layout of a frozen chain file. It starts with a header:
+//!
+//! | offset | size | content                              |
+//! |--------|------|--------------------------------------|
+//! | 0      | 8    | magic bytes `MKVFROZN`               |
+//! | 8      | 4    | format version                       |
+//! | 12     | 4    | order of the chain                   |
+//! | 16     | 4    | length of the tokenizer name         |
+//! | 20     | 4    | number of words                      |
+//! | 24     | 8    | number of states                     |
+//! | 32     | 8    | number of transitions                |
+//! | 40     | 8    | length of the string table           |
+//!
+//! followed by these sections, with no padding in between:
+//!
+//! 1. name of the tokenizer,
+//! 2. end offset of every word in the string table, as u64,
+//! 3. string table of words sorted by their UTF-8 bytes, so a word's id is its
+//!    index,
+//! 4. states as the ids of their words, sorted, so the states starting with a
+//!    word are next to each other,
+//! 5. end index of the successors of every state, as u64,
+//! 6. ids of successors, sorted within every state,
+//! 7. cumulative weights of successors within every state, as u64, so that a
+//!    successor can be sampled by binary search.
+//!
+//! All integers are little-endian. Sizes of the sections follow from the
+//! header, so opening a file doesn't read anything but the header.
+
+use std::io::{self, Write};
+use std::ops::Range;
+
+use anyhow::{ensure, Context, Result};
+
+pub const MAGIC: &[u8; 8] = b"MKVFROZN";
+pub const VERSION: u32 = 1;
+pub const HEADER_LEN: usize = 48;
+
+pub struct Header {
+    pub order: u32,
+    pub tokenizer: u32,
+    pub words: u32,
+    pub states: u64,
+    pub transitions: u64,
+    pub strings: u64,
+}
+
+impl Header {
+    pub fn read(bytes: &[u8]) -> Result<Header> {
+        ensure!(
+            bytes.len() >= HEADER_LEN && &bytes[..8] == MAGIC,
+            "Not a frozen chain"
+        );
+        let version = u32_at(bytes, 8);
+        ensure!(
+            version == VERSION,
+            "Unsupported frozen chain version {}",
+            version
+        );
+        Ok(Header {
+            order: u32_at(bytes, 12),
+            tokenizer: u32_at(bytes, 16),
+            words: u32_at(bytes, 20),
+            states: u64_at(bytes, 24),
+            transitions: u64_at(bytes, 32),
+            strings: u64_at(bytes, 40),
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.order.to_le_bytes())?;
+        writer.write_all(&self.tokenizer.to_le_bytes())?;
+        writer.write_all(&self.words.to_le_bytes())?;
+        writer.write_all(&self.states.to_le_bytes())?;
+        writer.write_all(&self.transitions.to_le_bytes())?;
+        writer.write_all(&self.strings.to_le_bytes())
+    }
+}
+
+/// Byte ranges of the sections of a file.
+pub struct Layout {
+    pub order: usize,
+    pub words: usize,
+    pub states: usize,
+    pub transitions: usize,
+    pub tokenizer: Range<usize>,
+    pub word_ends: Range<usize>,
+    pub strings: Range<usize>,
+    pub state_ids: Range<usize>,
+    pub successor_ends: Range<usize>,
+    pub successors: Range<usize>,
+    pub cumulative: Range<usize>,
+}
+
+impl Layout {
+    pub fn new(header: &Header) -> Result<Layout> {
+        let count = |value: u64| usize::try_from(value).context("Frozen chain is too large");
+        let order = header.order as usize;
+        let words = header.words as usize;
+        let states = count(header.states)?;
+        let transitions = count(header.transitions)?;
+
+        let mut end = HEADER_LEN;
+        let mut section = |items: usize, size: usize| -> Result<Range<usize>> {
+            let start = end;
+            end = items
+                .checked_mul(size)
+                .and_then(|len| start.checked_add(len))
+                .context("Frozen chain is too large")?;
+            Ok(start..end)
+        };
+        Ok(Layout {
+            order,
+            words,
+            states,
+            transitions,
+            tokenizer: section(header.tokenizer as usize, 1)?,
+            word_ends: section(words, 8)?,
+            strings: section(count(header.strings)?, 1)?,
+            state_ids: section(states, order.checked_mul(4).context("Invalid order")?)?,
+            successor_ends: section(states, 8)?,
+            successors: section(transitions, 4)?,
+            cumulative: section(transitions, 8)?,
+        })
+    }
+
+    /// Length of the whole file.
+    pub fn file_len(&self) -> usize {
+        self.cumulative.end
+    }
+}
+
+/// Reads the little-endian u32 at byte `offset`.
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the little-endian u64 at byte `offset`.
+pub fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
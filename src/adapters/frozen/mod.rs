@@ -0,0 +1,3 @@
+pub mod compile;
+mod layout;
+pub mod repository;
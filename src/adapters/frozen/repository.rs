@@ -0,0 +1,596 @@
+use std::array;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+use std::time::SystemTime;
+
+use anyhow::{bail, ensure, Context, Result};
+use memmap2::Mmap;
+use rand::{thread_rng, Rng};
+use tracing::{info, instrument};
+
+use super::layout::{u32_at, u64_at, Header, Layout};
+use crate::markov::choose::Choose;
+use crate::markov::decay::{DecayPolicy, Decayed};
+use crate::markov::repository::{ReadOnly, Repository};
+use crate::markov::stats::{self, StateStats, Stats};
+use crate::markov::tokenizer::Tokenizer;
+use crate::markov::types::{Link, Size, WeightMap};
+
+const CORRUPTED: &str = "Frozen chain is corrupted";
+
+/// Read-only repository serving a chain written by
+/// [`compile`](super::compile::compile) straight from a memory-mapped file.
+/// Opening it only reads the header, and lookups are binary searches over the
+/// mapped tables, so large chains are served without loading them first.
+///
+/// Writes fail with [`ReadOnly`].
+pub struct FrozenRepository {
+    mmap: Mmap,
+    layout: Layout,
+}
+
+impl FrozenRepository {
+    /// Maps the chain at `path` and checks that it was compiled with order
+    /// `N` and `tokenizer`.
+    #[instrument(skip_all, fields(path = %path.as_ref().display()))]
+    pub fn open<const N: usize>(
+        path: impl AsRef<Path>,
+        tokenizer: Tokenizer,
+    ) -> Result<FrozenRepository> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        // SAFETY: The file must not change while it's mapped. Chains are
+        // compiled to a new file which is then moved into place, so even
+        // recompiling the served chain leaves the mapped file untouched.
+        let mmap = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to map {}", path.display()))?;
+        let repository = FrozenRepository::new::<N>(mmap, tokenizer)
+            .with_context(|| format!("Failed to open frozen chain {}", path.display()))?;
+        info!(states = repository.layout.states, "Opened frozen chain");
+        Ok(repository)
+    }
+
+    fn new<const N: usize>(mmap: Mmap, tokenizer: Tokenizer) -> Result<FrozenRepository> {
+        let header = Header::read(&mmap)?;
+        let layout = Layout::new(&header)?;
+        ensure!(mmap.len() == layout.file_len(), CORRUPTED);
+        ensure!(
+            layout.order == N,
+            "Chain was compiled with order {}, but order {} is used",
+            layout.order,
+            N
+        );
+        let name = str::from_utf8(&mmap[layout.tokenizer.clone()]).context(CORRUPTED)?;
+        if name != tokenizer.name() {
+            bail!(
+                "Chain was compiled with {} tokenizer, but {} is used",
+                name,
+                tokenizer.name()
+            );
+        }
+        Ok(FrozenRepository { mmap, layout })
+    }
+
+    fn word(&self, id: u32) -> Result<&str> {
+        let id = id as usize;
+        ensure!(id < self.layout.words, CORRUPTED);
+        let end = |id: usize| u64_at(&self.mmap, self.layout.word_ends.start + id * 8) as usize;
+        let start = if id == 0 { 0 } else { end(id - 1) };
+        let bytes = self.mmap[self.layout.strings.clone()]
+            .get(start..end(id))
+            .context(CORRUPTED)?;
+        str::from_utf8(bytes).context(CORRUPTED)
+    }
+
+    fn word_id(&self, word: &str) -> Result<Option<u32>> {
+        let (mut low, mut high) = (0, self.layout.words);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.word(mid as u32)?.cmp(word) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Some(mid as u32)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn words<const N: usize>(&self, ids: [u32; N]) -> Result<[String; N]> {
+        let mut words: [String; N] = array::from_fn(|_| String::new());
+        for (word, id) in words.iter_mut().zip(ids) {
+            *word = self.word(id)?.to_string();
+        }
+        Ok(words)
+    }
+
+    fn state<const N: usize>(&self, index: usize) -> [u32; N] {
+        let start = self.layout.state_ids.start + index * N * 4;
+        array::from_fn(|i| u32_at(&self.mmap, start + i * 4))
+    }
+
+    /// Index of the state made of `from`, if there is one.
+    fn state_index<const N: usize>(&self, from: &[String; N]) -> Result<Option<usize>> {
+        let mut ids = [0; N];
+        for (id, word) in ids.iter_mut().zip(from) {
+            match self.word_id(word)? {
+                Some(word) => *id = word,
+                None => return Ok(None),
+            }
+        }
+        let index = partition_point(self.layout.states, |i| self.state::<N>(i) < ids);
+        Ok(Some(index).filter(|&i| i < self.layout.states && self.state::<N>(i) == ids))
+    }
+
+    /// Indices of the successors of the state at `index`.
+    fn successors(&self, index: usize) -> Result<Range<usize>> {
+        let end = |index: usize| {
+            u64_at(&self.mmap, self.layout.successor_ends.start + index * 8) as usize
+        };
+        let start = if index == 0 { 0 } else { end(index - 1) };
+        let successors = start..end(index);
+        ensure!(
+            successors.start <= successors.end && successors.end <= self.layout.transitions,
+            CORRUPTED
+        );
+        Ok(successors)
+    }
+
+    fn successor(&self, index: usize) -> u32 {
+        u32_at(&self.mmap, self.layout.successors.start + index * 4)
+    }
+
+    fn cumulative(&self, index: usize) -> u64 {
+        u64_at(&self.mmap, self.layout.cumulative.start + index * 8)
+    }
+
+    /// Calls `f` with the id and weight of every successor of the state at
+    /// `index`.
+    fn for_each_successor(
+        &self,
+        index: usize,
+        mut f: impl FnMut(u32, u32) -> Result<()>,
+    ) -> Result<()> {
+        let mut previous = 0;
+        for i in self.successors(index)? {
+            let cumulative = self.cumulative(i);
+            let weight = cumulative
+                .checked_sub(previous)
+                .and_then(|weight| u32::try_from(weight).ok())
+                .context(CORRUPTED)?;
+            previous = cumulative;
+            f(self.successor(i), weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the first of `len` items for which `pred` is false, given that it
+/// is true for all items before it and false for all after.
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+impl<const N: usize> Repository<String, N> for FrozenRepository {
+    #[instrument(level = "trace", skip_all)]
+    fn get(&self, from: &[String; N]) -> Result<WeightMap<String>> {
+        let mut weights = WeightMap::new();
+        if let Some(index) = self.state_index(from)? {
+            self.for_each_successor(index, |id, weight| {
+                weights.insert(self.word(id)?.to_string(), weight);
+                Ok(())
+            })?;
+        }
+        Ok(weights)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn sample(&self, from: &[String; N], chooser: &dyn Choose<String>) -> Result<Option<String>> {
+        let index = match self.state_index(from)? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let successors = self.successors(index)?;
+        if successors.is_empty() {
+            return Ok(None);
+        }
+        // Weights are drawn as u32, so larger totals are cut off.
+        let total = self.cumulative(successors.end - 1);
+        let total = u32::try_from(total).unwrap_or(u32::MAX);
+        if total == 0 {
+            return Ok(None);
+        }
+        let position = u64::from(chooser.generate_random(total));
+        let i = successors.start
+            + partition_point(successors.len(), |i| {
+                self.cumulative(successors.start + i) <= position
+            });
+        ensure!(i < successors.end, CORRUPTED);
+        Ok(Some(self.word(self.successor(i))?.to_string()))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random(&self) -> Result<Option<[String; N]>> {
+        if self.layout.states == 0 {
+            return Ok(None);
+        }
+        let index = thread_rng().gen_range(0..self.layout.states);
+        Ok(Some(self.words(self.state(index))?))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random_starting_with(&self, state: &String) -> Result<Option<[String; N]>> {
+        let id = match self.word_id(state)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        // States are sorted, so the ones starting with the word are next to
+        // each other.
+        let first = |i| self.state::<N>(i)[0];
+        let start = partition_point(self.layout.states, |i| first(i) < id);
+        let end = partition_point(self.layout.states, |i| first(i) <= id);
+        if start == end {
+            return Ok(None);
+        }
+        let index = thread_rng().gen_range(start..end);
+        Ok(Some(self.words(self.state(index))?))
+    }
+
+    fn add_weight(&self, _link: Link<String, N>, _weight: u32) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn add_weights(&self, _links: &[(Link<String, N>, u32)]) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn add_all_weights(
+        &self,
+        _links: &mut dyn Iterator<Item = Result<(Link<String, N>, u32)>>,
+    ) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn decay(&self, _policy: &DecayPolicy, _now: SystemTime) -> Result<Decayed> {
+        Err(ReadOnly.into())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn size(&self) -> Result<Size> {
+        Ok(Size {
+            words: self.layout.words as u64,
+            states: self.layout.states as u64,
+            transitions: self.layout.transitions as u64,
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn stats(&self, top: usize) -> Result<Stats<String, N>> {
+        let mut word_weights = vec![0u64; self.layout.words];
+        let mut states = Vec::with_capacity(self.layout.states);
+        let mut total_weight = 0;
+        let mut weighted_entropy = 0.0;
+
+        for index in 0..self.layout.states {
+            let mut weights = Vec::new();
+            self.for_each_successor(index, |id, weight| {
+                *word_weights.get_mut(id as usize).context(CORRUPTED)? += u64::from(weight);
+                weights.push(weight);
+                Ok(())
+            })?;
+            let weight: u64 = weights.iter().copied().map(u64::from).sum();
+            let entropy = stats::entropy(weights);
+            total_weight += weight;
+            weighted_entropy += weight as f64 * entropy;
+            states.push((index, weight, entropy));
+        }
+
+        // Words only ever seen in states aren't counted, like in other backends.
+        let word_weights = word_weights
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, weight)| weight > 0);
+        let top_words = stats::top(word_weights, top, |(_, weight)| *weight)
+            .into_iter()
+            .map(|(id, weight)| Ok((self.word(id as u32)?.to_string(), weight)))
+            .collect::<Result<_>>()?;
+        let top_states = stats::top(states, top, |(_, weight, _)| *weight)
+            .into_iter()
+            .map(|(index, weight, entropy)| {
+                Ok(StateStats {
+                    state: self.words(self.state(index))?,
+                    weight,
+                    entropy,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Stats {
+            size: Repository::<String, N>::size(self)?,
+            total_weight,
+            top_words,
+            top_states,
+            mean_entropy: if total_weight > 0 {
+                weighted_entropy / total_weight as f64
+            } else {
+                0.0
+            },
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn for_each_transition(
+        &self,
+        f: &mut dyn FnMut(Link<String, N>, u32) -> Result<()>,
+    ) -> Result<()> {
+        for index in 0..self.layout.states {
+            let from: [String; N] = self.words(self.state(index))?;
+            self.for_each_successor(index, |id, weight| {
+                f(Link::new(from.clone(), self.word(id)?.to_string()), weight)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::fs;
+
+    use tempfile::{tempdir, TempDir};
+
+    use super::FrozenRepository;
+    use crate::adapters::frozen::compile::compile;
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::markov::choose::Choose;
+    use crate::markov::repository::{ReadOnly, Repository};
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::{Link, Size, WeightMap};
+
+    fn state(words: [&str; 2]) -> [String; 2] {
+        words.map(str::to_string)
+    }
+
+    fn link(from: [&str; 2], to: &str) -> Link<String, 2> {
+        Link::new(state(from), to.to_string())
+    }
+
+    fn weights(weights: &[(&str, u32)]) -> WeightMap<String> {
+        weights
+            .iter()
+            .map(|&(word, weight)| (word.to_string(), weight))
+            .collect()
+    }
+
+    fn memory() -> MemoryRepository<String, 2> {
+        let repository = MemoryRepository::new();
+        repository.add_weight(link(["a", "b"], "c"), 3).unwrap();
+        repository.add_weight(link(["a", "b"], "a"), 1).unwrap();
+        repository.add_weight(link(["a", "c"], "b"), 2).unwrap();
+        repository
+            .add_weight(link(["b", "c"], "zażółć"), 7)
+            .unwrap();
+        repository.add_weight(link(["", "\0"], "c"), 1).unwrap();
+        repository
+    }
+
+    fn frozen(source: &MemoryRepository<String, 2>) -> (TempDir, FrozenRepository) {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.frozen");
+        compile::<_, 2>(source, Tokenizer::Whitespace, &path).unwrap();
+        let repository = FrozenRepository::open::<2>(&path, Tokenizer::Whitespace).unwrap();
+        (dir, repository)
+    }
+
+    fn transitions(repository: &impl Repository<String, 2>) -> HashSet<([String; 2], String, u32)> {
+        let mut transitions = HashSet::new();
+        repository
+            .for_each_transition(&mut |link, weight| {
+                assert!(transitions.insert((link.from, link.to, weight)));
+                Ok(())
+            })
+            .unwrap();
+        transitions
+    }
+
+    // Draws 0, 1, 2 and so on.
+    #[derive(Default)]
+    struct Sequence(Cell<u32>);
+
+    impl<T> Choose<T> for Sequence {
+        fn generate_random(&self, upper_bound: u32) -> u32 {
+            let i = self.0.get();
+            self.0.set(i + 1);
+            i % upper_bound
+        }
+    }
+
+    #[test]
+    fn serves_compiled_chain() {
+        let source = memory();
+        let (_dir, repository) = frozen(&source);
+
+        assert_eq!(transitions(&repository), transitions(&source));
+        assert_eq!(
+            Repository::<String, 2>::size(&repository).unwrap(),
+            Size {
+                words: 6,
+                states: 4,
+                transitions: 5,
+            }
+        );
+        assert_eq!(
+            repository.get(&state(["a", "b"])).unwrap(),
+            weights(&[("c", 3), ("a", 1)])
+        );
+        assert_eq!(
+            repository.get(&state(["", "\0"])).unwrap(),
+            weights(&[("c", 1)])
+        );
+        assert_eq!(
+            repository.get(&state(["b", "a"])).unwrap(),
+            WeightMap::new()
+        );
+        assert_eq!(
+            repository.get(&state(["x", "a"])).unwrap(),
+            WeightMap::new()
+        );
+    }
+
+    #[test]
+    fn samples_successors_by_weight() {
+        let (_dir, repository) = frozen(&memory());
+        let chooser = Sequence::default();
+
+        let mut sampled = WeightMap::new();
+        for _ in 0..4 {
+            let word = repository.sample(&state(["a", "b"]), &chooser).unwrap();
+            *sampled.entry(word.unwrap()).or_default() += 1;
+        }
+
+        assert_eq!(sampled, weights(&[("c", 3), ("a", 1)]));
+        assert_eq!(
+            repository.sample(&state(["c", "a"]), &chooser).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn draws_random_states() {
+        let (_dir, repository) = frozen(&memory());
+
+        for _ in 0..20 {
+            let state: [String; 2] = repository.random().unwrap().unwrap();
+            assert!(!repository.get(&state).unwrap().is_empty());
+            let state = repository
+                .random_starting_with(&"a".to_string())
+                .unwrap()
+                .unwrap();
+            assert!(state == ["a", "b"] || state == ["a", "c"]);
+        }
+        assert_eq!(
+            Repository::<String, 2>::random_starting_with(&repository, &"zażółć".to_string())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn stats_match_source() {
+        let source = memory();
+        let (_dir, repository) = frozen(&source);
+
+        let frozen = Repository::<String, 2>::stats(&repository, 2).unwrap();
+        let expected = source.stats(2).unwrap();
+
+        assert_eq!(frozen.size, expected.size);
+        assert_eq!(frozen.total_weight, expected.total_weight);
+        assert_eq!(frozen.top_words, expected.top_words);
+        assert_eq!(frozen.top_states[0], expected.top_states[0]);
+        assert!((frozen.mean_entropy - expected.mean_entropy).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_chain() {
+        let (_dir, repository) = frozen(&MemoryRepository::new());
+
+        assert_eq!(Repository::<String, 2>::random(&repository).unwrap(), None);
+        assert_eq!(
+            repository.get(&state(["a", "b"])).unwrap(),
+            WeightMap::new()
+        );
+        assert_eq!(
+            Repository::<String, 2>::size(&repository).unwrap(),
+            Size::default()
+        );
+    }
+
+    #[test]
+    fn writes_are_rejected() {
+        let (_dir, repository) = frozen(&memory());
+
+        let error = repository
+            .increment_weight(link(["a", "b"], "c"))
+            .unwrap_err();
+        assert!(error.is::<ReadOnly>());
+        let error = repository
+            .add_weights(&[(link(["a", "b"], "c"), 1)])
+            .unwrap_err();
+        assert!(error.is::<ReadOnly>());
+        assert_eq!(
+            repository.get(&state(["a", "b"])).unwrap(),
+            weights(&[("c", 3), ("a", 1)])
+        );
+    }
+
+    #[test]
+    fn rejects_different_order_and_tokenizer() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.frozen");
+        compile::<_, 2>(&memory(), Tokenizer::Whitespace, &path).unwrap();
+
+        let error = FrozenRepository::open::<3>(&path, Tokenizer::Whitespace)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("order 2"));
+
+        let bytes = fs::read(&path).unwrap();
+        let name = b"whitespace";
+        let start = bytes.windows(name.len()).position(|w| w == name).unwrap();
+        let mut other = bytes.clone();
+        other[start..start + name.len()].copy_from_slice(b"characters");
+        fs::write(&path, other).unwrap();
+        let error = FrozenRepository::open::<2>(&path, Tokenizer::Whitespace)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("characters tokenizer"));
+    }
+
+    #[test]
+    fn rejects_garbage_and_truncated_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.frozen");
+        fs::write(&path, b"definitely not a frozen chain, honestly").unwrap();
+        assert!(FrozenRepository::open::<2>(&path, Tokenizer::Whitespace).is_err());
+
+        compile::<_, 2>(&memory(), Tokenizer::Whitespace, &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(FrozenRepository::open::<2>(&path, Tokenizer::Whitespace).is_err());
+    }
+
+    #[test]
+    fn recompiling_replaces_served_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.frozen");
+        compile::<_, 2>(&memory(), Tokenizer::Whitespace, &path).unwrap();
+        let served = FrozenRepository::open::<2>(&path, Tokenizer::Whitespace).unwrap();
+
+        compile::<_, 2>(&MemoryRepository::new(), Tokenizer::Whitespace, &path).unwrap();
+
+        assert_eq!(
+            served.get(&state(["a", "b"])).unwrap(),
+            weights(&[("c", 3), ("a", 1)])
+        );
+        let reopened = FrozenRepository::open::<2>(&path, Tokenizer::Whitespace).unwrap();
+        assert_eq!(
+            Repository::<String, 2>::size(&reopened).unwrap(),
+            Size::default()
+        );
+        assert!(!dir.path().join("chain.frozen.tmp").exists());
+    }
+}
@@ -0,0 +1,406 @@
+//! Portable JSON Lines representation of a chain, used to move it between
+//! backends and machines.
+//!
+//! The first line is a header describing the chain:
+//!
+//! ```json
+//! {"format":"markov-chain","version":1,"order":2,"tokenizer":"whitespace"}
+//! ```
+//!
+//! Every following line is a single transition:
+//!
+//! ```json
+//! {"from":["hello","there"],"to":"friend","weight":3}
+//! ```
+//!
+//! The last line is a trailer with the number of transitions, which tells a
+//! complete export from a truncated one:
+//!
+//! ```json
+//! {"transitions":2}
+//! ```
+//!
+//! Transitions are listed in no particular order. Importing adds the weights to
+//! the ones already stored, so repeated transitions are summed up.
+
+use std::io::{self, BufRead, Write};
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::markov::repository::Repository;
+use crate::markov::tokenizer::Tokenizer;
+use crate::markov::types::Link;
+
+const FORMAT: &str = "markov-chain";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    format: String,
+    version: u32,
+    order: usize,
+    tokenizer: String,
+}
+
+// Counted while writing, so it matches the transitions of the export even if
+// the repository is being written to at the same time.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Trailer {
+    transitions: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Record<T> {
+    from: Vec<T>,
+    to: T,
+    weight: u32,
+}
+
+/// Writes every transition of `repository` to `writer`, returning the number
+/// of exported transitions.
+#[instrument(skip_all)]
+pub fn export<T, R, const N: usize>(
+    repository: &R,
+    tokenizer: Tokenizer,
+    writer: &mut impl Write,
+) -> Result<u64>
+where
+    T: Serialize,
+    R: Repository<T, N> + ?Sized,
+{
+    let header = Header {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        order: N,
+        tokenizer: tokenizer.name().to_string(),
+    };
+    serde_json::to_writer(&mut *writer, &header)?;
+    writer.write_all(b"\n")?;
+
+    let mut transitions = 0;
+    repository.for_each_transition(&mut |link, weight| {
+        let record = Record {
+            from: Vec::from(link.from),
+            to: link.to,
+            weight,
+        };
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+        transitions += 1;
+        Ok(())
+    })?;
+    serde_json::to_writer(&mut *writer, &Trailer { transitions })?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    info!(transitions, "Exported chain");
+    Ok(transitions)
+}
+
+/// Reads transitions written by [`export`] from `reader` and adds them to
+/// `repository`, returning the number of imported transitions. Transitions
+/// are stored as they're read, but all or nothing, so a truncated or corrupted
+/// export, or a failure of the storage, leaves `repository` unchanged.
+#[instrument(skip_all)]
+pub fn import<T, R, const N: usize>(
+    repository: &R,
+    tokenizer: Tokenizer,
+    reader: impl BufRead,
+) -> Result<u64>
+where
+    T: DeserializeOwned,
+    R: Repository<T, N> + ?Sized,
+{
+    let mut lines = reader.lines().enumerate();
+    let header: Header = match lines.next() {
+        Some((_, line)) => serde_json::from_str(&line?).context("Invalid header")?,
+        None => bail!("Missing header"),
+    };
+    ensure!(header.format == FORMAT, "Not a chain export");
+    ensure!(
+        header.version == VERSION,
+        "Unsupported export version {}",
+        header.version
+    );
+    ensure!(
+        header.order == N,
+        "Chain was exported with order {}, but order {} is used",
+        header.order,
+        N
+    );
+    if header.tokenizer != tokenizer.name() {
+        bail!(
+            "Chain was exported with {} tokenizer, but {} is used",
+            header.tokenizer,
+            tokenizer.name()
+        );
+    }
+
+    let mut records = Records {
+        lines,
+        transitions: 0,
+        done: false,
+        link: PhantomData,
+    };
+    repository.add_all_weights(&mut records)?;
+    let transitions = records.transitions;
+    info!(transitions, "Imported chain");
+    Ok(transitions)
+}
+
+/// Transitions read from the lines of an export that follow its header. It
+/// yields an error if a line is invalid, or if the lines run out without a
+/// trailer counting every transition.
+struct Records<I, T, const N: usize> {
+    lines: I,
+    transitions: u64,
+    done: bool,
+    link: PhantomData<Link<T, N>>,
+}
+
+impl<I, T, const N: usize> Records<I, T, N>
+where
+    T: DeserializeOwned,
+{
+    // Returns `None` for lines without a transition, i.e. blank ones and the
+    // trailer.
+    fn parse(&mut self, idx: usize, line: io::Result<String>) -> Result<Option<(Link<T, N>, u32)>> {
+        let line = line?;
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        ensure!(!self.done, "Unexpected line {} after the trailer", idx + 1);
+        if let Ok(trailer) = serde_json::from_str::<Trailer>(&line) {
+            ensure!(
+                trailer.transitions == self.transitions,
+                "Expected {} transitions, but found {}",
+                trailer.transitions,
+                self.transitions
+            );
+            self.done = true;
+            return Ok(None);
+        }
+        let link = parse_record(&line).with_context(|| format!("Invalid line {}", idx + 1))?;
+        self.transitions += 1;
+        Ok(Some(link))
+    }
+}
+
+impl<I, T, const N: usize> Iterator for Records<I, T, N>
+where
+    I: Iterator<Item = (usize, io::Result<String>)>,
+    T: DeserializeOwned,
+{
+    type Item = Result<(Link<T, N>, u32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((idx, line)) = self.lines.next() {
+            match self.parse(idx, line) {
+                Ok(None) => continue,
+                result => return result.transpose(),
+            }
+        }
+        if self.done {
+            None
+        } else {
+            Some(Err(anyhow!("Missing trailer, the export may be truncated")))
+        }
+    }
+}
+
+fn parse_record<T, const N: usize>(line: &str) -> Result<(Link<T, N>, u32)>
+where
+    T: DeserializeOwned,
+{
+    let record: Record<T> = serde_json::from_str(line)?;
+    let len = record.from.len();
+    let from = <[T; N]>::try_from(record.from)
+        .map_err(|_| anyhow!("Expected state of {} words, but found {}", N, len))?;
+    ensure!(record.weight > 0, "Weight must be positive");
+    Ok((Link::new(from, record.to), record.weight))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::collections::HashSet;
+
+    use rusqlite::Connection;
+
+    use super::{export, import};
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::adapters::sqlite::repository::SqliteRepository;
+    use crate::markov::repository::Repository;
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::Link;
+
+    fn link(from: [&str; 2], to: &str) -> Link<String, 2> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    fn memory() -> MemoryRepository<String, 2> {
+        let repository = MemoryRepository::new();
+        repository.add_weight(link(["a", "b"], "c"), 3).unwrap();
+        repository.add_weight(link(["a", "b"], "\0"), 1).unwrap();
+        repository.add_weight(link(["\0", "a"], "b"), 2).unwrap();
+        repository
+            .add_weight(link(["b", "c"], "zażółć \"x\""), 7)
+            .unwrap();
+        repository
+    }
+
+    fn sqlite() -> SqliteRepository {
+        let connection = Connection::open_in_memory().unwrap();
+        SqliteRepository::new::<2>(connection).unwrap()
+    }
+
+    fn transitions(repository: &impl Repository<String, 2>) -> HashSet<([String; 2], String, u32)> {
+        let mut transitions = HashSet::new();
+        repository
+            .for_each_transition(&mut |link, weight| {
+                transitions.insert((link.from, link.to, weight));
+                Ok(())
+            })
+            .unwrap();
+        transitions
+    }
+
+    fn exported(repository: &impl Repository<String, 2>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        export(repository, Tokenizer::Whitespace, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn round_trip_memory_to_sqlite_and_back() {
+        let source = memory();
+
+        let sqlite = sqlite();
+        let imported =
+            import::<String, _, 2>(&sqlite, Tokenizer::Whitespace, &exported(&source)[..]).unwrap();
+        let memory = MemoryRepository::new();
+        import::<String, _, 2>(&memory, Tokenizer::Whitespace, &exported(&sqlite)[..]).unwrap();
+
+        assert_eq!(imported, 4);
+        assert_eq!(transitions(&sqlite), transitions(&source));
+        assert_eq!(transitions(&memory), transitions(&source));
+        assert_eq!(
+            Repository::<String, 2>::size(&sqlite).unwrap(),
+            source.size().unwrap()
+        );
+    }
+
+    #[test]
+    fn import_adds_to_existing_weights() {
+        let buffer = exported(&memory());
+        let repository = sqlite();
+        import::<String, _, 2>(&repository, Tokenizer::Whitespace, &buffer[..]).unwrap();
+        import::<String, _, 2>(&repository, Tokenizer::Whitespace, &buffer[..]).unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 6);
+    }
+
+    #[test]
+    fn header_and_trailer_describe_chain() {
+        let buffer = exported(&memory());
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "{\"format\":\"markov-chain\",\"version\":1,\"order\":2,\"tokenizer\":\"whitespace\"}"
+        );
+        assert_eq!(lines[5], "{\"transitions\":4}");
+    }
+
+    #[test]
+    fn rejects_different_order() {
+        let buffer = exported(&memory());
+        let repository: MemoryRepository<String, 3> = MemoryRepository::new();
+
+        let error =
+            import::<String, _, 3>(&repository, Tokenizer::Whitespace, &buffer[..]).unwrap_err();
+
+        assert!(error.to_string().contains("order 2"));
+    }
+
+    #[test]
+    fn rejects_truncated_export() {
+        let buffer = exported(&memory());
+        let text = String::from_utf8(buffer).unwrap();
+        let truncated: Vec<_> = text.lines().take(3).collect();
+        let repository = sqlite();
+        import::<String, _, 2>(&repository, Tokenizer::Whitespace, text.as_bytes()).unwrap();
+        let before = transitions(&repository);
+        let size = Repository::<String, 2>::size(&repository).unwrap();
+
+        let error = import::<String, _, 2>(
+            &repository,
+            Tokenizer::Whitespace,
+            truncated.join("\n").as_bytes(),
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("Missing trailer"));
+        assert_eq!(transitions(&repository), before);
+        assert_eq!(Repository::<String, 2>::size(&repository).unwrap(), size);
+    }
+
+    #[test]
+    fn rejects_missing_transitions() {
+        let buffer = exported(&memory());
+        let text = String::from_utf8(buffer).unwrap();
+        let mut lines: Vec<_> = text.lines().collect();
+        lines.remove(1);
+        let repository = sqlite();
+
+        let error = import::<String, _, 2>(
+            &repository,
+            Tokenizer::Whitespace,
+            lines.join("\n").as_bytes(),
+        )
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "Expected 4 transitions, but found 3");
+        assert!(transitions(&repository).is_empty());
+    }
+
+    #[test]
+    fn rejects_lines_after_trailer() {
+        let mut input = exported(&memory());
+        input.extend_from_slice(b"{\"from\":[\"a\",\"b\"],\"to\":\"c\",\"weight\":1}\n");
+        let repository = sqlite();
+
+        let error =
+            import::<String, _, 2>(&repository, Tokenizer::Whitespace, &input[..]).unwrap_err();
+
+        assert_eq!(error.to_string(), "Unexpected line 7 after the trailer");
+        assert!(transitions(&repository).is_empty());
+    }
+
+    // The valid transition is stored before the invalid one is read, and
+    // rolled back with the rest of the import.
+    #[test]
+    fn rejects_invalid_transition() {
+        let input = "\
+{\"format\":\"markov-chain\",\"version\":1,\"order\":2,\"tokenizer\":\"whitespace\"}
+{\"from\":[\"a\",\"b\"],\"to\":\"c\",\"weight\":1}
+{\"from\":[\"a\"],\"to\":\"b\",\"weight\":1}
+{\"transitions\":2}
+";
+        let repository = sqlite();
+
+        let error = import::<String, _, 2>(&repository, Tokenizer::Whitespace, input.as_bytes())
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "Invalid line 3");
+        assert!(transitions(&repository).is_empty());
+        assert!(format!("{:#}", error).contains("state of 2 words"));
+    }
+}
@@ -0,0 +1,749 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Result};
+use rand::{thread_rng, Rng};
+use redb::backends::InMemoryBackend;
+use redb::{
+    Database, Durability, ReadOnlyTable, ReadTransaction, ReadableTable, ReadableTableMetadata,
+    Table, TableDefinition, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::markov::decay::{self, DecayPolicy, Decayed};
+use crate::markov::repository::Repository;
+use crate::markov::stats::{self, StateStats, Stats};
+use crate::markov::types::{Link, Size, WeightMap};
+
+/// Version of the layout of the tables, stored in the `metadata` table.
+pub const VERSION: u64 = 2;
+
+// Word to its id.
+const WORDS: TableDefinition<&str, u32> = TableDefinition::new("word");
+// Id to its word.
+const WORD_VALUES: TableDefinition<u32, &str> = TableDefinition::new("word_value");
+// Encoded state to bincode serialized `Successors`.
+const STATES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("state");
+// Dense index of states, used to draw a random one.
+const STATE_INDEX: TableDefinition<u64, &[u8]> = TableDefinition::new("state_index");
+const METADATA: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+
+const VERSION_KEY: &str = "version";
+const ORDER_KEY: &str = "order";
+const TRANSITIONS_KEY: &str = "transitions";
+// Id of the next new word. Ids of words no longer used are not reused.
+const NEXT_WORD_KEY: &str = "next_word";
+
+/// Repository backed by [redb](https://www.redb.org), an embedded key-value
+/// store. States are keyed by the ids of their words, so looking up successors
+/// takes a single read instead of joins.
+///
+/// Writes are committed without waiting for the disk, so a crash may lose the
+/// ones since the last [`Repository::flush`], but never corrupts the database.
+pub struct KvRepository {
+    database: Database,
+}
+
+impl KvRepository {
+    /// Opens the database at `path`, creating it if needed, and checks that it
+    /// stores a chain of order `N`.
+    pub fn open<const N: usize>(path: impl AsRef<Path>) -> Result<KvRepository> {
+        let database = Database::create(path).context("Failed to open key-value database")?;
+        KvRepository::new::<N>(database)
+    }
+
+    /// Creates a repository that is never written to the disk.
+    pub fn in_memory<const N: usize>() -> Result<KvRepository> {
+        let database = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        KvRepository::new::<N>(database)
+    }
+
+    fn new<const N: usize>(database: Database) -> Result<KvRepository> {
+        let transaction = database.begin_write()?;
+        {
+            transaction.open_table(WORDS)?;
+            transaction.open_table(WORD_VALUES)?;
+            transaction.open_table(STATES)?;
+            transaction.open_table(STATE_INDEX)?;
+            let mut metadata = transaction.open_table(METADATA)?;
+            let version = metadata.get(VERSION_KEY)?.map(|v| v.value());
+            match version {
+                None => {
+                    metadata.insert(VERSION_KEY, VERSION)?;
+                    metadata.insert(ORDER_KEY, N as u64)?;
+                    metadata.insert(TRANSITIONS_KEY, 0)?;
+                    metadata.insert(NEXT_WORD_KEY, 0)?;
+                }
+                Some(version) if version > VERSION => bail!(
+                    "Database version {} is newer than supported version {}",
+                    version,
+                    VERSION
+                ),
+                Some(1) => {
+                    let next_word = upgrade_v1(&transaction)?;
+                    metadata.insert(NEXT_WORD_KEY, next_word)?;
+                    metadata.insert(VERSION_KEY, VERSION)?;
+                }
+                Some(_) => {}
+            }
+            let order = metadata.get(ORDER_KEY)?.context("Missing order")?.value();
+            ensure!(
+                order == N as u64,
+                "Database was created with order {}, but order {} is used",
+                order,
+                N
+            );
+        }
+        transaction.commit()?;
+        Ok(KvRepository { database })
+    }
+
+    fn read<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&Reader) -> Result<O>,
+    {
+        let transaction = self.database.begin_read()?;
+        f(&Reader::new(&transaction)?)
+    }
+
+    fn write<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&mut Writer) -> Result<O>,
+    {
+        let mut transaction = self.database.begin_write()?;
+        transaction.set_durability(Durability::Eventual);
+        let output = Writer::new(&transaction)?.run(f)?;
+        transaction.commit()?;
+        Ok(output)
+    }
+}
+
+// Version 1 stored only the weights of successors. Transitions count as
+// learned when the database is upgraded, since nothing better is known. Word
+// ids were dense, so the next one is the number of words.
+fn upgrade_v1(transaction: &WriteTransaction) -> Result<u64> {
+    let now = decay::unix_time(SystemTime::now());
+    let state_index = transaction.open_table(STATE_INDEX)?;
+    let mut states = transaction.open_table(STATES)?;
+    for entry in state_index.iter()? {
+        let (_, key) = entry?;
+        let weights: WeightMap<u32> = match states.get(key.value())? {
+            Some(weights) => bincode::deserialize(weights.value())?,
+            None => bail!("Missing state"),
+        };
+        let successors: Successors = weights
+            .into_iter()
+            .map(|(id, weight)| {
+                (
+                    id,
+                    Successor {
+                        weight,
+                        updated: now,
+                    },
+                )
+            })
+            .collect();
+        states.insert(key.value(), &bincode::serialize(&successors)?[..])?;
+    }
+    Ok(transaction.open_table(WORD_VALUES)?.len()?)
+}
+
+/// Weight of a transition and Unix time of its last change.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct Successor {
+    weight: u32,
+    updated: u64,
+}
+
+impl Successor {
+    /// Decays the weight as of `now`, returning whether it's still above
+    /// zero.
+    fn decay(&mut self, policy: &DecayPolicy, now: u64, decayed: &mut Decayed) -> bool {
+        let elapsed = Duration::from_secs(now.saturating_sub(self.updated));
+        let (weight, consumed) = policy.decay(self.weight, elapsed);
+        if weight == self.weight {
+            return true;
+        }
+        decayed.decayed += 1;
+        if weight == 0 {
+            decayed.removed += 1;
+            return false;
+        }
+        self.weight = weight;
+        self.updated = self.updated.saturating_add(consumed.as_secs());
+        true
+    }
+}
+
+/// Successors of a state by word id.
+type Successors = HashMap<u32, Successor>;
+
+fn decode_successors(value: &[u8]) -> Result<Successors> {
+    Ok(bincode::deserialize(value)?)
+}
+
+fn encode_state(ids: &[u32]) -> Vec<u8> {
+    ids.iter().flat_map(|id| id.to_be_bytes()).collect()
+}
+
+fn decode_state(key: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    key.chunks_exact(4)
+        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+}
+
+struct Reader {
+    words: ReadOnlyTable<&'static str, u32>,
+    word_values: ReadOnlyTable<u32, &'static str>,
+    states: ReadOnlyTable<&'static [u8], &'static [u8]>,
+    state_index: ReadOnlyTable<u64, &'static [u8]>,
+    metadata: ReadOnlyTable<&'static str, u64>,
+}
+
+impl Reader {
+    fn new(transaction: &ReadTransaction) -> Result<Reader> {
+        Ok(Reader {
+            words: transaction.open_table(WORDS)?,
+            word_values: transaction.open_table(WORD_VALUES)?,
+            states: transaction.open_table(STATES)?,
+            state_index: transaction.open_table(STATE_INDEX)?,
+            metadata: transaction.open_table(METADATA)?,
+        })
+    }
+
+    fn word_id(&self, word: &str) -> Result<Option<u32>> {
+        Ok(self.words.get(word)?.map(|id| id.value()))
+    }
+
+    fn word(&self, id: u32) -> Result<String> {
+        let word = self
+            .word_values
+            .get(id)?
+            .with_context(|| format!("Missing word {}", id))?;
+        Ok(word.value().to_string())
+    }
+
+    fn state<const N: usize>(&self, key: &[u8]) -> Result<[String; N]> {
+        let words = decode_state(key)
+            .map(|id| self.word(id))
+            .collect::<Result<Vec<_>>>()?;
+        let len = words.len();
+        <[String; N]>::try_from(words)
+            .map_err(|_| anyhow::anyhow!("Expected state of {} words, but found {}", N, len))
+    }
+
+    fn successors(&self, key: &[u8]) -> Result<Option<Successors>> {
+        match self.states.get(key)? {
+            Some(successors) => Ok(Some(decode_successors(successors.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn size(&self) -> Result<Size> {
+        let transitions = self
+            .metadata
+            .get(TRANSITIONS_KEY)?
+            .context("Missing transition count")?;
+        Ok(Size {
+            words: self.words.len()?,
+            states: self.states.len()?,
+            transitions: transitions.value(),
+        })
+    }
+}
+
+struct Writer<'t> {
+    words: Table<'t, &'static str, u32>,
+    word_values: Table<'t, u32, &'static str>,
+    states: Table<'t, &'static [u8], &'static [u8]>,
+    state_index: Table<'t, u64, &'static [u8]>,
+    metadata: Table<'t, &'static str, u64>,
+    new_transitions: u64,
+    removed_transitions: u64,
+    // Unix time links are learned at.
+    now: u64,
+}
+
+impl<'t> Writer<'t> {
+    fn new(transaction: &'t WriteTransaction) -> Result<Writer<'t>> {
+        Ok(Writer {
+            words: transaction.open_table(WORDS)?,
+            word_values: transaction.open_table(WORD_VALUES)?,
+            states: transaction.open_table(STATES)?,
+            state_index: transaction.open_table(STATE_INDEX)?,
+            metadata: transaction.open_table(METADATA)?,
+            new_transitions: 0,
+            removed_transitions: 0,
+            now: decay::unix_time(SystemTime::now()),
+        })
+    }
+
+    fn run<F, O>(mut self, f: F) -> Result<O>
+    where
+        F: FnOnce(&mut Writer) -> Result<O>,
+    {
+        let output = f(&mut self)?;
+        if self.new_transitions > 0 || self.removed_transitions > 0 {
+            let transitions = self
+                .metadata
+                .get(TRANSITIONS_KEY)?
+                .context("Missing transition count")?
+                .value();
+            let transitions = transitions + self.new_transitions - self.removed_transitions;
+            self.metadata.insert(TRANSITIONS_KEY, transitions)?;
+        }
+        Ok(output)
+    }
+
+    fn get_or_create_word(&mut self, word: &str) -> Result<u32> {
+        if let Some(id) = self.words.get(word)? {
+            return Ok(id.value());
+        }
+        let next = self
+            .metadata
+            .get(NEXT_WORD_KEY)?
+            .context("Missing next word id")?
+            .value();
+        let id = u32::try_from(next).context("Too many words")?;
+        self.words.insert(word, id)?;
+        self.word_values.insert(id, word)?;
+        self.metadata.insert(NEXT_WORD_KEY, next + 1)?;
+        Ok(id)
+    }
+
+    fn add_weight<const N: usize>(&mut self, link: &Link<String, N>, weight: u32) -> Result<()> {
+        let from_ids = link
+            .from
+            .iter()
+            .map(|word| self.get_or_create_word(word))
+            .collect::<Result<Vec<_>>>()?;
+        let to_id = self.get_or_create_word(&link.to)?;
+        let key = encode_state(&from_ids);
+
+        let existing = match self.states.get(&key[..])? {
+            Some(successors) => Some(decode_successors(successors.value())?),
+            None => None,
+        };
+        let mut successors = match existing {
+            Some(successors) => successors,
+            None => {
+                let idx = self.state_index.len()?;
+                self.state_index.insert(idx, &key[..])?;
+                Successors::new()
+            }
+        };
+        let successor = successors.entry(to_id).or_insert_with(|| {
+            self.new_transitions += 1;
+            Successor {
+                weight: 0,
+                updated: 0,
+            }
+        });
+        successor.weight = successor.weight.saturating_add(weight);
+        successor.updated = successor.updated.max(self.now);
+        self.states
+            .insert(&key[..], &bincode::serialize(&successors)?[..])?;
+        Ok(())
+    }
+
+    /// Decays weights as of `now`, removing successors decayed to zero, and
+    /// states and words no longer used by any.
+    fn decay(&mut self, policy: &DecayPolicy, now: u64) -> Result<Decayed> {
+        let mut decayed = Decayed::default();
+        // Collected first, since the table can't change while it's iterated.
+        let mut changes = Vec::new();
+        for entry in self.states.iter()? {
+            let (key, successors) = entry?;
+            let mut successors = decode_successors(successors.value())?;
+            let before = decayed;
+            successors.retain(|_, successor| successor.decay(policy, now, &mut decayed));
+            if decayed != before {
+                changes.push((key.value().to_vec(), successors));
+            }
+        }
+        for (key, successors) in changes {
+            if successors.is_empty() {
+                self.states.remove(&key[..])?;
+            } else {
+                self.states
+                    .insert(&key[..], &bincode::serialize(&successors)?[..])?;
+            }
+        }
+        if decayed.removed > 0 {
+            self.removed_transitions += decayed.removed;
+            self.remove_unused()?;
+        }
+        Ok(decayed)
+    }
+
+    // Renumbers the state index, so that it stays dense after removing states,
+    // and removes words no longer used by any state.
+    fn remove_unused(&mut self) -> Result<()> {
+        self.state_index.retain(|_, _| false)?;
+        let mut used = HashSet::new();
+        for (idx, entry) in self.states.iter()?.enumerate() {
+            let (key, successors) = entry?;
+            used.extend(decode_state(key.value()));
+            used.extend(decode_successors(successors.value())?.into_keys());
+            self.state_index.insert(idx as u64, key.value())?;
+        }
+        let mut unused = Vec::new();
+        for entry in self.word_values.iter()? {
+            let (id, word) = entry?;
+            if !used.contains(&id.value()) {
+                unused.push((id.value(), word.value().to_string()));
+            }
+        }
+        for (id, word) in unused {
+            self.word_values.remove(id)?;
+            self.words.remove(&word[..])?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Repository<String, N> for KvRepository {
+    #[instrument(level = "trace", skip_all)]
+    fn get(&self, from: &[String; N]) -> Result<WeightMap<String>> {
+        self.read(|reader| {
+            let mut ids = Vec::with_capacity(N);
+            for word in from {
+                match reader.word_id(word)? {
+                    Some(id) => ids.push(id),
+                    None => return Ok(WeightMap::new()),
+                }
+            }
+            let successors = reader.successors(&encode_state(&ids))?.unwrap_or_default();
+            successors
+                .into_iter()
+                .map(|(id, successor)| Ok((reader.word(id)?, successor.weight)))
+                .collect()
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random(&self) -> Result<Option<[String; N]>> {
+        self.read(|reader| {
+            let len = reader.state_index.len()?;
+            if len == 0 {
+                return Ok(None);
+            }
+            let idx = thread_rng().gen_range(0..len);
+            let key = reader
+                .state_index
+                .get(idx)?
+                .with_context(|| format!("Missing state {}", idx))?;
+            Ok(Some(reader.state(key.value())?))
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random_starting_with(&self, state: &String) -> Result<Option<[String; N]>> {
+        self.read(|reader| {
+            let id = match reader.word_id(state)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            // States are ordered by their encoded ids, so the ones starting
+            // with the word are next to each other.
+            let prefix = id.to_be_bytes();
+            let matching = || -> Result<_> {
+                Ok(reader
+                    .states
+                    .range(&prefix[..]..)?
+                    .map_while(move |entry| match entry {
+                        Ok((key, _)) if !key.value().starts_with(&prefix) => None,
+                        entry => Some(entry),
+                    }))
+            };
+            let count = matching()?.count();
+            if count == 0 {
+                return Ok(None);
+            }
+            let nth = thread_rng().gen_range(0..count);
+            match matching()?.nth(nth) {
+                Some(entry) => Ok(Some(reader.state(entry?.0.value())?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_weight(&self, link: Link<String, N>, weight: u32) -> Result<()> {
+        self.write(|writer| writer.add_weight(&link, weight))
+    }
+
+    #[instrument(level = "trace", skip_all, fields(links = links.len()))]
+    fn add_weights(&self, links: &[(Link<String, N>, u32)]) -> Result<()> {
+        self.write(|writer| {
+            links
+                .iter()
+                .try_for_each(|(link, weight)| writer.add_weight(link, *weight))
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<String, N>, u32)>>,
+    ) -> Result<()> {
+        self.write(|writer| {
+            for link in links {
+                let (link, weight) = link?;
+                writer.add_weight(&link, weight)?;
+            }
+            Ok(())
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn size(&self) -> Result<Size> {
+        self.read(Reader::size)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn stats(&self, top: usize) -> Result<Stats<String, N>> {
+        self.read(|reader| {
+            let mut word_weights: HashMap<u32, u64> = HashMap::new();
+            let mut states = Vec::new();
+            let mut total_weight = 0;
+            let mut weighted_entropy = 0.0;
+
+            for entry in reader.states.iter()? {
+                let (key, successors) = entry?;
+                let weights: WeightMap<u32> = decode_successors(successors.value())?
+                    .into_iter()
+                    .map(|(id, successor)| (id, successor.weight))
+                    .collect();
+                let weight: u64 = weights.values().map(|&w| u64::from(w)).sum();
+                let entropy = stats::entropy(weights.values().copied());
+                for (to, w) in weights {
+                    *word_weights.entry(to).or_default() += u64::from(w);
+                }
+                total_weight += weight;
+                weighted_entropy += weight as f64 * entropy;
+                states.push((key.value().to_vec(), weight, entropy));
+            }
+
+            let top_words = stats::top(word_weights, top, |(_, weight)| *weight)
+                .into_iter()
+                .map(|(id, weight)| Ok((reader.word(id)?, weight)))
+                .collect::<Result<_>>()?;
+            let top_states = stats::top(states, top, |(_, weight, _)| *weight)
+                .into_iter()
+                .map(|(key, weight, entropy)| {
+                    Ok(StateStats {
+                        state: reader.state(&key)?,
+                        weight,
+                        entropy,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            Ok(Stats {
+                size: reader.size()?,
+                total_weight,
+                top_words,
+                top_states,
+                mean_entropy: if total_weight > 0 {
+                    weighted_entropy / total_weight as f64
+                } else {
+                    0.0
+                },
+            })
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn for_each_transition(
+        &self,
+        f: &mut dyn FnMut(Link<String, N>, u32) -> Result<()>,
+    ) -> Result<()> {
+        self.read(|reader| {
+            for entry in reader.states.iter()? {
+                let (key, successors) = entry?;
+                let from: [String; N] = reader.state(key.value())?;
+                for (to, successor) in decode_successors(successors.value())? {
+                    f(Link::new(from.clone(), reader.word(to)?), successor.weight)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        let now = decay::unix_time(now);
+        self.write(|writer| writer.decay(policy, now))
+    }
+
+    /// Waits until all writes are persisted on the disk.
+    #[instrument(level = "trace", skip_all)]
+    fn flush(&self) -> Result<()> {
+        let mut transaction = self.database.begin_write()?;
+        transaction.set_durability(Durability::Immediate);
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use redb::backends::InMemoryBackend;
+    use redb::Database;
+    use tempfile::tempdir;
+
+    use super::{
+        encode_state, KvRepository, METADATA, NEXT_WORD_KEY, ORDER_KEY, STATES, STATE_INDEX,
+        TRANSITIONS_KEY, VERSION, VERSION_KEY, WORDS, WORD_VALUES,
+    };
+    use crate::markov::conformance::{conformance_tests, decay_tests};
+    use crate::markov::decay::{DecayPolicy, Decayed};
+    use crate::markov::repository::Repository;
+    use crate::markov::types::{Link, WeightMap};
+
+    fn repository<const N: usize>() -> KvRepository {
+        KvRepository::in_memory::<N>().unwrap()
+    }
+
+    conformance_tests!(repository);
+    decay_tests!(repository);
+
+    fn link<const N: usize>(from: [&str; N], to: &str) -> Link<String, N> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    #[test]
+    fn stats_summarize_chain() {
+        let repository = repository::<1>();
+        for (from, to) in [("a", "b"), ("a", "c"), ("a", "b"), ("b", "c"), ("a", "b")] {
+            repository.increment_weight(link([from], to)).unwrap();
+        }
+
+        let stats = Repository::<String, 1>::stats(&repository, 1).unwrap();
+
+        assert_eq!(stats.size.words, 3);
+        assert_eq!(stats.size.states, 2);
+        assert_eq!(stats.size.transitions, 3);
+        assert_eq!(stats.total_weight, 5);
+        assert_eq!(stats.top_words, vec![("b".to_string(), 3)]);
+        assert_eq!(stats.top_states[0].state, ["a".to_string()]);
+        assert_eq!(stats.top_states[0].weight, 4);
+        assert!((stats.top_states[0].entropy - 0.811).abs() < 1e-3);
+        assert!((stats.mean_entropy - 4.0 * 0.811 / 5.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reopened_database_keeps_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.redb");
+        let repository = KvRepository::open::<2>(&path).unwrap();
+        repository.add_weight(link(["a", "b"], "\0"), 3).unwrap();
+        Repository::<String, 2>::flush(&repository).unwrap();
+        drop(repository);
+
+        let repository = KvRepository::open::<2>(&path).unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["\0"], 3);
+        assert_eq!(
+            Repository::<String, 2>::size(&repository)
+                .unwrap()
+                .transitions,
+            1
+        );
+    }
+
+    #[test]
+    fn open_rejects_different_order() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.redb");
+        drop(KvRepository::open::<2>(&path).unwrap());
+
+        let error = KvRepository::open::<3>(&path).err().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "Database was created with order 2, but order 3 is used"
+        );
+    }
+
+    // A chain of order 2 learned from "a b c" by version 1.
+    fn v1_fixture() -> Database {
+        let database = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        let transaction = database.begin_write().unwrap();
+        {
+            let mut words = transaction.open_table(WORDS).unwrap();
+            let mut word_values = transaction.open_table(WORD_VALUES).unwrap();
+            for (id, word) in ["a", "b", "c"].into_iter().enumerate() {
+                words.insert(word, id as u32).unwrap();
+                word_values.insert(id as u32, word).unwrap();
+            }
+            let key = encode_state(&[0, 1]);
+            let weights = WeightMap::from([(2, 3)]);
+            let mut states = transaction.open_table(STATES).unwrap();
+            states
+                .insert(&key[..], &bincode::serialize(&weights).unwrap()[..])
+                .unwrap();
+            let mut state_index = transaction.open_table(STATE_INDEX).unwrap();
+            state_index.insert(0, &key[..]).unwrap();
+            let mut metadata = transaction.open_table(METADATA).unwrap();
+            metadata.insert(VERSION_KEY, 1).unwrap();
+            metadata.insert(ORDER_KEY, 2).unwrap();
+            metadata.insert(TRANSITIONS_KEY, 1).unwrap();
+        }
+        transaction.commit().unwrap();
+        database
+    }
+
+    #[test]
+    fn upgrades_v1_database() {
+        let repository = KvRepository::new::<2>(v1_fixture()).unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 3);
+        repository.increment_weight(link(["b", "c"], "d")).unwrap();
+        let from = ["b", "c"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["d"], 1);
+        let size = Repository::<String, 2>::size(&repository).unwrap();
+        assert_eq!((size.words, size.states, size.transitions), (4, 2, 2));
+        let metadata = repository.database.begin_read().unwrap();
+        let metadata = metadata.open_table(METADATA).unwrap();
+        assert_eq!(metadata.get(VERSION_KEY).unwrap().unwrap().value(), VERSION);
+        assert_eq!(metadata.get(NEXT_WORD_KEY).unwrap().unwrap().value(), 4);
+        // Transitions learned before they were timestamped count as recent.
+        let policy = DecayPolicy {
+            half_life: Duration::from_secs(60 * 60),
+        };
+        let decayed = Repository::<String, 2>::decay(&repository, &policy, SystemTime::now());
+        assert_eq!(decayed.unwrap(), Decayed::default());
+    }
+
+    #[test]
+    fn new_words_do_not_reuse_ids_of_forgotten_ones() {
+        let repository = repository::<1>();
+        repository.increment_weight(link(["a"], "b")).unwrap();
+        repository.add_weight(link(["c"], "d"), 100).unwrap();
+        let policy = DecayPolicy {
+            half_life: Duration::from_secs(60 * 60),
+        };
+        let now = SystemTime::now() + 2 * policy.half_life;
+        Repository::<String, 1>::decay(&repository, &policy, now).unwrap();
+
+        repository.increment_weight(link(["e"], "f")).unwrap();
+
+        assert_eq!(repository.get(&["c".to_string()]).unwrap()["d"], 25);
+        assert_eq!(repository.get(&["e".to_string()]).unwrap()["f"], 1);
+        assert_eq!(
+            repository.get(&["a".to_string()]).unwrap(),
+            WeightMap::new()
+        );
+        let size = Repository::<String, 1>::size(&repository).unwrap();
+        assert_eq!((size.words, size.states, size.transitions), (4, 2, 2));
+    }
+}
@@ -0,0 +1,594 @@
+//! Chain storing every word once. States and successors refer to words by
+//! their id, so a word shared by many states costs 4 bytes per use instead of
+//! a copy of the word.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::markov::decay::{self, DecayPolicy, Decayed};
+use crate::markov::types::{Link, WeightMap};
+
+// States with fewer successors are sampled by scanning them, which is faster
+// than building and searching a table of cumulative weights.
+const CUMULATIVE_THRESHOLD: usize = 32;
+
+// Most weights kept in cached tables of cumulative weights, i.e. 8 MiB.
+const CUMULATIVE_CAPACITY: usize = 1 << 20;
+
+/// Assigns consecutive ids to distinct words.
+#[derive(Debug)]
+pub struct Dictionary<T> {
+    words: Vec<Arc<T>>,
+    ids: HashMap<Arc<T>, u32>,
+}
+
+impl<T> Dictionary<T>
+where
+    T: Eq + Hash,
+{
+    pub fn new() -> Dictionary<T> {
+        Dictionary {
+            words: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self, word: &T) -> Option<u32> {
+        self.ids.get(word).copied()
+    }
+
+    pub fn intern(&mut self, word: T) -> u32 {
+        if let Some(id) = self.id(&word) {
+            return id;
+        }
+        let id = u32::try_from(self.words.len()).expect("too many distinct words");
+        let word = Arc::new(word);
+        self.words.push(word.clone());
+        self.ids.insert(word, id);
+        id
+    }
+}
+
+impl<T> Dictionary<T> {
+    /// Panics if `id` wasn't returned by this dictionary.
+    pub fn word(&self, id: u32) -> &T {
+        &self.words[id as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Words ordered by their id.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.words.iter().map(|word| &**word)
+    }
+}
+
+impl<T> Dictionary<T>
+where
+    T: Eq + Hash,
+{
+    /// Removes words whose id isn't `used`, renumbering the rest. Returns the
+    /// new id of every old one, or `u32::MAX` for removed words.
+    fn retain(&mut self, used: &[bool]) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(self.words.len());
+        let mut words = Vec::new();
+        for (word, &used) in mem::take(&mut self.words).into_iter().zip(used) {
+            if used {
+                ids.push(words.len() as u32);
+                words.push(word);
+            } else {
+                ids.push(u32::MAX);
+            }
+        }
+        self.ids = words
+            .iter()
+            .enumerate()
+            .map(|(id, word)| (word.clone(), id as u32))
+            .collect();
+        self.words = words;
+        ids
+    }
+}
+
+/// Seconds since the Unix epoch, as when transitions were learned is stored.
+/// They fit u32 until 2106.
+pub fn timestamp(time: SystemTime) -> u32 {
+    u32::try_from(decay::unix_time(time)).unwrap_or(u32::MAX)
+}
+
+/// Transition to a word, and when it was last learned.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Successor {
+    pub id: u32,
+    pub weight: u32,
+    pub updated: u32,
+}
+
+/// Successors of a state and their weights, sorted by word id.
+#[derive(Debug, Default)]
+pub struct Successors(Vec<Successor>);
+
+impl Successors {
+    /// Adds `weight` to the successor `id`, learned at `updated`.
+    pub fn add(&mut self, id: u32, weight: u32, updated: u32) {
+        match self.0.binary_search_by_key(&id, |successor| successor.id) {
+            Ok(i) => {
+                let successor = &mut self.0[i];
+                successor.weight = successor.weight.saturating_add(weight);
+                successor.updated = successor.updated.max(updated);
+            }
+            Err(i) => self.0.insert(
+                i,
+                Successor {
+                    id,
+                    weight,
+                    updated,
+                },
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.0
+            .iter()
+            .map(|successor| (successor.id, successor.weight))
+    }
+
+    pub fn entries(&self) -> &[Successor] {
+        &self.0
+    }
+
+    pub fn weights(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().map(|successor| successor.weight)
+    }
+
+    fn total(&self) -> u64 {
+        self.weights().map(u64::from).sum()
+    }
+
+    fn cumulative(&self) -> Arc<[u64]> {
+        self.weights()
+            .scan(0, |sum, weight| {
+                *sum += u64::from(weight);
+                Some(*sum)
+            })
+            .collect()
+    }
+
+    /// Id of the successor at `position` if every successor was repeated as
+    /// many times as its weight.
+    fn at(&self, position: u64) -> u32 {
+        let mut sum = 0;
+        for (id, weight) in self.iter() {
+            sum += u64::from(weight);
+            if sum > position {
+                return id;
+            }
+        }
+        unreachable!("position out of bounds")
+    }
+
+    /// Decays weights as of `now`, dropping successors decayed to zero.
+    fn decay(&mut self, policy: &DecayPolicy, now: u32, decayed: &mut Decayed) {
+        self.0.retain_mut(|successor| {
+            let elapsed = Duration::from_secs(now.saturating_sub(successor.updated).into());
+            let (weight, consumed) = policy.decay(successor.weight, elapsed);
+            if weight == successor.weight {
+                return true;
+            }
+            decayed.decayed += 1;
+            if weight == 0 {
+                decayed.removed += 1;
+                return false;
+            }
+            successor.weight = weight;
+            let consumed = u32::try_from(consumed.as_secs()).unwrap_or(u32::MAX);
+            successor.updated = successor.updated.saturating_add(consumed);
+            true
+        });
+    }
+}
+
+/// Cumulative weights of high-branching states, built when a state is first
+/// sampled and dropped when it learns a transition. Once the tables hold more
+/// than `capacity` weights, the least recently used ones are evicted.
+#[derive(Debug)]
+struct CumulativeCache<const N: usize> {
+    tables: HashMap<[u32; N], (Arc<[u64]>, u64)>,
+    capacity: usize,
+    weights: usize,
+    clock: u64,
+}
+
+impl<const N: usize> CumulativeCache<N> {
+    fn new(capacity: usize) -> CumulativeCache<N> {
+        CumulativeCache {
+            tables: HashMap::new(),
+            capacity,
+            weights: 0,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, ids: &[u32; N]) -> Option<Arc<[u64]>> {
+        self.clock += 1;
+        let (table, used) = self.tables.get_mut(ids)?;
+        *used = self.clock;
+        Some(table.clone())
+    }
+
+    fn insert(&mut self, ids: [u32; N], table: Arc<[u64]>) {
+        self.clock += 1;
+        self.weights += table.len();
+        if let Some((previous, _)) = self.tables.insert(ids, (table, self.clock)) {
+            self.weights -= previous.len();
+        }
+        if self.weights > self.capacity {
+            self.evict();
+        }
+    }
+
+    // Evicts down to half the capacity, so that the sort is amortized over
+    // many insertions.
+    fn evict(&mut self) {
+        let mut used: Vec<_> = self
+            .tables
+            .iter()
+            .map(|(ids, (_, used))| (*used, *ids))
+            .collect();
+        used.sort_unstable();
+        for (_, ids) in used {
+            if self.weights <= self.capacity / 2 {
+                break;
+            }
+            self.remove(&ids);
+        }
+    }
+
+    fn remove(&mut self, ids: &[u32; N]) {
+        if let Some((table, _)) = self.tables.remove(ids) {
+            self.weights -= table.len();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.tables.clear();
+        self.weights = 0;
+    }
+}
+
+#[derive(Debug)]
+pub struct InternedChain<T, const N: usize> {
+    pub words: Dictionary<T>,
+    pub states: HashMap<[u32; N], Successors>,
+    cumulative: Mutex<CumulativeCache<N>>,
+}
+
+impl<T, const N: usize> InternedChain<T, N>
+where
+    T: Eq + Hash,
+{
+    pub fn new() -> InternedChain<T, N> {
+        InternedChain {
+            words: Dictionary::new(),
+            states: HashMap::new(),
+            cumulative: Mutex::new(CumulativeCache::new(CUMULATIVE_CAPACITY)),
+        }
+    }
+
+    /// Ids of the words of `state`, or `None` if some word is unknown.
+    pub fn state_ids(&self, state: &[T; N]) -> Option<[u32; N]> {
+        let mut ids = [0; N];
+        for (id, word) in ids.iter_mut().zip(state) {
+            *id = self.words.id(word)?;
+        }
+        Some(ids)
+    }
+
+    pub fn get(&self, from: &[T; N]) -> WeightMap<T>
+    where
+        T: Clone,
+    {
+        let successors = self.state_ids(from).and_then(|ids| self.states.get(&ids));
+        match successors {
+            Some(successors) => successors
+                .iter()
+                .map(|(id, weight)| (self.words.word(id).clone(), weight))
+                .collect(),
+            None => WeightMap::new(),
+        }
+    }
+
+    /// Adds `weight` to `link`, learned at the [`timestamp`] `now`.
+    pub fn add_weight(&mut self, link: &Link<T, N>, weight: u32, now: u32)
+    where
+        T: Clone,
+    {
+        // Only words seen for the first time are copied.
+        let mut intern = |word: &T| match self.words.id(word) {
+            Some(id) => id,
+            None => self.words.intern(word.clone()),
+        };
+        let from = link.from.each_ref().map(&mut intern);
+        let to = intern(&link.to);
+        self.states.entry(from).or_default().add(to, weight, now);
+        self.cumulative
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&from);
+    }
+
+    /// Picks a successor of `from` with probability proportional to its
+    /// weight, or returns `None` if `from` has no successors. `random` returns
+    /// a number below the one it's given.
+    pub fn sample(&self, from: &[T; N], random: impl FnOnce(u32) -> u32) -> Option<&T> {
+        let ids = self.state_ids(from)?;
+        let successors = self.states.get(&ids)?;
+        // Weights are drawn as u32, so larger totals are cut off.
+        let total = u32::try_from(successors.total()).unwrap_or(u32::MAX);
+        if total == 0 {
+            return None;
+        }
+        let position = u64::from(random(total));
+        let id = if successors.len() < CUMULATIVE_THRESHOLD {
+            successors.at(position)
+        } else {
+            let cumulative = self.cumulative(&ids, successors);
+            successors.0[cumulative.partition_point(|&sum| sum <= position)].id
+        };
+        Some(self.words.word(id))
+    }
+
+    /// Decays weights as of the [`timestamp`] `now`, removing transitions
+    /// decayed to zero, and states and words no longer used by any.
+    pub fn decay(&mut self, policy: &DecayPolicy, now: u32) -> Decayed {
+        let mut decayed = Decayed::default();
+        self.states.retain(|_, successors| {
+            successors.decay(policy, now, &mut decayed);
+            successors.len() > 0
+        });
+        if decayed.decayed > 0 {
+            self.cumulative
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        if decayed.removed > 0 {
+            self.remove_unused_words();
+        }
+        decayed
+    }
+
+    fn remove_unused_words(&mut self) {
+        let mut used = vec![false; self.words.len()];
+        for (from, successors) in &self.states {
+            for &id in from {
+                used[id as usize] = true;
+            }
+            for (id, _) in successors.iter() {
+                used[id as usize] = true;
+            }
+        }
+        if used.iter().all(|&used| used) {
+            return;
+        }
+        let ids = self.words.retain(&used);
+        self.states = mem::take(&mut self.states)
+            .into_iter()
+            .map(|(from, mut successors)| {
+                for successor in &mut successors.0 {
+                    successor.id = ids[successor.id as usize];
+                }
+                successors.0.sort_unstable_by_key(|successor| successor.id);
+                (from.map(|id| ids[id as usize]), successors)
+            })
+            .collect();
+    }
+
+    fn cumulative(&self, ids: &[u32; N], successors: &Successors) -> Arc<[u64]> {
+        let cached = self
+            .cumulative
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(ids);
+        cached.unwrap_or_else(|| {
+            // Built without holding the lock, so that sampling other states
+            // doesn't wait for it.
+            let cumulative = successors.cumulative();
+            self.cumulative
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(*ids, cumulative.clone());
+            cumulative
+        })
+    }
+}
+
+impl<T, const N: usize> InternedChain<T, N> {
+    pub fn state(&self, ids: &[u32; N]) -> [T; N]
+    where
+        T: Clone,
+    {
+        ids.map(|id| self.words.word(id).clone())
+    }
+
+    pub fn transitions(&self) -> usize {
+        self.states.values().map(Successors::len).sum()
+    }
+}
+
+impl<T, const N: usize> Default for InternedChain<T, N>
+where
+    T: Eq + Hash,
+{
+    fn default() -> InternedChain<T, N> {
+        InternedChain::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::{CumulativeCache, Dictionary, InternedChain, Successors, CUMULATIVE_THRESHOLD};
+    use crate::markov::decay::{DecayPolicy, Decayed};
+    use crate::markov::types::{Link, WeightMap};
+
+    // Samples `from` once at every position, so that each successor is
+    // picked exactly as many times as its weight.
+    fn sample_all(chain: &InternedChain<u32, 1>, from: u32) -> HashMap<u32, u32> {
+        let total: u32 = chain.get(&[from]).values().sum();
+        let mut picked = HashMap::new();
+        for position in 0..total {
+            let to = chain.sample(&[from], |bound| {
+                assert_eq!(bound, total);
+                position
+            });
+            *picked.entry(*to.unwrap()).or_default() += 1;
+        }
+        picked
+    }
+
+    #[test]
+    fn dictionary_interns_each_word_once() {
+        let mut dictionary = Dictionary::new();
+
+        assert_eq!(dictionary.intern("a"), 0);
+        assert_eq!(dictionary.intern("b"), 1);
+        assert_eq!(dictionary.intern("a"), 0);
+        assert_eq!(dictionary.len(), 2);
+        assert_eq!(dictionary.id(&"b"), Some(1));
+        assert_eq!(dictionary.id(&"c"), None);
+        assert_eq!(*dictionary.word(1), "b");
+    }
+
+    #[test]
+    fn successors_stay_sorted_and_accumulate() {
+        let mut successors = Successors::default();
+        successors.add(5, 1, 0);
+        successors.add(2, 1, 0);
+        successors.add(5, 2, 0);
+        successors.add(7, u32::MAX, 0);
+        successors.add(7, 1, 0);
+
+        assert_eq!(
+            successors.iter().collect::<Vec<_>>(),
+            [(2, 1), (5, 3), (7, u32::MAX)]
+        );
+    }
+
+    #[test]
+    fn shares_words_between_states() {
+        let mut chain: InternedChain<String, 2> = InternedChain::new();
+        let link = |from: [&str; 2], to: &str| Link::new(from.map(str::to_string), to.to_string());
+        chain.add_weight(&link(["a", "b"], "c"), 1, 0);
+        chain.add_weight(&link(["b", "c"], "a"), 2, 0);
+
+        assert_eq!(chain.words.len(), 3);
+        assert_eq!(chain.transitions(), 2);
+        assert_eq!(
+            chain.get(&["b", "c"].map(str::to_string)),
+            WeightMap::from([("a".to_string(), 2)])
+        );
+        assert_eq!(chain.get(&["c", "x"].map(str::to_string)), WeightMap::new());
+    }
+
+    #[test]
+    fn samples_successors_by_weight() {
+        let mut chain = InternedChain::new();
+        chain.add_weight(&Link::new([0], 1), 3, 0);
+        chain.add_weight(&Link::new([0], 2), 1, 0);
+
+        assert_eq!(sample_all(&chain, 0), chain.get(&[0]));
+        assert_eq!(chain.sample(&[1], |_| 0), None);
+        assert_eq!(chain.sample(&[5], |_| 0), None);
+    }
+
+    #[test]
+    fn samples_high_branching_states_by_weight() {
+        let mut chain = InternedChain::new();
+        for to in 1..=CUMULATIVE_THRESHOLD as u32 * 2 {
+            chain.add_weight(&Link::new([0], to), to % 3 + 1, 0);
+        }
+        assert_eq!(sample_all(&chain, 0), chain.get(&[0]));
+
+        // Learning invalidates the cached table.
+        chain.add_weight(&Link::new([0], 1), 5, 0);
+        chain.add_weight(&Link::new([0], 1000), 2, 0);
+        assert_eq!(sample_all(&chain, 0), chain.get(&[0]));
+    }
+
+    #[test]
+    fn cumulative_cache_evicts_least_recently_used() {
+        let mut cache = CumulativeCache::new(6);
+        let table = |len: u64| (1..=len).collect();
+        cache.insert([0], table(2));
+        cache.insert([1], table(2));
+        cache.insert([2], table(2));
+        assert!(cache.get(&[0]).is_some());
+        cache.insert([3], table(1));
+
+        assert_eq!(cache.weights, 3);
+        assert!(cache.get(&[1]).is_none());
+        assert!(cache.get(&[2]).is_none());
+        assert_eq!(cache.get(&[0]).as_deref(), Some(&[1, 2][..]));
+        assert_eq!(cache.get(&[3]).as_deref(), Some(&[1][..]));
+
+        cache.insert([3], table(3));
+        assert_eq!(cache.weights, 5);
+        cache.remove(&[3]);
+        assert_eq!(cache.weights, 2);
+    }
+
+    #[test]
+    fn zero_weights_are_never_sampled() {
+        let mut chain = InternedChain::new();
+        chain.add_weight(&Link::new([0], 1), 0, 0);
+
+        assert_eq!(chain.sample(&[0], |_| 0), None);
+
+        chain.add_weight(&Link::new([0], 2), 1, 0);
+        assert_eq!(chain.sample(&[0], |_| 0), Some(&2));
+    }
+
+    #[test]
+    fn decay_removes_unused_words() {
+        let policy = DecayPolicy {
+            half_life: Duration::from_secs(100),
+        };
+        let mut chain: InternedChain<String, 1> = InternedChain::new();
+        let link = |from: &str, to: &str| Link::new([from.to_string()], to.to_string());
+        chain.add_weight(&link("a", "b"), 1, 0);
+        chain.add_weight(&link("b", "c"), 1, 1000);
+        chain.add_weight(&link("c", "d"), 4, 800);
+        chain.add_weight(&link("c", "b"), 4, 1000);
+
+        let decayed = chain.decay(&policy, 1000);
+
+        assert_eq!(
+            decayed,
+            Decayed {
+                decayed: 2,
+                removed: 1
+            }
+        );
+        assert_eq!(chain.words.len(), 3);
+        assert_eq!(chain.words.id(&"a".to_string()), None);
+        assert_eq!(chain.states.len(), 2);
+        assert_eq!(
+            chain.get(&["c".to_string()]),
+            WeightMap::from([("b".to_string(), 4), ("d".to_string(), 1)])
+        );
+        assert_eq!(chain.sample(&["c".to_string()], |_| 4).unwrap(), "d");
+    }
+}
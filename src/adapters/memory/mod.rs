@@ -0,0 +1,3 @@
+mod interned;
+pub mod repository;
+pub mod snapshot;
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use rand::seq::IteratorRandom;
+use rand::thread_rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::instrument;
+
+use super::interned::{self, InternedChain};
+use super::snapshot::Snapshot;
+use crate::markov::choose::Choose;
+use crate::markov::decay::{DecayPolicy, Decayed};
+use crate::markov::repository::Repository;
+use crate::markov::stats::{self, StateStats, Stats};
+use crate::markov::types::{Link, Size, WeightMap};
+
+/// Repository keeping the whole chain in memory. Words are interned, so each
+/// distinct word is stored once however many states it appears in.
+pub struct MemoryRepository<T, const N: usize> {
+    chain: RwLock<InternedChain<T, N>>,
+    snapshot: Option<Snapshot>,
+    dirty: AtomicBool,
+}
+
+impl<T, const N: usize> MemoryRepository<T, N>
+where
+    T: Eq + Hash,
+{
+    pub fn new() -> MemoryRepository<T, N> {
+        MemoryRepository {
+            chain: RwLock::new(InternedChain::new()),
+            snapshot: None,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates a repository persisted to `snapshot`, loading its contents if
+    /// the snapshot already exists. The snapshot is written on every flush.
+    pub fn with_snapshot(snapshot: Snapshot) -> Result<MemoryRepository<T, N>>
+    where
+        T: DeserializeOwned,
+    {
+        let chain = snapshot.load()?.unwrap_or_default();
+        Ok(MemoryRepository {
+            chain: RwLock::new(chain),
+            snapshot: Some(snapshot),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    // Changes panic, if at all, before modifying the chain, so a panic while
+    // holding the lock can't leave it inconsistent.
+    fn read(&self) -> RwLockReadGuard<'_, InternedChain<T, N>> {
+        self.chain.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, InternedChain<T, N>> {
+        self.chain.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Words are only interned when a transition is added, so every word of the
+// dictionary is used by some state or successor.
+fn size<T, const N: usize>(chain: &InternedChain<T, N>) -> Size {
+    Size {
+        words: chain.words.len() as u64,
+        states: chain.states.len() as u64,
+        transitions: chain.transitions() as u64,
+    }
+}
+
+impl<T, const N: usize> Default for MemoryRepository<T, N>
+where
+    T: Eq + Hash,
+{
+    fn default() -> MemoryRepository<T, N> {
+        MemoryRepository::new()
+    }
+}
+
+impl<T, const N: usize> Repository<T, N> for MemoryRepository<T, N>
+where
+    T: Clone + Eq + Hash + Serialize,
+{
+    #[instrument(level = "trace", skip_all)]
+    fn get(&self, from: &[T; N]) -> Result<WeightMap<T>> {
+        Ok(self.read().get(from))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn sample(&self, from: &[T; N], chooser: &dyn Choose<T>) -> Result<Option<T>> {
+        let chain = self.read();
+        let to = chain.sample(from, |bound| chooser.generate_random(bound));
+        Ok(to.cloned())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random(&self) -> Result<Option<[T; N]>> {
+        let mut rng = thread_rng();
+        let chain = self.read();
+        let random = chain.states.keys().choose(&mut rng);
+        Ok(random.map(|ids| chain.state(ids)))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random_starting_with(&self, state: &T) -> Result<Option<[T; N]>> {
+        let mut rng = thread_rng();
+        let chain = self.read();
+        let id = match chain.words.id(state) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let random = chain
+            .states
+            .keys()
+            .filter(|ids| ids.first() == Some(&id))
+            .choose(&mut rng);
+        Ok(random.map(|ids| chain.state(ids)))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_weight(&self, link: Link<T, N>, weight: u32) -> Result<()> {
+        let now = interned::timestamp(SystemTime::now());
+        self.write().add_weight(&link, weight, now);
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_weights(&self, links: &[(Link<T, N>, u32)]) -> Result<()> {
+        let now = interned::timestamp(SystemTime::now());
+        let mut chain = self.write();
+        for (link, weight) in links {
+            chain.add_weight(link, *weight, now);
+        }
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn size(&self) -> Result<Size> {
+        Ok(size(&self.read()))
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn stats(&self, top: usize) -> Result<Stats<T, N>> {
+        let chain = self.read();
+        let mut word_weights: HashMap<u32, u64> = HashMap::new();
+        let mut states = Vec::with_capacity(chain.states.len());
+        let mut total_weight = 0;
+        let mut weighted_entropy = 0.0;
+
+        for (from, successors) in chain.states.iter() {
+            let weight: u64 = successors.weights().map(u64::from).sum();
+            let entropy = stats::entropy(successors.weights());
+            for (to, w) in successors.iter() {
+                *word_weights.entry(to).or_default() += u64::from(w);
+            }
+            total_weight += weight;
+            weighted_entropy += weight as f64 * entropy;
+            states.push((from, weight, entropy));
+        }
+
+        let top_words = stats::top(word_weights, top, |(_, weight)| *weight)
+            .into_iter()
+            .map(|(word, weight)| (chain.words.word(word).clone(), weight))
+            .collect();
+        let top_states = stats::top(states, top, |(_, weight, _)| *weight)
+            .into_iter()
+            .map(|(state, weight, entropy)| StateStats {
+                state: chain.state(state),
+                weight,
+                entropy,
+            })
+            .collect();
+
+        Ok(Stats {
+            size: size(&chain),
+            total_weight,
+            top_words,
+            top_states,
+            mean_entropy: if total_weight > 0 {
+                weighted_entropy / total_weight as f64
+            } else {
+                0.0
+            },
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn for_each_transition(&self, f: &mut dyn FnMut(Link<T, N>, u32) -> Result<()>) -> Result<()> {
+        let chain = self.read();
+        for (from, successors) in chain.states.iter() {
+            let from = chain.state(from);
+            for (to, weight) in successors.iter() {
+                f(
+                    Link::new(from.clone(), chain.words.word(to).clone()),
+                    weight,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        let decayed = self.write().decay(policy, interned::timestamp(now));
+        if decayed.decayed > 0 {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(decayed)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn flush(&self) -> Result<()> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        // Changes made while saving mark the repository dirty again.
+        if self.dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = snapshot.save(&self.read()) {
+                self.dirty.store(true, Ordering::Release);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::MemoryRepository;
+    use crate::adapters::memory::snapshot::Snapshot;
+    use crate::markov::conformance::{conformance_tests, decay_tests};
+    use crate::markov::repository::Repository;
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::{Link, WeightMap};
+
+    fn repository<const N: usize>() -> MemoryRepository<String, N> {
+        MemoryRepository::new()
+    }
+
+    conformance_tests!(repository);
+    decay_tests!(repository);
+
+    #[test]
+    fn get_returns_empty_if_missing() {
+        let repository: MemoryRepository<i32, 3> = MemoryRepository::new();
+
+        assert_eq!(repository.get(&[1, 2, 3]).unwrap(), WeightMap::new());
+    }
+
+    #[test]
+    fn get_returns_requested_map() {
+        let mut repository: MemoryRepository<i32, 3> = MemoryRepository::new();
+        let chain = repository.chain.get_mut().unwrap();
+        chain.add_weight(&Link::new([1, 2, 3], 4), 2, 0);
+        chain.add_weight(&Link::new([1, 2, 3], 5), 1, 0);
+
+        assert_eq!(
+            repository.get(&[1, 2, 3]).unwrap(),
+            WeightMap::from([(4, 2), (5, 1)])
+        );
+    }
+
+    #[test]
+    fn increment_weight_sets_weight_to_1_if_missing() {
+        let repository: MemoryRepository<i32, 3> = MemoryRepository::new();
+        let link = Link::new([1, 2, 3], 4);
+        repository.increment_weight(link).unwrap();
+
+        assert_eq!(repository.get(&[1, 2, 3]).unwrap()[&4], 1);
+    }
+
+    #[test]
+    fn increments_weight_by_1() {
+        let mut repository: MemoryRepository<i32, 3> = MemoryRepository::new();
+        let chain = repository.chain.get_mut().unwrap();
+        chain.add_weight(&Link::new([1, 2, 3], 4), 1, 0);
+        let link = Link::new([1, 2, 3], 4);
+        repository.increment_weight(link).unwrap();
+
+        assert_eq!(repository.get(&[1, 2, 3]).unwrap()[&4], 2);
+    }
+
+    #[test]
+    fn stores_each_word_once() {
+        let repository: MemoryRepository<String, 2> = MemoryRepository::new();
+        let words = ["a", "b", "c", "a", "c", "b", "a"].map(str::to_string);
+        for window in words.windows(3) {
+            let from = [window[0].clone(), window[1].clone()];
+            repository
+                .increment_weight(Link::new(from, window[2].clone()))
+                .unwrap();
+        }
+
+        let chain = repository.read();
+        assert_eq!(chain.words.len(), 3);
+        assert_eq!(chain.states.len(), 5);
+    }
+
+    #[test]
+    fn size_counts_distinct_words_states_and_transitions() {
+        let repository: MemoryRepository<i32, 2> = MemoryRepository::new();
+        repository.increment_weight(Link::new([1, 2], 3)).unwrap();
+        repository.increment_weight(Link::new([1, 2], 3)).unwrap();
+        repository.increment_weight(Link::new([1, 2], 4)).unwrap();
+        repository.increment_weight(Link::new([2, 3], 1)).unwrap();
+
+        let size = repository.size().unwrap();
+
+        assert_eq!(size.words, 4);
+        assert_eq!(size.states, 2);
+        assert_eq!(size.transitions, 3);
+    }
+
+    #[test]
+    fn stats_summarize_chain() {
+        let repository: MemoryRepository<i32, 1> = MemoryRepository::new();
+        for link in [([1], 2), ([1], 3), ([1], 2), ([2], 3), ([1], 2)] {
+            repository
+                .increment_weight(Link::new(link.0, link.1))
+                .unwrap();
+        }
+
+        let stats = repository.stats(1).unwrap();
+
+        assert_eq!(stats.total_weight, 5);
+        assert_eq!(stats.top_words, vec![(2, 3)]);
+        assert_eq!(stats.top_states.len(), 1);
+        assert_eq!(stats.top_states[0].state, [1]);
+        assert_eq!(stats.top_states[0].weight, 4);
+        assert!((stats.top_states[0].entropy - 0.811).abs() < 1e-3);
+        assert!((stats.mean_entropy - 4.0 * 0.811 / 5.0).abs() < 1e-3);
+        assert_eq!(stats.branching_factor(), 1.5);
+    }
+
+    #[test]
+    fn flush_persists_snapshot() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        let repository: MemoryRepository<i32, 2> =
+            MemoryRepository::with_snapshot(Snapshot::new(&path, Tokenizer::Whitespace)).unwrap();
+        repository.increment_weight(Link::new([1, 2], 3)).unwrap();
+        repository.flush().unwrap();
+
+        let repository: MemoryRepository<i32, 2> =
+            MemoryRepository::with_snapshot(Snapshot::new(&path, Tokenizer::Whitespace)).unwrap();
+
+        assert_eq!(repository.get(&[1, 2]).unwrap()[&3], 1);
+    }
+
+    #[test]
+    fn flush_skips_unchanged_repository() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        let repository: MemoryRepository<i32, 2> =
+            MemoryRepository::with_snapshot(Snapshot::new(&path, Tokenizer::Whitespace)).unwrap();
+        repository.flush().unwrap();
+
+        assert!(!path.exists());
+    }
+}
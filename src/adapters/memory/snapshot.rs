@@ -0,0 +1,392 @@
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, ensure, Context, Result};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use super::interned::{self, InternedChain};
+use crate::markov::tokenizer::Tokenizer;
+
+const MAGIC: &[u8; 8] = b"MKVSNAP\0";
+const VERSION: u32 = 2;
+// Version 1 didn't store when transitions were learned.
+const UNTIMED_VERSION: u32 = 1;
+// Upper bound for preallocations driven by counts read from the file, so a
+// corrupted header can't make us allocate absurd amounts of memory up front.
+const MAX_PREALLOCATION: usize = 1 << 16;
+
+/// Binary snapshot of a [`MemoryRepository`](super::repository::MemoryRepository).
+///
+/// The file starts with a header: magic bytes, format version, order of the
+/// chain and name of the tokenizer, followed by a table of distinct words and
+/// a list of states referencing the words by their index, i.e. the interned
+/// chain as it is kept in memory. Every successor is stored with its weight
+/// and when it was last learned. All integers are little-endian and words are
+/// encoded with bincode.
+///
+/// Snapshots of version 1 are still read, as if every transition was learned
+/// when the snapshot is loaded.
+pub struct Snapshot {
+    path: PathBuf,
+    tokenizer: Tokenizer,
+}
+
+impl Snapshot {
+    pub fn new(path: impl Into<PathBuf>, tokenizer: Tokenizer) -> Snapshot {
+        Snapshot {
+            path: path.into(),
+            tokenizer,
+        }
+    }
+
+    /// Loads the snapshot, or returns `None` if it doesn't exist yet.
+    #[instrument(skip_all, fields(path = %self.path.display()))]
+    pub(super) fn load<T, const N: usize>(&self) -> Result<Option<InternedChain<T, N>>>
+    where
+        T: DeserializeOwned + Eq + Hash,
+    {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to open snapshot"),
+        };
+        let file_len = file.metadata().context("Failed to open snapshot")?.len();
+        let chain = self
+            .read(&mut BufReader::new(file), file_len)
+            .with_context(|| format!("Failed to load snapshot {}", self.path.display()))?;
+        info!(states = chain.states.len(), "Loaded snapshot");
+        Ok(Some(chain))
+    }
+
+    /// Writes the snapshot to a temporary file and atomically moves it into
+    /// place, so a crash never leaves a truncated snapshot behind.
+    #[instrument(skip_all, fields(path = %self.path.display()))]
+    pub(super) fn save<T, const N: usize>(&self, chain: &InternedChain<T, N>) -> Result<()>
+    where
+        T: Eq + Hash + Serialize,
+    {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer, chain)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+        sync_parent(&self.path)?;
+
+        info!(states = chain.states.len(), "Saved snapshot");
+        Ok(())
+    }
+
+    fn write<T, const N: usize>(
+        &self,
+        writer: &mut impl Write,
+        chain: &InternedChain<T, N>,
+    ) -> Result<()>
+    where
+        T: Eq + Hash + Serialize,
+    {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        write_u32(writer, N as u32)?;
+        let tokenizer = self.tokenizer.name().as_bytes();
+        write_u32(writer, tokenizer.len() as u32)?;
+        writer.write_all(tokenizer)?;
+
+        write_u64(writer, chain.words.len() as u64)?;
+        for word in chain.words.iter() {
+            bincode::serialize_into(&mut *writer, word)?;
+        }
+
+        write_u64(writer, chain.states.len() as u64)?;
+        for (from, successors) in &chain.states {
+            for &id in from {
+                write_u32(writer, id)?;
+            }
+            write_u32(writer, successors.len() as u32)?;
+            for successor in successors.entries() {
+                write_u32(writer, successor.id)?;
+                write_u32(writer, successor.weight)?;
+                write_u32(writer, successor.updated)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a snapshot of `file_len` bytes. No word is longer than that, so
+    /// a corrupted length can't make us allocate more.
+    fn read<T, const N: usize>(
+        &self,
+        reader: &mut impl Read,
+        file_len: u64,
+    ) -> Result<InternedChain<T, N>>
+    where
+        T: DeserializeOwned + Eq + Hash,
+    {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "Not a snapshot file");
+        let version = read_u32(reader)?;
+        ensure!(
+            version == VERSION || version == UNTIMED_VERSION,
+            "Unsupported snapshot version {}",
+            version
+        );
+        let loaded = interned::timestamp(SystemTime::now());
+        let order = read_u32(reader)? as usize;
+        ensure!(
+            order == N,
+            "Snapshot was created with order {}, but order {} is used",
+            order,
+            N
+        );
+        let mut tokenizer = Vec::new();
+        let len = read_u32(reader)? as u64;
+        reader.take(len).read_to_end(&mut tokenizer)?;
+        ensure!(tokenizer.len() as u64 == len, "Unexpected end of snapshot");
+        let tokenizer = String::from_utf8(tokenizer)?;
+        if tokenizer != self.tokenizer.name() {
+            bail!(
+                "Snapshot was created with {} tokenizer, but {} is used",
+                tokenizer,
+                self.tokenizer.name()
+            );
+        }
+
+        // Same encoding as `bincode::serialize_into`, which wrote the words.
+        let words = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(file_len);
+        let mut chain = InternedChain::new();
+        // Ids of the file may differ from the ones assigned by the dictionary,
+        // e.g. if the file lists a word twice.
+        let ids = (0..read_u64(reader)?)
+            .map(|_| {
+                let word = words.deserialize_from(&mut *reader)?;
+                Ok(chain.words.intern(word))
+            })
+            .collect::<Result<Vec<u32>>>()?;
+        let id = |id: u32| {
+            ids.get(id as usize)
+                .copied()
+                .with_context(|| format!("Invalid word id {}", id))
+        };
+
+        let states = read_u64(reader)?;
+        chain
+            .states
+            .reserve((states as usize).min(MAX_PREALLOCATION));
+        for _ in 0..states {
+            let mut from = [0; N];
+            for word in &mut from {
+                *word = id(read_u32(reader)?)?;
+            }
+            let successors = chain.states.entry(from).or_default();
+            for _ in 0..read_u32(reader)? {
+                let to = id(read_u32(reader)?)?;
+                let weight = read_u32(reader)?;
+                let updated = match version {
+                    UNTIMED_VERSION => loaded,
+                    _ => read_u32(reader)?,
+                };
+                successors.add(to, weight, updated);
+            }
+        }
+
+        Ok(chain)
+    }
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{write_u32, write_u64, Snapshot, MAGIC, UNTIMED_VERSION, VERSION};
+    use crate::adapters::memory::interned::InternedChain;
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::{Link, WeightMap};
+
+    fn state(words: [&str; 2]) -> [String; 2] {
+        words.map(str::to_string)
+    }
+
+    fn chain() -> InternedChain<String, 2> {
+        let mut chain = InternedChain::new();
+        chain.add_weight(&Link::new(state(["a", "b"]), "c".to_string()), 3, 0);
+        chain.add_weight(&Link::new(state(["a", "b"]), "a".to_string()), 1, 0);
+        chain.add_weight(&Link::new(state(["b", "c"]), "zażółć".to_string()), 7, 100);
+        chain
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempdir().unwrap();
+        let snapshot = Snapshot::new(dir.path().join("chain.snap"), Tokenizer::Whitespace);
+        snapshot.save(&chain()).unwrap();
+
+        let loaded = snapshot.load::<String, 2>().unwrap().unwrap();
+        assert_eq!(loaded.words.len(), 4);
+        assert_eq!(loaded.states.len(), 2);
+        assert_eq!(
+            loaded.get(&state(["a", "b"])),
+            WeightMap::from([("c".to_string(), 3), ("a".to_string(), 1)])
+        );
+        assert_eq!(
+            loaded.get(&state(["b", "c"])),
+            WeightMap::from([("zażółć".to_string(), 7)])
+        );
+        assert_eq!(
+            loaded
+                .states
+                .values()
+                .flat_map(|s| s.entries())
+                .map(|s| s.updated)
+                .max(),
+            Some(100)
+        );
+        assert!(!dir.path().join("chain.snap.tmp").exists());
+    }
+
+    #[test]
+    fn rejects_word_longer_than_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        let mut bytes = MAGIC.to_vec();
+        write_u32(&mut bytes, VERSION).unwrap();
+        write_u32(&mut bytes, 2).unwrap();
+        write_u32(&mut bytes, 10).unwrap();
+        bytes.extend_from_slice(b"whitespace");
+        write_u64(&mut bytes, 1).unwrap();
+        // Length of a word that would take a terabyte.
+        write_u64(&mut bytes, 1 << 40).unwrap();
+        bytes.extend_from_slice(b"word");
+        fs::write(&path, bytes).unwrap();
+
+        let error = Snapshot::new(path, Tokenizer::Whitespace)
+            .load::<String, 2>()
+            .unwrap_err();
+
+        assert!(format!("{:#}", error).contains("limit"));
+    }
+
+    #[test]
+    fn reads_untimed_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        let mut bytes = MAGIC.to_vec();
+        write_u32(&mut bytes, UNTIMED_VERSION).unwrap();
+        write_u32(&mut bytes, 2).unwrap();
+        write_u32(&mut bytes, 10).unwrap();
+        bytes.extend_from_slice(b"whitespace");
+        write_u64(&mut bytes, 2).unwrap();
+        for word in ["a", "b"] {
+            bincode::serialize_into(&mut bytes, word).unwrap();
+        }
+        write_u64(&mut bytes, 1).unwrap();
+        for value in [0, 0, 1, 1, 5] {
+            write_u32(&mut bytes, value).unwrap();
+        }
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = Snapshot::new(path, Tokenizer::Whitespace)
+            .load::<String, 2>()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            loaded.get(&state(["a", "a"])),
+            WeightMap::from([("b".to_string(), 5)])
+        );
+        let updated = loaded.states.values().next().unwrap().entries()[0].updated;
+        assert!(updated > 0);
+    }
+
+    #[test]
+    fn missing_file_loads_nothing() {
+        let dir = tempdir().unwrap();
+        let snapshot = Snapshot::new(dir.path().join("chain.snap"), Tokenizer::Whitespace);
+
+        assert!(snapshot.load::<String, 2>().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_different_order() {
+        let dir = tempdir().unwrap();
+        let snapshot = Snapshot::new(dir.path().join("chain.snap"), Tokenizer::Whitespace);
+        snapshot.save(&chain()).unwrap();
+
+        let error = snapshot.load::<String, 3>().unwrap_err();
+
+        assert!(format!("{:#}", error).contains("order 2"));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        fs::write(&path, b"definitely not a snapshot").unwrap();
+        let snapshot = Snapshot::new(path, Tokenizer::Whitespace);
+
+        assert!(snapshot.load::<String, 2>().is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.snap");
+        let snapshot = Snapshot::new(&path, Tokenizer::Whitespace);
+        snapshot.save(&chain()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+
+        assert!(snapshot.load::<String, 2>().is_err());
+    }
+}
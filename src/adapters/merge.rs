@@ -0,0 +1,159 @@
+use anyhow::{ensure, Result};
+use tracing::{info, instrument};
+
+use crate::markov::repository::Repository;
+use crate::markov::types::Link;
+
+// Number of transitions stored at once while merging.
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeSummary {
+    /// Transitions added to the target repository.
+    pub merged: u64,
+    /// Transitions whose scaled weight was rounded down to zero.
+    pub skipped: u64,
+}
+
+/// Adds every transition of `source` to `target`, with its weight multiplied
+/// by `scale` and rounded to the nearest integer. Transitions are matched by
+/// their words, so the repositories don't have to share any internal ids.
+#[instrument(skip(target, source))]
+pub fn merge<T, R, S, const N: usize>(target: &R, source: &S, scale: f64) -> Result<MergeSummary>
+where
+    R: Repository<T, N> + ?Sized,
+    S: Repository<T, N> + ?Sized,
+{
+    ensure!(
+        scale.is_finite() && scale > 0.0,
+        "Scale must be a positive number, got {}",
+        scale
+    );
+
+    let mut summary = MergeSummary::default();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    source.for_each_transition(&mut |link: Link<T, N>, weight| {
+        // Float to int casts saturate, so huge weights are capped at u32::MAX.
+        let weight = (f64::from(weight) * scale).round() as u32;
+        if weight == 0 {
+            summary.skipped += 1;
+            return Ok(());
+        }
+        batch.push((link, weight));
+        summary.merged += 1;
+        if batch.len() == BATCH_SIZE {
+            target.add_weights(&batch)?;
+            batch.clear();
+        }
+        Ok(())
+    })?;
+    target.add_weights(&batch)?;
+
+    info!(
+        merged = summary.merged,
+        skipped = summary.skipped,
+        "Merged chain"
+    );
+    Ok(summary)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use rusqlite::Connection;
+    use tempfile::tempdir;
+
+    use super::{merge, MergeSummary};
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::adapters::sqlite::repository::SqliteRepository;
+    use crate::adapters::sqlite::schema;
+    use crate::markov::repository::Repository;
+    use crate::markov::types::Link;
+
+    fn link(from: [&str; 2], to: &str) -> Link<String, 2> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    fn state(from: [&str; 2]) -> [String; 2] {
+        from.map(str::to_string)
+    }
+
+    fn sqlite(links: &[([&str; 2], &str, u32)]) -> SqliteRepository {
+        let connection = Connection::open_in_memory().unwrap();
+        let repository = SqliteRepository::new::<2>(connection).unwrap();
+        for &(from, to, weight) in links {
+            Repository::<String, 2>::add_weight(&repository, link(from, to), weight).unwrap();
+        }
+        repository
+    }
+
+    #[test]
+    fn sums_weights_of_sources() {
+        let a = sqlite(&[(["a", "b"], "c", 2), (["b", "c"], "d", 1)]);
+        // Words are inserted in a different order, so their ids differ from `a`.
+        let b = sqlite(&[(["x", "y"], "z", 5), (["a", "b"], "c", 3)]);
+        let target = sqlite(&[(["a", "b"], "x", 1)]);
+
+        merge::<String, _, _, 2>(&target, &a, 1.0).unwrap();
+        merge::<String, _, _, 2>(&target, &b, 1.0).unwrap();
+
+        let weights = Repository::<String, 2>::get(&target, &state(["a", "b"])).unwrap();
+        assert_eq!(weights["c"], 5);
+        assert_eq!(weights["x"], 1);
+        let weights = Repository::<String, 2>::get(&target, &state(["x", "y"])).unwrap();
+        assert_eq!(weights["z"], 5);
+    }
+
+    #[test]
+    fn scales_and_rounds_weights() {
+        let source = sqlite(&[
+            (["a", "b"], "c", 5),
+            (["a", "b"], "d", 1),
+            (["b", "c"], "d", 3),
+        ]);
+        let target: MemoryRepository<String, 2> = MemoryRepository::new();
+
+        let summary = merge(&target, &source, 0.4).unwrap();
+
+        assert_eq!(
+            summary,
+            MergeSummary {
+                merged: 2,
+                skipped: 1
+            }
+        );
+        let weights = target.get(&state(["a", "b"])).unwrap();
+        assert_eq!(weights["c"], 2);
+        assert!(!weights.contains_key("d"));
+        assert_eq!(target.get(&state(["b", "c"])).unwrap()["d"], 1);
+    }
+
+    #[test]
+    fn rejects_invalid_scale() {
+        let source: MemoryRepository<String, 2> = MemoryRepository::new();
+        let target: MemoryRepository<String, 2> = MemoryRepository::new();
+
+        assert!(merge(&target, &source, 0.0).is_err());
+        assert!(merge(&target, &source, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn reads_outdated_source_only_once_migrated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("v1.db");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(include_str!("sqlite/fixtures/v1.sql"))
+            .unwrap();
+
+        let error = SqliteRepository::open_read_only::<2>(&path).err().unwrap();
+        assert!(error.to_string().contains("version 1 is outdated"));
+        assert_eq!(schema::version(&connection).unwrap(), 1);
+
+        schema::migrate::<2>(&connection).unwrap();
+        let source = SqliteRepository::open_read_only::<2>(&path).unwrap();
+        let target: MemoryRepository<String, 2> = MemoryRepository::new();
+        merge(&target, &source, 1.0).unwrap();
+
+        assert_eq!(target.get(&state(["hello", "there"])).unwrap()["friend"], 3);
+    }
+}
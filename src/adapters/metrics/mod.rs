@@ -0,0 +1,9 @@
+#[cfg(not(feature = "metrics"))]
+mod noop;
+#[cfg(feature = "metrics")]
+mod prometheus;
+
+#[cfg(not(feature = "metrics"))]
+pub use noop::Metrics;
+#[cfg(feature = "metrics")]
+pub use prometheus::{serve, Metrics};
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use crate::markov::types::Size;
+
+/// Stand-in used when the crate is built without the `metrics` feature.
+pub struct Metrics;
+
+impl Metrics {
+    pub fn disabled() -> Metrics {
+        Metrics
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        false
+    }
+
+    pub fn message_queued(&self) {}
+
+    pub fn message_dequeued(&self) {}
+
+    pub fn message_learned(&self, _elapsed: Duration) {}
+
+    pub fn reply_generated(&self, _elapsed: Duration) {}
+
+    pub fn reply_sent(&self) {}
+
+    pub fn storage_error(&self, _operation: &str) {}
+
+    pub fn set_healthy(&self, _healthy: bool) {}
+
+    pub fn set_repository_size(&self, _size: Size) {}
+}
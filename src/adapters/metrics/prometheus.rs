@@ -0,0 +1,234 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use tracing::{error, info};
+
+use crate::markov::types::Size;
+
+pub struct Metrics {
+    inner: Option<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    messages_learned: IntCounter,
+    learn_seconds: Histogram,
+    generation_seconds: Histogram,
+    replies_sent: IntCounter,
+    queue_depth: IntGauge,
+    storage_errors: IntCounterVec,
+    healthy: IntGauge,
+    words: IntGauge,
+    states: IntGauge,
+    transitions: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics> {
+        let registry = Registry::new_custom(Some("markov".to_string()), None)?;
+        let inner = Inner {
+            messages_learned: IntCounter::new("messages_learned_total", "Messages learned")?,
+            learn_seconds: Histogram::with_opts(HistogramOpts::new(
+                "learn_seconds",
+                "Time spent learning a single message",
+            ))?,
+            generation_seconds: Histogram::with_opts(HistogramOpts::new(
+                "generation_seconds",
+                "Time spent generating a single reply",
+            ))?,
+            replies_sent: IntCounter::new("replies_sent_total", "Replies sent")?,
+            queue_depth: IntGauge::new("queue_depth", "Messages waiting to be processed")?,
+            storage_errors: IntCounterVec::new(
+                Opts::new("storage_errors_total", "Failed storage operations"),
+                &["operation"],
+            )?,
+            healthy: IntGauge::new("healthy", "Whether message processing is healthy")?,
+            words: IntGauge::new("repository_words", "Distinct words in the repository")?,
+            states: IntGauge::new("repository_states", "States in the repository")?,
+            transitions: IntGauge::new("repository_transitions", "Transitions in the repository")?,
+            registry,
+        };
+        inner.register()?;
+        inner.healthy.set(1);
+        Ok(Metrics { inner: Some(inner) })
+    }
+
+    pub fn disabled() -> Metrics {
+        Metrics { inner: None }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    pub fn message_queued(&self) {
+        if let Some(inner) = &self.inner {
+            inner.queue_depth.inc();
+        }
+    }
+
+    pub fn message_dequeued(&self) {
+        if let Some(inner) = &self.inner {
+            inner.queue_depth.dec();
+        }
+    }
+
+    pub fn message_learned(&self, elapsed: Duration) {
+        if let Some(inner) = &self.inner {
+            inner.messages_learned.inc();
+            inner.learn_seconds.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn reply_generated(&self, elapsed: Duration) {
+        if let Some(inner) = &self.inner {
+            inner.generation_seconds.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn reply_sent(&self) {
+        if let Some(inner) = &self.inner {
+            inner.replies_sent.inc();
+        }
+    }
+
+    pub fn storage_error(&self, operation: &str) {
+        if let Some(inner) = &self.inner {
+            inner.storage_errors.with_label_values(&[operation]).inc();
+        }
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        if let Some(inner) = &self.inner {
+            inner.healthy.set(healthy as i64);
+        }
+    }
+
+    pub fn set_repository_size(&self, size: Size) {
+        if let Some(inner) = &self.inner {
+            inner.words.set(size.words as i64);
+            inner.states.set(size.states as i64);
+            inner.transitions.set(size.transitions as i64);
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        if let Some(inner) = &self.inner {
+            TextEncoder::new().encode(&inner.registry.gather(), &mut buffer)?;
+        }
+        Ok(buffer)
+    }
+}
+
+impl Inner {
+    fn register(&self) -> Result<()> {
+        self.registry
+            .register(Box::new(self.messages_learned.clone()))?;
+        self.registry
+            .register(Box::new(self.learn_seconds.clone()))?;
+        self.registry
+            .register(Box::new(self.generation_seconds.clone()))?;
+        self.registry
+            .register(Box::new(self.replies_sent.clone()))?;
+        self.registry.register(Box::new(self.queue_depth.clone()))?;
+        self.registry
+            .register(Box::new(self.storage_errors.clone()))?;
+        self.registry.register(Box::new(self.healthy.clone()))?;
+        self.registry.register(Box::new(self.words.clone()))?;
+        self.registry.register(Box::new(self.states.clone()))?;
+        self.registry.register(Box::new(self.transitions.clone()))?;
+        Ok(())
+    }
+}
+
+/// Binds `addr` and serves the metrics in Prometheus text format at `/metrics`
+/// from a background task.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&metrics, request)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!(%addr, "Serving metrics");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!(error = %e, "Metrics server failed");
+        }
+    });
+    Ok(())
+}
+
+fn respond(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if (request.method(), request.uri().path()) != (&Method::GET, "/metrics") {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    match metrics.encode() {
+        Ok(buffer) => {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                TextEncoder::new().format_type().parse().unwrap(),
+            );
+            *response.body_mut() = Body::from(buffer);
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to encode metrics");
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+    use crate::markov::types::Size;
+
+    #[test]
+    fn encodes_recorded_values() {
+        let metrics = Metrics::new().unwrap();
+        metrics.message_learned(Duration::from_millis(5));
+        metrics.storage_error("learn");
+        metrics.set_repository_size(Size {
+            words: 3,
+            states: 2,
+            transitions: 1,
+        });
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+
+        assert!(text.contains("markov_messages_learned_total 1"));
+        assert!(text.contains("markov_storage_errors_total{operation=\"learn\"} 1"));
+        assert!(text.contains("markov_repository_words 3"));
+        assert!(text.contains("markov_learn_seconds_count 1"));
+    }
+
+    #[test]
+    fn disabled_metrics_encode_nothing() {
+        let metrics = Metrics::disabled();
+        metrics.message_learned(Duration::from_millis(5));
+
+        assert!(metrics.encode().unwrap().is_empty());
+    }
+}
@@ -1,4 +1,16 @@
+pub mod blocking;
+#[cfg(feature = "discord")]
 pub mod discord;
+pub mod frozen;
+pub mod interchange;
+pub mod kv;
 pub mod memory;
+pub mod merge;
+pub mod metrics;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod rand;
+pub mod retry;
+#[cfg(feature = "sqlite")]
 pub mod sqlite;
+pub mod token;
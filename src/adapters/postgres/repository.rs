@@ -0,0 +1,815 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use postgres::types::ToSql;
+use postgres::{Client, GenericClient, NoTls, Row, Transaction};
+use rand::{thread_rng, Rng};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::{instrument, warn};
+
+use super::schema;
+use crate::markov::decay::{self, DecayPolicy, Decayed};
+use crate::markov::repository::Repository;
+use crate::markov::stats::{self, StateStats, Stats};
+use crate::markov::types::{Link, Size, WeightMap};
+
+// Random ids tried before falling back to counting all states. Ids have more
+// gaps than in SQLite, because conflicting inserts still consume them.
+const RANDOM_STATE_ATTEMPTS: usize = 8;
+
+/// Repository backed by a PostgreSQL database, which can be shared by several
+/// replicas of the bot.
+pub struct PostgresRepository {
+    // Only taken when the repository is dropped.
+    client: Mutex<Option<Client>>,
+}
+
+impl PostgresRepository {
+    /// Opens a repository of a chain of order `N`, creating or upgrading the
+    /// schema if needed.
+    pub fn new<const N: usize>(mut client: Client) -> Result<PostgresRepository> {
+        blocking(|| schema::setup::<N>(&mut client))?;
+        Ok(PostgresRepository {
+            client: Mutex::new(Some(client)),
+        })
+    }
+
+    /// Connects to the database given by a connection string, e.g.
+    /// `postgresql://user@localhost/markov`.
+    pub fn connect<const N: usize>(url: &str) -> Result<PostgresRepository> {
+        let client =
+            blocking(|| Client::connect(url, NoTls)).context("Failed to connect to PostgreSQL")?;
+        PostgresRepository::new::<N>(client)
+    }
+
+    fn with_client<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&mut Client) -> Result<O>,
+    {
+        blocking(|| {
+            let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+            f(client.as_mut().expect("client is only taken on drop"))
+        })
+    }
+
+    fn get_or_insert(
+        transaction: &mut Transaction,
+        get: &str,
+        insert: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<i64> {
+        if let Some(row) = transaction.query_opt(get, params)? {
+            return Ok(row.try_get(0)?);
+        }
+        match transaction.query_opt(insert, params)? {
+            Some(row) => Ok(row.try_get(0)?),
+            // Inserted by another replica in the meantime.
+            None => Ok(transaction.query_one(get, params)?.try_get(0)?),
+        }
+    }
+
+    fn get_or_create_word(transaction: &mut Transaction, value: &str) -> Result<i64> {
+        let (get, insert) = (schema::get_word(), schema::insert_word());
+        Self::get_or_insert(transaction, &get, &insert, &[&value.as_bytes()])
+    }
+
+    fn add_weight<const N: usize>(
+        transaction: &mut Transaction,
+        link: &Link<String, N>,
+        weight: u32,
+        now: i64,
+    ) -> Result<()> {
+        let from_ids = link
+            .from
+            .iter()
+            .map(|word| Self::get_or_create_word(transaction, word))
+            .collect::<Result<Vec<_>>>()?;
+        let params: Vec<&(dyn ToSql + Sync)> = from_ids.iter().map(|id| id as _).collect();
+        let transition_from_id = Self::get_or_insert(
+            transaction,
+            &schema::get_transition_from(N),
+            &schema::insert_transition_from(N),
+            &params,
+        )?;
+        let to_id = Self::get_or_create_word(transaction, &link.to)?;
+        transaction.execute(
+            &schema::add_weight(),
+            &[&transition_from_id, &to_id, &i64::from(weight), &now],
+        )?;
+        Ok(())
+    }
+
+    fn decay<const N: usize>(
+        transaction: &mut Transaction,
+        policy: &DecayPolicy,
+        now: i64,
+    ) -> Result<Decayed> {
+        // Changed by a few statements taking arrays, rather than one per row.
+        let (mut from_ids, mut to_ids, mut weights, mut times) = (vec![], vec![], vec![], vec![]);
+        let (mut removed_from_ids, mut removed_to_ids) = (vec![], vec![]);
+        let half_life = policy.half_life.as_secs() as i64;
+        let sql = schema::get_transitions_updated_before();
+        for row in transaction.query(&sql, &[&now.saturating_sub(half_life)])? {
+            let (transition_from_id, to_id): (i64, i64) = (row.try_get(0)?, row.try_get(1)?);
+            let weight = weight(&row, 2)?;
+            let updated_at: i64 = row.try_get(3)?;
+            let elapsed = Duration::from_secs((now - updated_at) as u64);
+            let (decayed, consumed) = policy.decay(weight, elapsed);
+            if decayed == 0 {
+                removed_from_ids.push(transition_from_id);
+                removed_to_ids.push(to_id);
+            } else if decayed != weight {
+                from_ids.push(transition_from_id);
+                to_ids.push(to_id);
+                weights.push(i64::from(decayed));
+                times.push(updated_at.saturating_add(consumed.as_secs() as i64));
+            }
+        }
+
+        let decayed = Decayed {
+            decayed: (from_ids.len() + removed_from_ids.len()) as u64,
+            removed: removed_from_ids.len() as u64,
+        };
+        if !from_ids.is_empty() {
+            let params: [&(dyn ToSql + Sync); 4] = [&from_ids, &to_ids, &weights, &times];
+            transaction.execute(&schema::set_weights(), &params)?;
+        }
+        if decayed.removed > 0 {
+            let sql = schema::delete_transitions();
+            transaction.execute(&sql, &[&removed_from_ids, &removed_to_ids])?;
+            transaction.execute(&schema::delete_unused_states(), &[])?;
+            transaction.execute(&schema::delete_unused_words(N), &[])?;
+        }
+        Ok(decayed)
+    }
+
+    fn get_state<const N: usize>(
+        client: &mut impl GenericClient,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<[String; N]>> {
+        client
+            .query_opt(sql, params)?
+            .map(|row| state(&row, 0))
+            .transpose()
+    }
+
+    fn get_size(client: &mut impl GenericClient) -> Result<Size> {
+        let row = client.query_one(&schema::get_size(), &[])?;
+        Ok(Size {
+            words: row.try_get::<_, i64>(0)? as u64,
+            states: row.try_get::<_, i64>(1)? as u64,
+            transitions: row.try_get::<_, i64>(2)? as u64,
+        })
+    }
+
+    /// Returns `(transition_from_id, weight, entropy)` of every state.
+    fn get_state_weights(client: &mut impl GenericClient) -> Result<Vec<(i64, u64, f64)>> {
+        let mut states = Vec::new();
+        let mut current: Option<i64> = None;
+        let mut weights = Vec::new();
+
+        let mut finish = |id, weights: &mut Vec<u32>| {
+            let weight = weights.iter().map(|&w| u64::from(w)).sum();
+            states.push((id, weight, stats::entropy(weights.drain(..))));
+        };
+        for row in client.query(&schema::get_state_weights(), &[])? {
+            let id = row.try_get(0)?;
+            match current {
+                Some(previous) if previous != id => finish(previous, &mut weights),
+                _ => {}
+            }
+            current = Some(id);
+            weights.push(weight(&row, 1)?);
+        }
+        if let Some(id) = current {
+            finish(id, &mut weights);
+        }
+
+        Ok(states)
+    }
+}
+
+impl Drop for PostgresRepository {
+    fn drop(&mut self) {
+        let client = self
+            .client
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = client.take() {
+            if let Err(e) = blocking(|| client.close()) {
+                warn!(error = %e, "Failed to close PostgreSQL connection");
+            }
+        }
+    }
+}
+
+// The client blocks on a runtime of its own, which can't be done on a thread
+// driving another runtime unless it's marked as blocking.
+fn blocking<F, O>(f: F) -> O
+where
+    F: FnOnce() -> O,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+fn word(row: &Row, idx: usize) -> Result<String> {
+    let bytes: Vec<u8> = row.try_get(idx)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn weight(row: &Row, idx: usize) -> Result<u32> {
+    let weight: i64 = row.try_get(idx)?;
+    Ok(u32::try_from(weight)?)
+}
+
+fn state<const N: usize>(row: &Row, first: usize) -> Result<[String; N]> {
+    let words = (first..first + N)
+        .map(|idx| word(row, idx))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(words.try_into().unwrap_or_else(|_| unreachable!()))
+}
+
+impl<const N: usize> Repository<String, N> for PostgresRepository {
+    #[instrument(level = "trace", skip_all)]
+    fn get(&self, from: &[String; N]) -> Result<WeightMap<String>> {
+        self.with_client(|client| {
+            let values: Vec<&[u8]> = from.iter().map(|word| word.as_bytes()).collect();
+            let params: Vec<&(dyn ToSql + Sync)> = values.iter().map(|value| value as _).collect();
+            client
+                .query(&schema::get_weights(N), &params)?
+                .iter()
+                .map(|row| Ok((word(row, 0)?, weight(row, 1)?)))
+                .collect()
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random(&self) -> Result<Option<[String; N]>> {
+        self.with_client(|client| {
+            let max_id: Option<i64> = client
+                .query_one(&schema::get_max_state_id(), &[])?
+                .try_get(0)?;
+            let max_id = match max_id {
+                Some(max_id) => max_id,
+                None => return Ok(None),
+            };
+
+            let mut rng = thread_rng();
+            let sql = schema::get_state(N);
+            for _ in 0..RANDOM_STATE_ATTEMPTS {
+                let id = rng.gen_range(1..=max_id);
+                if let Some(state) = Self::get_state(client, &sql, &[&id])? {
+                    return Ok(Some(state));
+                }
+            }
+
+            let count: i64 = client
+                .query_one(&schema::count_states(false), &[])?
+                .try_get(0)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            let offset = rng.gen_range(0..count);
+            Self::get_state(client, &schema::get_nth_state(N, false), &[&offset])
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn random_starting_with(&self, state: &String) -> Result<Option<[String; N]>> {
+        self.with_client(|client| {
+            let word_id: i64 = match client.query_opt(&schema::get_word(), &[&state.as_bytes()])? {
+                Some(row) => row.try_get(0)?,
+                None => return Ok(None),
+            };
+
+            let count: i64 = client
+                .query_one(&schema::count_states(true), &[&word_id])?
+                .try_get(0)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            let offset = thread_rng().gen_range(0..count);
+            let sql = schema::get_nth_state(N, true);
+            Self::get_state(client, &sql, &[&word_id, &offset])
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_weight(&self, link: Link<String, N>, weight: u32) -> Result<()> {
+        self.add_weights(&[(link, weight)])
+    }
+
+    #[instrument(level = "trace", skip_all, fields(links = links.len()))]
+    fn add_weights(&self, links: &[(Link<String, N>, u32)]) -> Result<()> {
+        let now = decay::unix_time(SystemTime::now()) as i64;
+        self.with_client(|client| {
+            let mut transaction = client.transaction()?;
+            for (link, weight) in links {
+                Self::add_weight(&mut transaction, link, *weight, now)?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<String, N>, u32)>>,
+    ) -> Result<()> {
+        let now = decay::unix_time(SystemTime::now()) as i64;
+        self.with_client(|client| {
+            let mut transaction = client.transaction()?;
+            for link in links {
+                let (link, weight) = link?;
+                Self::add_weight(&mut transaction, &link, weight, now)?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        let now = decay::unix_time(now) as i64;
+        self.with_client(|client| {
+            let mut transaction = client.transaction()?;
+            let decayed = Self::decay::<N>(&mut transaction, policy, now)?;
+            transaction.commit()?;
+            Ok(decayed)
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn size(&self) -> Result<Size> {
+        self.with_client(Self::get_size)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn stats(&self, top: usize) -> Result<Stats<String, N>> {
+        self.with_client(|client| {
+            // Read everything from a single snapshot of the database.
+            let mut transaction = client
+                .build_transaction()
+                .isolation_level(postgres::IsolationLevel::RepeatableRead)
+                .read_only(true)
+                .start()?;
+            let total_weight: i64 = transaction
+                .query_one(&schema::get_total_weight(), &[])?
+                .try_get(0)?;
+            let top_words = transaction
+                .query(&schema::get_top_words(), &[&(top as i64)])?
+                .iter()
+                .map(|row| Ok((word(row, 0)?, row.try_get::<_, i64>(1)? as u64)))
+                .collect::<Result<_>>()?;
+
+            let states = Self::get_state_weights(&mut transaction)?;
+            let weighted_entropy: f64 = states
+                .iter()
+                .map(|&(_, weight, entropy)| weight as f64 * entropy)
+                .sum();
+            let sql = schema::get_state(N);
+            let top_states = stats::top(states, top, |&(_, weight, _)| weight)
+                .into_iter()
+                .map(|(id, weight, entropy)| {
+                    let state = Self::get_state(&mut transaction, &sql, &[&id])?
+                        .context("State disappeared while computing stats")?;
+                    Ok(StateStats {
+                        state,
+                        weight,
+                        entropy,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            Ok(Stats {
+                size: Self::get_size(&mut transaction)?,
+                total_weight: total_weight as u64,
+                top_words,
+                top_states,
+                mean_entropy: if total_weight > 0 {
+                    weighted_entropy / total_weight as f64
+                } else {
+                    0.0
+                },
+            })
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn for_each_transition(
+        &self,
+        f: &mut dyn FnMut(Link<String, N>, u32) -> Result<()>,
+    ) -> Result<()> {
+        self.with_client(|client| {
+            for row in client.query(&schema::get_transitions(N), &[])? {
+                let link = Link::new(state(&row, 0)?, word(&row, N)?);
+                f(link, weight(&row, N + 1)?)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// These tests run against a throwaway cluster started with `initdb` and
+// `pg_ctl` in a temporary directory, or against the server given by
+// `MARKOV_TEST_POSTGRES_URL`. Without it, they are skipped if `initdb` is
+// missing or refuses to run as root. Each test uses a schema of its own,
+// dropped when it finishes.
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, Weak};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use postgres::{Client, Config, NoTls};
+    use tempfile::TempDir;
+
+    use super::super::schema;
+    use super::PostgresRepository;
+    use crate::adapters::interchange::{export, import};
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::adapters::retry::{RetryPolicy, RetryingRepository};
+    use crate::markov::conformance::{conformance_tests, decay_tests};
+    use crate::markov::decay::{DecayPolicy, Decayed};
+    use crate::markov::repository::Repository;
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::Link;
+
+    static NEXT_SCHEMA: AtomicUsize = AtomicUsize::new(0);
+    static SERVER: Mutex<Weak<Server>> = Mutex::new(Weak::new());
+
+    /// The server of the tests running at the same time.
+    struct Server {
+        config: Config,
+        _cluster: Option<Cluster>,
+    }
+
+    /// A cluster in a temporary directory, listening only on a socket there.
+    struct Cluster {
+        bin: PathBuf,
+        dir: TempDir,
+    }
+
+    impl Cluster {
+        /// Starts a cluster, or returns `None` if running as root, which
+        /// `initdb` refuses.
+        fn start(bin: PathBuf) -> Option<Cluster> {
+            let dir = TempDir::new().unwrap();
+            if dir.path().metadata().unwrap().uid() == 0 {
+                eprintln!("Skipped: initdb cannot be run as root");
+                return None;
+            }
+            let data = dir.path().join("data");
+            run(Command::new(bin.join("initdb")).arg("-D").arg(&data).args([
+                "-A",
+                "trust",
+                "-U",
+                "postgres",
+                "--no-sync",
+            ]));
+            run(Command::new(bin.join("pg_ctl"))
+                .arg("-D")
+                .arg(&data)
+                .arg("-l")
+                .arg(dir.path().join("log"))
+                .arg("-o")
+                .arg(format!(
+                    "-k {} -c listen_addresses='' -c fsync=off",
+                    dir.path().display()
+                ))
+                .args(["-w", "start"]));
+            Some(Cluster { bin, dir })
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            // The directory is removed anyway, so errors are ignored.
+            let _ = Command::new(self.bin.join("pg_ctl"))
+                .arg("-D")
+                .arg(self.dir.path().join("data"))
+                .args(["-m", "immediate", "-w", "stop"])
+                .output();
+        }
+    }
+
+    fn run(command: &mut Command) {
+        let output = command.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Finds the directory with `initdb` and `pg_ctl`, in `PATH` or where
+    /// Debian installs them.
+    fn binaries() -> Option<PathBuf> {
+        let path = env::var_os("PATH").unwrap_or_default();
+        let versions = fs::read_dir("/usr/lib/postgresql")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path().join("bin"));
+        env::split_paths(&path)
+            .chain(versions)
+            .find(|dir| dir.join("initdb").is_file() && dir.join("pg_ctl").is_file())
+    }
+
+    /// Returns the server for a test, starting a cluster unless one is running
+    /// or `MARKOV_TEST_POSTGRES_URL` is set. The cluster is stopped once no
+    /// test holds it.
+    fn server() -> Option<Arc<Server>> {
+        let mut shared = SERVER.lock().unwrap();
+        if let Some(server) = shared.upgrade() {
+            return Some(server);
+        }
+        let server = match env::var("MARKOV_TEST_POSTGRES_URL") {
+            Ok(url) => Server {
+                config: url.parse().unwrap(),
+                _cluster: None,
+            },
+            Err(_) => {
+                let Some(bin) = binaries() else {
+                    eprintln!("Skipped: initdb and pg_ctl not found");
+                    return None;
+                };
+                let cluster = Cluster::start(bin)?;
+                let mut config = Config::new();
+                config.host_path(cluster.dir.path()).user("postgres");
+                Server {
+                    config,
+                    _cluster: Some(cluster),
+                }
+            }
+        };
+        let server = Arc::new(server);
+        *shared = Arc::downgrade(&server);
+        Some(server)
+    }
+
+    // Tables created in the temporary schema are only visible to the
+    // connection and dropped with it. The server is held by the test.
+    fn repository<const N: usize>() -> PostgresRepository {
+        let client = server()
+            .unwrap()
+            .config
+            .clone()
+            .options("-c search_path=pg_temp")
+            .connect(NoTls)
+            .unwrap();
+        PostgresRepository::new::<N>(client).unwrap()
+    }
+
+    conformance_tests!(repository, server);
+    decay_tests!(repository, server);
+
+    struct TestDatabase {
+        _server: Arc<Server>,
+        config: Config,
+        schema: String,
+    }
+
+    impl TestDatabase {
+        fn new() -> Option<TestDatabase> {
+            let server = server()?;
+            let mut config = server.config.clone();
+            let schema = format!(
+                "markov_test_{}_{}",
+                std::process::id(),
+                NEXT_SCHEMA.fetch_add(1, Ordering::Relaxed)
+            );
+            let mut client = config.connect(NoTls).unwrap();
+            client
+                .batch_execute(&format!("CREATE SCHEMA {};", schema))
+                .unwrap();
+            config.options(&format!("-c search_path={}", schema));
+            Some(TestDatabase {
+                _server: server,
+                config,
+                schema,
+            })
+        }
+
+        fn client(&self) -> Client {
+            self.config.connect(NoTls).unwrap()
+        }
+
+        fn repository<const N: usize>(&self) -> PostgresRepository {
+            PostgresRepository::new::<N>(self.client()).unwrap()
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            let mut client = self.client();
+            client
+                .batch_execute(&format!("DROP SCHEMA {} CASCADE;", self.schema))
+                .unwrap();
+        }
+    }
+
+    fn link<const N: usize>(from: [&str; N], to: &str) -> Link<String, N> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    #[test]
+    fn stores_weights() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        let repository = database.repository::<2>();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+        repository.add_weight(link(["a", "b"], "c"), 4).unwrap();
+        repository.increment_weight(link(["a", "b"], "\0")).unwrap();
+        repository.increment_weight(link(["\0", "a"], "b")).unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        let weights = repository.get(&from).unwrap();
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights["c"], 5);
+        assert_eq!(weights["\0"], 1);
+        let size = Repository::<String, 2>::size(&repository).unwrap();
+        assert_eq!((size.words, size.states, size.transitions), (4, 2, 3));
+        let missing = ["b", "a"].map(str::to_string);
+        assert!(repository.get(&missing).unwrap().is_empty());
+    }
+
+    #[test]
+    fn random_states() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        let repository = database.repository::<2>();
+        assert_eq!(Repository::<String, 2>::random(&repository).unwrap(), None);
+
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+        repository.increment_weight(link(["b", "c"], "d")).unwrap();
+
+        let state: [String; 2] = repository.random().unwrap().unwrap();
+        assert!(state == ["a", "b"] || state == ["b", "c"]);
+        let word = "b".to_string();
+        let state = repository.random_starting_with(&word).unwrap();
+        assert_eq!(state, Some(["b".to_string(), "c".to_string()]));
+        let word = "d".to_string();
+        let state: Option<[String; 2]> = repository.random_starting_with(&word).unwrap();
+        assert_eq!(state, None);
+    }
+
+    #[test]
+    fn stats_summarize_chain() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        let repository = database.repository::<1>();
+        for (from, to) in [("a", "b"), ("a", "c"), ("a", "b"), ("b", "c"), ("a", "b")] {
+            repository.increment_weight(link([from], to)).unwrap();
+        }
+
+        let stats = Repository::<String, 1>::stats(&repository, 1).unwrap();
+
+        assert_eq!(stats.size.words, 3);
+        assert_eq!(stats.size.states, 2);
+        assert_eq!(stats.size.transitions, 3);
+        assert_eq!(stats.total_weight, 5);
+        assert_eq!(stats.top_words, vec![("b".to_string(), 3)]);
+        assert_eq!(stats.top_states[0].state, ["a".to_string()]);
+        assert_eq!(stats.top_states[0].weight, 4);
+        assert!((stats.top_states[0].entropy - 0.811).abs() < 1e-3);
+        assert!((stats.mean_entropy - 4.0 * 0.811 / 5.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn upgrades_v1_schema() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        database.repository::<2>();
+        database
+            .client()
+            .batch_execute(
+                "DROP INDEX transition_updated_at;
+                 ALTER TABLE transition DROP COLUMN updated_at;
+                 UPDATE metadata SET value = '1' WHERE key = 'version';",
+            )
+            .unwrap();
+        assert_eq!(schema::version(&mut database.client()).unwrap(), 1);
+        database
+            .client()
+            .batch_execute(
+                "INSERT INTO word (id, value) VALUES (1, 'a'), (2, 'b'), (3, 'c');
+                 INSERT INTO transition_from (id, word_0_id, word_1_id) VALUES (1, 1, 2);
+                 INSERT INTO transition (transition_from_id, to_id, weight) VALUES (1, 3, 3);",
+            )
+            .unwrap();
+
+        let repository = database.repository::<2>();
+
+        assert_eq!(
+            schema::version(&mut database.client()).unwrap(),
+            schema::VERSION
+        );
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 3);
+        // Transitions learned before they were timestamped count as recent.
+        let policy = DecayPolicy {
+            half_life: Duration::from_secs(60 * 60),
+        };
+        let decayed = Repository::<String, 2>::decay(&repository, &policy, SystemTime::now());
+        assert_eq!(decayed.unwrap(), Decayed::default());
+    }
+
+    #[test]
+    fn setup_is_idempotent_and_checks_order() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        let repository = database.repository::<2>();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+        let repository = database.repository::<2>();
+        let error = PostgresRepository::new::<3>(database.client())
+            .err()
+            .unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 1);
+        assert_eq!(
+            error.to_string(),
+            "Database was created with order 2, but order 3 is used"
+        );
+    }
+
+    #[test]
+    fn concurrent_writers_sum_weights() {
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        database.repository::<2>();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    // Replicas may deadlock on each other's inserts, which is
+                    // left to the retrying decorator, as in the bot.
+                    let repository =
+                        RetryingRepository::new(database.repository::<2>(), RetryPolicy::default());
+                    for i in 0..50 {
+                        let word = (i % 10).to_string();
+                        repository
+                            .add_weights(&[
+                                (link(["a", &word], "b"), 1),
+                                (link(["b", "a"], &word), 2),
+                            ])
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let repository = database.repository::<2>();
+        let size = Repository::<String, 2>::size(&repository).unwrap();
+        assert_eq!((size.words, size.states, size.transitions), (12, 11, 20));
+        let from = ["b", "a"].map(str::to_string);
+        let weights = repository.get(&from).unwrap();
+        assert!(weights.values().all(|&weight| weight == 4 * 5 * 2));
+    }
+
+    #[test]
+    fn round_trip_through_interchange() {
+        let source = MemoryRepository::new();
+        source.add_weight(link(["a", "b"], "c"), 3).unwrap();
+        source.add_weight(link(["\0", "a"], "zażółć"), 2).unwrap();
+        let mut exported = Vec::new();
+        export(&source, Tokenizer::Whitespace, &mut exported).unwrap();
+        let Some(database) = TestDatabase::new() else {
+            return;
+        };
+        let repository = database.repository::<2>();
+
+        import::<String, _, 2>(&repository, Tokenizer::Whitespace, &exported[..]).unwrap();
+        let mut reexported = Vec::new();
+        export::<String, _, 2>(&repository, Tokenizer::Whitespace, &mut reexported).unwrap();
+
+        let mut lines: Vec<_> = exported.split(|&b| b == b'\n').collect();
+        let mut relines: Vec<_> = reexported.split(|&b| b == b'\n').collect();
+        lines.sort();
+        relines.sort();
+        assert_eq!(lines, relines);
+    }
+}
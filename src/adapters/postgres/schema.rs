@@ -0,0 +1,374 @@
+use anyhow::{ensure, Context, Result};
+use cached::proc_macro::cached;
+use postgres::{Client, GenericClient, Transaction};
+use tracing::{info, instrument};
+
+fn word_fk(nth: usize) -> String {
+    format!("word_{}_id", nth)
+}
+
+/// Latest version of the schema, stored in the `metadata` table.
+pub const VERSION: u32 = 2;
+
+// Key of the advisory lock serializing migrations of concurrently started
+// replicas.
+const MIGRATION_LOCK: i64 = 0x6d61_726b_6f76;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&mut Transaction, usize) -> Result<()>,
+}
+
+/// Migrations in the order they are applied. Each of them brings the schema
+/// from the previous version to `version`. Existing migrations must never be
+/// changed, because they have already been applied to users' databases.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add transition updated_at",
+        apply: add_transition_updated_at,
+    },
+];
+
+/// Creates the schema, or brings an existing one up to date, and checks that
+/// the database stores a chain of order `N`.
+pub fn setup<const N: usize>(client: &mut Client) -> Result<()> {
+    migrate::<N>(client)?;
+    let order: String = client
+        .query_one("SELECT value FROM metadata WHERE key = 'order';", &[])?
+        .try_get(0)?;
+    ensure!(
+        order == N.to_string(),
+        "Database was created with order {}, but order {} is used",
+        order,
+        N
+    );
+    Ok(())
+}
+
+/// Returns the version of the schema, or 0 if the database is empty.
+pub fn version(client: &mut impl GenericClient) -> Result<u32> {
+    let exists: bool = client
+        .query_one("SELECT to_regclass('metadata') IS NOT NULL;", &[])?
+        .try_get(0)?;
+    if !exists {
+        return Ok(0);
+    }
+    let version: String = client
+        .query_one("SELECT value FROM metadata WHERE key = 'version';", &[])?
+        .try_get(0)?;
+    Ok(version.parse()?)
+}
+
+/// Applies pending migrations in a single transaction and returns how many of
+/// them were applied.
+#[instrument(skip_all)]
+pub fn migrate<const N: usize>(client: &mut Client) -> Result<usize> {
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1);", &[&MIGRATION_LOCK])?;
+    let current = version(&mut transaction)?;
+    ensure!(
+        current <= VERSION,
+        "Database schema version {} is newer than supported version {}",
+        current,
+        VERSION
+    );
+
+    let pending: Vec<_> = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+        .collect();
+    for migration in &pending {
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "Applying migration"
+        );
+        (migration.apply)(&mut transaction, N).with_context(|| {
+            format!(
+                "Migration to version {} ({}) failed",
+                migration.version, migration.description
+            )
+        })?;
+        transaction.execute(
+            "INSERT INTO metadata (key, value) VALUES ('version', $1) \
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value;",
+            &[&migration.version.to_string()],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(pending.len())
+}
+
+// Words are stored as bytes, because text values can't contain NUL, which the
+// bot uses to mark the end of a message.
+fn create_tables(transaction: &mut Transaction, order: usize) -> Result<()> {
+    let word_fk_defs = (0..order)
+        .map(|i| format!("{} BIGINT NOT NULL REFERENCES word (id)", word_fk(i)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let word_fks = (0..order).map(word_fk).collect::<Vec<_>>().join(", ");
+    let sql = format!(
+        "\
+CREATE TABLE word (
+    id BIGSERIAL PRIMARY KEY,
+    value BYTEA NOT NULL UNIQUE
+);
+
+CREATE TABLE transition_from (
+    id BIGSERIAL PRIMARY KEY,
+    {},
+    UNIQUE ({})
+);
+
+CREATE TABLE transition (
+    transition_from_id BIGINT NOT NULL REFERENCES transition_from (id),
+    to_id BIGINT NOT NULL REFERENCES word (id),
+    weight BIGINT NOT NULL,
+    PRIMARY KEY (transition_from_id, to_id),
+    CHECK (weight > 0)
+);
+
+CREATE INDEX transition_to_id ON transition (to_id);
+
+CREATE TABLE metadata (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);",
+        word_fk_defs, word_fks
+    );
+    transaction.batch_execute(&sql)?;
+    transaction.execute(
+        "INSERT INTO metadata (key, value) VALUES ('order', $1);",
+        &[&order.to_string()],
+    )?;
+    Ok(())
+}
+
+// Unix time of the last change of a weight. Transitions of existing databases
+// count as learned when the migration runs, since nothing better is known.
+// Decay only looks up transitions unchanged for a half-life by the index.
+fn add_transition_updated_at(transaction: &mut Transaction, _order: usize) -> Result<()> {
+    transaction.batch_execute(
+        "\
+ALTER TABLE transition ADD COLUMN updated_at BIGINT NOT NULL DEFAULT 0;
+UPDATE transition SET updated_at = extract(epoch FROM now())::BIGINT;
+CREATE INDEX transition_updated_at ON transition (updated_at);",
+    )?;
+    Ok(())
+}
+
+fn placeholders(columns: impl Iterator<Item = String>, first: usize) -> String {
+    columns
+        .enumerate()
+        .map(|(i, column)| format!("{} = ${}", column, first + i))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+fn join_words(n: usize) -> String {
+    (0..n)
+        .map(|i| format!("JOIN word w{i} ON w{i}.id = tf.{}", word_fk(i), i = i))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn state_fields(n: usize) -> String {
+    (0..n)
+        .map(|i| format!("w{}.value", i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cached]
+pub fn get_word() -> String {
+    "SELECT id FROM word WHERE value = $1;".to_string()
+}
+
+#[cached]
+pub fn insert_word() -> String {
+    "INSERT INTO word (value) VALUES ($1) ON CONFLICT (value) DO NOTHING RETURNING id;".to_string()
+}
+
+#[cached]
+pub fn get_transition_from(n: usize) -> String {
+    format!(
+        "SELECT id FROM transition_from WHERE {};",
+        placeholders((0..n).map(word_fk), 1)
+    )
+}
+
+#[cached]
+pub fn insert_transition_from(n: usize) -> String {
+    let columns = (0..n).map(word_fk).collect::<Vec<_>>().join(", ");
+    let values = (1..=n)
+        .map(|i| format!("${}", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "INSERT INTO transition_from ({}) VALUES ({}) ON CONFLICT DO NOTHING RETURNING id;",
+        columns, values
+    )
+}
+
+#[cached]
+pub fn add_weight() -> String {
+    "INSERT INTO transition (transition_from_id, to_id, weight, updated_at) \
+     VALUES ($1, $2, $3, $4) ON CONFLICT (transition_from_id, to_id) DO UPDATE SET \
+     weight = transition.weight + excluded.weight, \
+     updated_at = greatest(transition.updated_at, excluded.updated_at);"
+        .to_string()
+}
+
+/// Selects transitions last changed at or before the time given by the
+/// parameter, locking them until the transaction ends.
+#[cached]
+pub fn get_transitions_updated_before() -> String {
+    "SELECT transition_from_id, to_id, weight, updated_at FROM transition \
+     WHERE updated_at <= $1 FOR UPDATE;"
+        .to_string()
+}
+
+/// Sets weights and times of the last change of transitions, given as arrays
+/// of ids of their states and successors, weights and times.
+#[cached]
+pub fn set_weights() -> String {
+    "UPDATE transition t SET weight = u.weight, updated_at = u.updated_at \
+     FROM unnest($1::BIGINT[], $2::BIGINT[], $3::BIGINT[], $4::BIGINT[]) \
+     AS u (transition_from_id, to_id, weight, updated_at) \
+     WHERE t.transition_from_id = u.transition_from_id AND t.to_id = u.to_id;"
+        .to_string()
+}
+
+/// Deletes transitions given as arrays of ids of their states and successors.
+#[cached]
+pub fn delete_transitions() -> String {
+    "DELETE FROM transition t USING unnest($1::BIGINT[], $2::BIGINT[]) \
+     AS u (transition_from_id, to_id) \
+     WHERE t.transition_from_id = u.transition_from_id AND t.to_id = u.to_id;"
+        .to_string()
+}
+
+#[cached]
+pub fn delete_unused_states() -> String {
+    "DELETE FROM transition_from tf \
+     WHERE NOT EXISTS (SELECT 1 FROM transition t WHERE t.transition_from_id = tf.id);"
+        .to_string()
+}
+
+/// Deletes words neither a successor nor a word of a state, which must be
+/// deleted first.
+#[cached]
+pub fn delete_unused_words(n: usize) -> String {
+    let states = (0..n)
+        .map(|i| {
+            format!(
+                " AND NOT EXISTS (SELECT 1 FROM transition_from tf WHERE tf.{} = w.id)",
+                word_fk(i)
+            )
+        })
+        .collect::<String>();
+    format!(
+        "DELETE FROM word w \
+         WHERE NOT EXISTS (SELECT 1 FROM transition t WHERE t.to_id = w.id){};",
+        states
+    )
+}
+
+#[cached]
+pub fn get_weights(n: usize) -> String {
+    format!(
+        "SELECT w.value, t.weight FROM transition_from tf {} \
+         JOIN transition t ON t.transition_from_id = tf.id \
+         JOIN word w ON w.id = t.to_id WHERE {};",
+        join_words(n),
+        placeholders((0..n).map(|i| format!("w{}.value", i)), 1)
+    )
+}
+
+#[cached]
+pub fn get_max_state_id() -> String {
+    "SELECT max(id) FROM transition_from;".to_string()
+}
+
+#[cached]
+pub fn count_states(filter_starts_with: bool) -> String {
+    if filter_starts_with {
+        format!(
+            "SELECT count(*) FROM transition_from WHERE {} = $1;",
+            word_fk(0)
+        )
+    } else {
+        "SELECT count(*) FROM transition_from;".to_string()
+    }
+}
+
+#[cached]
+pub fn get_state(n: usize) -> String {
+    format!(
+        "SELECT {} FROM transition_from tf {} WHERE tf.id = $1;",
+        state_fields(n),
+        join_words(n)
+    )
+}
+
+/// Selects the state at position given by the last parameter, optionally
+/// among states starting with the word of id given by the first parameter.
+#[cached]
+pub fn get_nth_state(n: usize, filter_starts_with: bool) -> String {
+    let (filter, offset) = if filter_starts_with {
+        (format!("WHERE tf.{} = $1", word_fk(0)), "$2")
+    } else {
+        (String::new(), "$1")
+    };
+    format!(
+        "SELECT {} FROM transition_from tf {} {} LIMIT 1 OFFSET {};",
+        state_fields(n),
+        join_words(n),
+        filter,
+        offset
+    )
+}
+
+#[cached]
+pub fn get_size() -> String {
+    "SELECT \
+        (SELECT count(*) FROM word), \
+        (SELECT count(*) FROM transition_from), \
+        (SELECT count(*) FROM transition);"
+        .to_string()
+}
+
+#[cached]
+pub fn get_total_weight() -> String {
+    "SELECT coalesce(sum(weight), 0)::BIGINT FROM transition;".to_string()
+}
+
+#[cached]
+pub fn get_top_words() -> String {
+    "SELECT w.value, sum(t.weight)::BIGINT AS total FROM transition t \
+     JOIN word w ON w.id = t.to_id GROUP BY w.id ORDER BY total DESC LIMIT $1;"
+        .to_string()
+}
+
+#[cached]
+pub fn get_state_weights() -> String {
+    "SELECT transition_from_id, weight FROM transition ORDER BY transition_from_id;".to_string()
+}
+
+#[cached]
+pub fn get_transitions(n: usize) -> String {
+    format!(
+        "SELECT {}, w.value, t.weight FROM transition_from tf {} \
+         JOIN transition t ON t.transition_from_id = tf.id \
+         JOIN word w ON w.id = t.to_id;",
+        state_fields(n),
+        join_words(n)
+    )
+}
@@ -2,6 +2,7 @@ use rand::{thread_rng, Rng};
 
 use crate::markov::choose::Choose;
 
+#[derive(Default)]
 pub struct RandChoose;
 
 impl RandChoose {
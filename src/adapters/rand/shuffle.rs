@@ -3,6 +3,7 @@ use rand::thread_rng;
 
 use crate::markov::shuffle::Shuffle;
 
+#[derive(Default)]
 pub struct RandShuffle;
 
 impl RandShuffle {
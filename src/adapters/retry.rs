@@ -0,0 +1,236 @@
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Error, Result};
+use tracing::warn;
+
+use crate::markov::choose::Choose;
+use crate::markov::decay::{DecayPolicy, Decayed};
+use crate::markov::repository::Repository;
+use crate::markov::stats::Stats;
+use crate::markov::types::{Link, Size, WeightMap};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: u32, initial_delay: Duration, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_delay,
+            max_delay,
+        }
+    }
+
+    pub fn run<F, O>(&self, mut f: F) -> Result<O>
+    where
+        F: FnMut() -> Result<O>,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    warn!(
+                        attempt,
+                        attempts = self.attempts,
+                        ?delay,
+                        error = %format!("{:#}", e),
+                        "Transient storage error, retrying"
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(5, Duration::from_millis(50), Duration::from_secs(2))
+    }
+}
+
+/// Returns true if the error is likely to go away when the operation is retried,
+/// e.g. the database is locked by another connection.
+pub fn is_transient(error: &Error) -> bool {
+    error.chain().any(is_transient_cause)
+}
+
+// Without any database backend nothing is transient.
+#[cfg_attr(
+    not(any(feature = "sqlite", feature = "postgres")),
+    allow(unused_variables)
+)]
+fn is_transient_cause(cause: &(dyn std::error::Error + 'static)) -> bool {
+    #[cfg(feature = "sqlite")]
+    if let Some(rusqlite::Error::SqliteFailure(e, _)) = cause.downcast_ref() {
+        use rusqlite::ErrorCode;
+        return matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked);
+    }
+    // A word or state looked up by a replica may be removed by another one
+    // decaying weights before it's referenced. Retrying creates it again.
+    #[cfg(feature = "postgres")]
+    if let Some(e) = cause.downcast_ref::<postgres::Error>() {
+        use postgres::error::SqlState;
+        return matches!(
+            e.code(),
+            Some(
+                &SqlState::T_R_DEADLOCK_DETECTED
+                    | &SqlState::T_R_SERIALIZATION_FAILURE
+                    | &SqlState::FOREIGN_KEY_VIOLATION
+            )
+        );
+    }
+    false
+}
+
+/// Repository decorator retrying transient failures of the wrapped repository
+/// with exponential backoff. It blocks the calling thread while waiting.
+pub struct RetryingRepository<R> {
+    repository: R,
+    policy: RetryPolicy,
+}
+
+impl<R> RetryingRepository<R> {
+    pub fn new(repository: R, policy: RetryPolicy) -> RetryingRepository<R> {
+        RetryingRepository { repository, policy }
+    }
+}
+
+impl<T, R, const N: usize> Repository<T, N> for RetryingRepository<R>
+where
+    R: Repository<T, N>,
+{
+    fn get(&self, from: &[T; N]) -> Result<WeightMap<T>> {
+        self.policy.run(|| self.repository.get(from))
+    }
+
+    fn random(&self) -> Result<Option<[T; N]>> {
+        self.policy.run(|| self.repository.random())
+    }
+
+    fn random_starting_with(&self, state: &T) -> Result<Option<[T; N]>> {
+        self.policy
+            .run(|| self.repository.random_starting_with(state))
+    }
+
+    fn sample(&self, from: &[T; N], chooser: &dyn Choose<T>) -> Result<Option<T>> {
+        self.policy.run(|| self.repository.sample(from, chooser))
+    }
+
+    // Stored as a batch of one, which can be retried without copying it.
+    fn add_weight(&self, link: Link<T, N>, weight: u32) -> Result<()> {
+        self.add_weights(&[(link, weight)])
+    }
+
+    fn add_weights(&self, links: &[(Link<T, N>, u32)]) -> Result<()> {
+        self.policy.run(|| self.repository.add_weights(links))
+    }
+
+    // Not retried, because `links` may have already been partly consumed.
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<T, N>, u32)>>,
+    ) -> Result<()> {
+        self.repository.add_all_weights(links)
+    }
+
+    fn size(&self) -> Result<Size> {
+        self.policy.run(|| self.repository.size())
+    }
+
+    fn stats(&self, top: usize) -> Result<Stats<T, N>> {
+        self.policy.run(|| self.repository.stats(top))
+    }
+
+    // Not retried, because `f` may have already seen some of the transitions.
+    fn for_each_transition(&self, f: &mut dyn FnMut(Link<T, N>, u32) -> Result<()>) -> Result<()> {
+        self.repository.for_each_transition(f)
+    }
+
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        self.policy.run(|| self.repository.decay(policy, now))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.policy.run(|| self.repository.flush())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use rusqlite::ffi;
+
+    use super::{is_transient, RetryPolicy};
+
+    fn busy() -> anyhow::Error {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None).into()
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new(3, Duration::ZERO, Duration::ZERO)
+    }
+
+    #[test]
+    fn busy_database_is_transient() {
+        assert!(is_transient(&busy()));
+        assert!(is_transient(&busy().context("while learning")));
+    }
+
+    #[test]
+    fn other_errors_are_not_transient() {
+        assert!(!is_transient(&anyhow!("disk on fire")));
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let calls = Cell::new(0);
+        let result = policy().run(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(busy())
+            } else {
+                Ok(42)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let calls = Cell::new(0);
+        let result: Result<()> = policy().run(|| {
+            calls.set(calls.get() + 1);
+            Err(busy())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let calls = Cell::new(0);
+        let result: Result<()> = policy().run(|| {
+            calls.set(calls.get() + 1);
+            Err(anyhow!("no such table: word"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
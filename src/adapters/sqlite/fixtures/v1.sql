@@ -0,0 +1,23 @@
+-- Database of order 2 created by `--setup-db` before schema versioning.
+CREATE TABLE word (
+    id INTEGER PRIMARY KEY,
+    value TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE transition_from (
+    id INTEGER PRIMARY KEY,
+    word_0_id INTEGER NOT NULL REFERENCES word (id), word_1_id INTEGER NOT NULL REFERENCES word (id),
+    UNIQUE (word_0_id, word_1_id)
+);
+
+CREATE TABLE transition (
+    transition_from_id INTEGER NOT NULL REFERENCES transition_from (id),
+    to_id INTEGER NOT NULL REFERENCES word (id),
+    weight INTEGER NOT NULL,
+    PRIMARY KEY (transition_from_id, to_id),
+    CHECK (weight > 0)
+);
+
+INSERT INTO word (id, value) VALUES (1, 'hello'), (2, 'there'), (3, 'friend'), (4, '');
+INSERT INTO transition_from (id, word_0_id, word_1_id) VALUES (1, 1, 2), (2, 2, 3);
+INSERT INTO transition (transition_from_id, to_id, weight) VALUES (1, 3, 3), (2, 4, 1);
@@ -1,2 +1,3 @@
+mod pool;
 pub mod repository;
 pub mod schema;
@@ -0,0 +1,47 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use rusqlite::{Connection, OpenFlags};
+
+/// Fixed set of read-only connections to a database, handed out round-robin.
+pub struct ReaderPool {
+    connections: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+}
+
+impl ReaderPool {
+    pub fn open(path: &Path, size: usize, busy_timeout: Duration) -> Result<ReaderPool> {
+        ensure!(size > 0, "Reader pool must have at least one connection");
+        let connections = (0..size)
+            .map(|_| {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                let connection = Connection::open_with_flags(path, flags)?;
+                connection.busy_timeout(busy_timeout)?;
+                Ok(Mutex::new(connection))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ReaderPool {
+            connections,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns a free connection, or waits for one if all of them are in use.
+    pub fn get(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.connections.len();
+        for i in 0..len {
+            if let Ok(connection) = self.connections[(start + i) % len].try_lock() {
+                return connection;
+            }
+        }
+        // A panic while holding the lock can't leave the connection in an
+        // inconsistent state, so poisoning is ignored.
+        self.connections[start % len]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
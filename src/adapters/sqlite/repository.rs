@@ -1,23 +1,134 @@
 use std::hash::Hash;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use arrayvec::ArrayVec;
+use rand::{thread_rng, Rng};
 use rusqlite::types::FromSql;
 use rusqlite::{
-    params_from_iter, Connection, Error, OptionalExtension, Params, ToSql, Transaction,
+    params_from_iter, Connection, Error, OpenFlags, OptionalExtension, Params, ToSql, Transaction,
 };
+use tracing::{instrument, warn};
 
+use super::pool::ReaderPool;
 use super::schema;
+use crate::markov::decay::{self, DecayPolicy, Decayed};
 use crate::markov::repository::Repository;
-use crate::markov::types::{Link, WeightMap};
+use crate::markov::stats::{self, StateStats, Stats};
+use crate::markov::types::{Link, Size, WeightMap};
 
+// Random ids tried before falling back to counting all states.
+const RANDOM_STATE_ATTEMPTS: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SqliteOptions {
+    /// How long to wait for a lock held by another connection before failing.
+    pub busy_timeout: Duration,
+    /// Number of read-only connections used for generating replies and stats.
+    pub readers: usize,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> SqliteOptions {
+        SqliteOptions {
+            busy_timeout: Duration::from_secs(5),
+            readers: 4,
+        }
+    }
+}
+
+/// Repository backed by a SQLite database. Writes go through a single
+/// connection, while reads may use a pool of read-only connections, so they
+/// don't wait for each other when the database is in WAL mode.
 pub struct SqliteRepository {
-    connection: Connection,
+    connection: Mutex<Connection>,
+    readers: Option<ReaderPool>,
 }
 
 impl SqliteRepository {
-    pub fn new(connection: Connection) -> SqliteRepository {
-        SqliteRepository { connection }
+    /// Opens a repository of a chain of order `N`, creating or upgrading the
+    /// schema if needed. All queries use the given connection.
+    pub fn new<const N: usize>(connection: Connection) -> Result<SqliteRepository> {
+        schema::setup::<N>(&connection)?;
+        Ok(SqliteRepository {
+            connection: Mutex::new(connection),
+            readers: None,
+        })
+    }
+
+    /// Opens the database at `path` in WAL mode, with a pool of read-only
+    /// connections next to the writing one.
+    #[instrument(skip_all, fields(path = %path.as_ref().display()))]
+    pub fn open<const N: usize>(
+        path: impl AsRef<Path>,
+        options: SqliteOptions,
+    ) -> Result<SqliteRepository> {
+        let path = path.as_ref();
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(options.busy_timeout)?;
+        let journal_mode: String =
+            connection.query_row("PRAGMA journal_mode = WAL;", [], |row| row.get(0))?;
+        if journal_mode != "wal" {
+            warn!(journal_mode = %journal_mode, "WAL mode is not supported by the database");
+        }
+        // Durable enough in WAL mode: a power loss may only roll back the last
+        // transactions, but never corrupts the database.
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let mut repository = SqliteRepository::new::<N>(connection)?;
+        if options.readers > 0 {
+            let readers = ReaderPool::open(path, options.readers, options.busy_timeout)
+                .context("Failed to open reader connections")?;
+            repository.readers = Some(readers);
+        }
+        Ok(repository)
+    }
+
+    /// Opens the database at `path` without ever writing to it, e.g. to read
+    /// a chain being merged. Unlike [`open`](SqliteRepository::open), it
+    /// doesn't create or upgrade the schema, so it fails unless the schema is
+    /// up to date.
+    #[instrument(skip_all, fields(path = %path.as_ref().display()))]
+    pub fn open_read_only<const N: usize>(path: impl AsRef<Path>) -> Result<SqliteRepository> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        schema::check::<N>(&connection)?;
+        Ok(SqliteRepository {
+            connection: Mutex::new(connection),
+            readers: None,
+        })
+    }
+
+    fn read<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> Result<O>,
+    {
+        match &self.readers {
+            Some(readers) => f(&readers.get()),
+            None => f(&self.writer()),
+        }
+    }
+
+    /// Like [`read`](Self::read), but all queries made by `f` see the same
+    /// snapshot, even if the writer commits in between.
+    fn read_snapshot<F, O>(&self, f: F) -> Result<O>
+    where
+        F: FnOnce(&Connection) -> Result<O>,
+    {
+        self.read(|connection| {
+            // Nothing is written, so the transaction is just rolled back.
+            let transaction = connection.unchecked_transaction()?;
+            f(&transaction)
+        })
+    }
+
+    // Transactions are rolled back when a panic unwinds through them, so the
+    // connection is usable even if the lock was poisoned.
+    fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 }
 
@@ -63,26 +174,86 @@ impl SqliteRepository {
         }
     }
 
-    fn increment_weight(
+    fn add_weight<T, const N: usize>(
         transaction: &Transaction,
-        transition_from_id: i64,
-        to_id: i64,
-    ) -> Result<()> {
-        let sql = schema::increment_weight();
-        let params = [transition_from_id, to_id];
+        link: &Link<T, N>,
+        weight: u32,
+        now: i64,
+    ) -> Result<()>
+    where
+        T: ToSql,
+    {
+        let from_ids = (0..N)
+            .map(|i| Self::get_or_create_word(transaction, &link.from[i]))
+            .collect::<Result<ArrayVec<_, N>>>()?;
+        let transition_from_id = Self::get_or_create_transition_from(transaction, &from_ids)?;
+        let to_id = Self::get_or_create_word(transaction, &link.to)?;
+        let sql = schema::add_weight();
+        let params = [transition_from_id, to_id, i64::from(weight), now];
         transaction.prepare_cached(&sql)?.execute(params)?;
         Ok(())
     }
 
+    fn decay<const N: usize>(
+        transaction: &Transaction,
+        policy: &DecayPolicy,
+        now: i64,
+    ) -> Result<Decayed> {
+        // Collected first, since rows must not change while they're queried.
+        let mut changes = Vec::new();
+        let sql = schema::get_transitions_updated_before();
+        let mut statement = transaction.prepare_cached(&sql)?;
+        let half_life = policy.half_life.as_secs() as i64;
+        let mut rows = statement.query([now.saturating_sub(half_life)])?;
+        while let Some(row) = rows.next()? {
+            let weight: u32 = row.get(2)?;
+            let updated_at: i64 = row.get(3)?;
+            let elapsed = Duration::from_secs((now - updated_at) as u64);
+            let (decayed, consumed) = policy.decay(weight, elapsed);
+            if decayed != weight {
+                let updated_at = updated_at.saturating_add(consumed.as_secs() as i64);
+                changes.push((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    decayed,
+                    updated_at,
+                ));
+            }
+        }
+        drop(rows);
+        drop(statement);
+
+        let mut decayed = Decayed::default();
+        for (transition_from_id, to_id, weight, updated_at) in changes {
+            decayed.decayed += 1;
+            if weight == 0 {
+                decayed.removed += 1;
+                let sql = schema::delete_transition();
+                transaction
+                    .prepare_cached(&sql)?
+                    .execute([transition_from_id, to_id])?;
+            } else {
+                let sql = schema::set_weight();
+                let params = [i64::from(weight), updated_at, transition_from_id, to_id];
+                transaction.prepare_cached(&sql)?.execute(params)?;
+            }
+        }
+        if decayed.removed > 0 {
+            transaction.execute(&schema::delete_unused_states(), [])?;
+            transaction.execute(&schema::delete_unused_words(N), [])?;
+        }
+        Ok(decayed)
+    }
+
     fn get_starting_states<T, const N: usize>(
-        &self,
+        connection: &Connection,
         sql: &str,
         params: impl Params,
     ) -> Result<Option<[T; N]>>
     where
         T: FromSql,
     {
-        let mut statement = self.connection.prepare_cached(sql)?;
+        let mut statement = connection.prepare_cached(sql)?;
         statement
             .query_row(params, |row| {
                 let mut words: ArrayVec<_, N> = ArrayVec::new();
@@ -99,42 +270,535 @@ impl SqliteRepository {
             .optional()
             .map_err(Into::into)
     }
+
+    fn get_size(connection: &Connection) -> Result<Size> {
+        let sql = schema::get_size();
+        connection
+            .prepare_cached(&sql)?
+            .query_row([], |row| {
+                Ok(Size {
+                    words: row.get(0)?,
+                    states: row.get(1)?,
+                    transitions: row.get(2)?,
+                })
+            })
+            .map_err(Into::into)
+    }
+
+    /// Returns `(transition_from_id, weight, entropy)` of every state.
+    fn get_state_weights(connection: &Connection) -> Result<Vec<(i64, u64, f64)>> {
+        let sql = schema::get_state_weights();
+        let mut statement = connection.prepare_cached(&sql)?;
+        let mut rows = statement.query([])?;
+        let mut states = Vec::new();
+        let mut current: Option<i64> = None;
+        let mut weights = Vec::new();
+
+        let mut finish = |id, weights: &mut Vec<u32>| {
+            let weight = weights.iter().map(|&w| u64::from(w)).sum();
+            states.push((id, weight, stats::entropy(weights.drain(..))));
+        };
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            match current {
+                Some(previous) if previous != id => finish(previous, &mut weights),
+                _ => {}
+            }
+            current = Some(id);
+            weights.push(row.get(1)?);
+        }
+        if let Some(id) = current {
+            finish(id, &mut weights);
+        }
+
+        Ok(states)
+    }
 }
 
 impl<T, const N: usize> Repository<T, N> for SqliteRepository
 where
     T: FromSql + ToSql + Hash + Eq,
 {
+    #[instrument(level = "trace", skip_all)]
     fn get(&self, from: &[T; N]) -> Result<WeightMap<T>> {
-        let sql = schema::get_weights(N);
-        let params = params_from_iter(from);
-        let map = self
-            .connection
-            .prepare_cached(&sql)?
-            .query_and_then(params, |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<Result<_>>()?;
-        Ok(map)
+        self.read(|connection| {
+            let sql = schema::get_weights(N);
+            let params = params_from_iter(from);
+            let map = connection
+                .prepare_cached(&sql)?
+                .query_and_then(params, |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_>>()?;
+            Ok(map)
+        })
     }
 
+    #[instrument(level = "trace", skip_all)]
     fn random(&self) -> Result<Option<[T; N]>> {
-        let sql = schema::get_random(N, false);
-        self.get_starting_states(&sql, [])
+        self.read_snapshot(|connection| {
+            let max_id: Option<i64> = connection
+                .prepare_cached(&schema::get_max_state_id())?
+                .query_row([], |row| row.get(0))?;
+            let max_id = match max_id {
+                Some(max_id) => max_id,
+                None => return Ok(None),
+            };
+
+            // Ids of states are dense unless some were deleted, so picking a random
+            // id and retrying on gaps is uniform and usually succeeds right away.
+            let mut rng = thread_rng();
+            let sql = schema::get_state(N);
+            for _ in 0..RANDOM_STATE_ATTEMPTS {
+                let id = rng.gen_range(1..=max_id);
+                if let Some(state) = Self::get_starting_states(connection, &sql, [id])? {
+                    return Ok(Some(state));
+                }
+            }
+
+            // Skipping rows with OFFSET takes time linear in the offset, but
+            // this is only reached when many states were deleted.
+            let count: i64 = connection
+                .prepare_cached(&schema::count_states(false))?
+                .query_row([], |row| row.get(0))?;
+            if count == 0 {
+                return Ok(None);
+            }
+            let sql = schema::get_nth_state(N, false);
+            Self::get_starting_states(connection, &sql, [rng.gen_range(0..count)])
+        })
     }
 
+    #[instrument(level = "trace", skip_all)]
     fn random_starting_with(&self, state: &T) -> Result<Option<[T; N]>> {
-        let sql = schema::get_random(N, true);
-        self.get_starting_states(&sql, [state])
+        self.read_snapshot(|connection| {
+            let word_id: Option<i64> = connection
+                .prepare_cached(&schema::get_word())?
+                .query_row([state], |row| row.get(0))
+                .optional()?;
+            let word_id = match word_id {
+                Some(word_id) => word_id,
+                None => return Ok(None),
+            };
+
+            // States starting with a word are numbered by position among
+            // them, so picking a random one works like picking a random id.
+            let max_position: Option<i64> = connection
+                .prepare_cached(&schema::get_max_state_position())?
+                .query_row([word_id], |row| row.get(0))?;
+            let max_position = match max_position {
+                Some(max_position) => max_position,
+                None => return Ok(None),
+            };
+            let mut rng = thread_rng();
+            let sql = schema::get_state_at_position(N);
+            for _ in 0..RANDOM_STATE_ATTEMPTS {
+                let position = rng.gen_range(0..=max_position);
+                let params = [word_id, position];
+                if let Some(state) = Self::get_starting_states(connection, &sql, params)? {
+                    return Ok(Some(state));
+                }
+            }
+
+            // Skipping rows with OFFSET is linear in the number of states
+            // starting with the word, but this is only reached when many of
+            // them were deleted.
+            let count: i64 = connection
+                .prepare_cached(&schema::count_states(true))?
+                .query_row([word_id], |row| row.get(0))?;
+            if count == 0 {
+                return Ok(None);
+            }
+            let sql = schema::get_nth_state(N, true);
+            Self::get_starting_states(connection, &sql, [word_id, rng.gen_range(0..count)])
+        })
     }
 
-    fn increment_weight(&mut self, link: Link<T, N>) -> Result<()> {
-        let transaction = self.connection.transaction()?;
-        let from_ids = (0..N)
-            .map(|i| Self::get_or_create_word(&transaction, &link.from[i]))
-            .collect::<Result<ArrayVec<_, N>>>()?;
-        let transition_from_id = Self::get_or_create_transition_from(&transaction, &from_ids)?;
-        let to_id = Self::get_or_create_word(&transaction, link.to)?;
-        Self::increment_weight(&transaction, transition_from_id, to_id)?;
+    #[instrument(level = "trace", skip_all)]
+    fn add_weight(&self, link: Link<T, N>, weight: u32) -> Result<()> {
+        let now = decay::unix_time(SystemTime::now()) as i64;
+        let mut connection = self.writer();
+        let transaction = connection.transaction()?;
+        Self::add_weight(&transaction, &link, weight, now)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip_all, fields(links = links.len()))]
+    fn add_weights(&self, links: &[(Link<T, N>, u32)]) -> Result<()> {
+        let now = decay::unix_time(SystemTime::now()) as i64;
+        let mut connection = self.writer();
+        let transaction = connection.transaction()?;
+        for (link, weight) in links {
+            Self::add_weight(&transaction, link, *weight, now)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<T, N>, u32)>>,
+    ) -> Result<()> {
+        let now = decay::unix_time(SystemTime::now()) as i64;
+        let mut connection = self.writer();
+        let transaction = connection.transaction()?;
+        for link in links {
+            let (link, weight) = link?;
+            Self::add_weight(&transaction, &link, weight, now)?;
+        }
         transaction.commit()?;
         Ok(())
     }
+
+    #[instrument(level = "trace", skip_all)]
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        let now = decay::unix_time(now) as i64;
+        let mut connection = self.writer();
+        let transaction = connection.transaction()?;
+        let decayed = Self::decay::<N>(&transaction, policy, now)?;
+        transaction.commit()?;
+        Ok(decayed)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn size(&self) -> Result<Size> {
+        self.read(Self::get_size)
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn stats(&self, top: usize) -> Result<Stats<T, N>> {
+        self.read(|connection| {
+            // Read everything from a single snapshot of the database.
+            let transaction = connection.unchecked_transaction()?;
+            let connection = &*transaction;
+            let total_weight: i64 = connection
+                .prepare_cached(&schema::get_total_weight())?
+                .query_row([], |row| row.get(0))?;
+            let top_words = connection
+                .prepare_cached(&schema::get_top_words())?
+                .query_and_then([top as i64], |row| {
+                    Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+                })?
+                .collect::<Result<_>>()?;
+
+            let states = Self::get_state_weights(connection)?;
+            let weighted_entropy: f64 = states
+                .iter()
+                .map(|&(_, weight, entropy)| weight as f64 * entropy)
+                .sum();
+            let sql = schema::get_state(N);
+            let top_states = stats::top(states, top, |&(_, weight, _)| weight)
+                .into_iter()
+                .map(|(id, weight, entropy)| {
+                    let state = Self::get_starting_states(connection, &sql, [id])?
+                        .context("State disappeared while computing stats")?;
+                    Ok(StateStats {
+                        state,
+                        weight,
+                        entropy,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            Ok(Stats {
+                size: Self::get_size(connection)?,
+                total_weight: total_weight as u64,
+                top_words,
+                top_states,
+                mean_entropy: if total_weight > 0 {
+                    weighted_entropy / total_weight as f64
+                } else {
+                    0.0
+                },
+            })
+        })
+    }
+
+    #[instrument(level = "trace", skip_all)]
+    fn for_each_transition(&self, f: &mut dyn FnMut(Link<T, N>, u32) -> Result<()>) -> Result<()> {
+        self.read(|connection| {
+            let sql = schema::get_transitions(N);
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let mut from: ArrayVec<_, N> = ArrayVec::new();
+                for idx in 0..N {
+                    from.push(row.get(idx)?);
+                }
+                let from = unsafe {
+                    // This is safe, because we've just pushed N items.
+                    from.into_inner_unchecked()
+                };
+                f(Link::new(from, row.get(N)?), row.get(N + 1)?)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+    use tempfile::tempdir;
+
+    use super::{SqliteOptions, SqliteRepository};
+    use crate::markov::conformance::{conformance_tests, decay_tests};
+    use crate::markov::repository::Repository;
+    use crate::markov::types::Link;
+
+    fn repository<const N: usize>() -> SqliteRepository {
+        let connection = Connection::open_in_memory().unwrap();
+        SqliteRepository::new::<N>(connection).unwrap()
+    }
+
+    conformance_tests!(repository);
+    decay_tests!(repository);
+
+    fn link<const N: usize>(from: [&str; N], to: &str) -> Link<String, N> {
+        Link::new(from.map(str::to_string), to.to_string())
+    }
+
+    #[test]
+    fn stats_summarize_chain() {
+        let repository = repository::<1>();
+        for (from, to) in [("a", "b"), ("a", "c"), ("a", "b"), ("b", "c"), ("a", "b")] {
+            repository.increment_weight(link([from], to)).unwrap();
+        }
+
+        let stats = Repository::<String, 1>::stats(&repository, 1).unwrap();
+
+        assert_eq!(stats.size.words, 3);
+        assert_eq!(stats.size.states, 2);
+        assert_eq!(stats.size.transitions, 3);
+        assert_eq!(stats.total_weight, 5);
+        assert_eq!(stats.top_words, vec![("b".to_string(), 3)]);
+        assert_eq!(stats.top_states[0].state, ["a".to_string()]);
+        assert_eq!(stats.top_states[0].weight, 4);
+        assert!((stats.top_states[0].entropy - 0.811).abs() < 1e-3);
+        assert!((stats.mean_entropy - 4.0 * 0.811 / 5.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn stats_of_empty_repository() {
+        let repository = repository::<2>();
+
+        let stats = Repository::<String, 2>::stats(&repository, 10).unwrap();
+
+        assert_eq!(stats.size.states, 0);
+        assert_eq!(stats.total_weight, 0);
+        assert!(stats.top_words.is_empty());
+        assert!(stats.top_states.is_empty());
+        assert_eq!(stats.mean_entropy, 0.0);
+    }
+
+    // Counts how many times each state is drawn in `samples` attempts.
+    fn histogram(
+        samples: usize,
+        mut random: impl FnMut() -> Option<[String; 2]>,
+    ) -> HashMap<[String; 2], usize> {
+        let mut histogram = HashMap::new();
+        for _ in 0..samples {
+            let state = random().expect("a state should always be found");
+            *histogram.entry(state).or_default() += 1;
+        }
+        histogram
+    }
+
+    // With 4000 samples over 4 states the standard deviation of each count is
+    // about 27, so the bounds are more than 7 deviations away from the mean.
+    fn assert_uniform(histogram: &HashMap<[String; 2], usize>, states: usize, samples: usize) {
+        assert_eq!(histogram.len(), states);
+        let expected = samples / states;
+        for (state, &count) in histogram {
+            assert!(
+                (expected - expected / 5..expected + expected / 5).contains(&count),
+                "{:?} drawn {} times, expected about {}",
+                state,
+                count,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn random_of_empty_repository() {
+        let repository = repository::<2>();
+
+        assert_eq!(Repository::<String, 2>::random(&repository).unwrap(), None);
+        let word = "a".to_string();
+        assert_eq!(
+            repository.random_starting_with(&word).unwrap(),
+            None::<[String; 2]>
+        );
+    }
+
+    #[test]
+    fn random_is_uniform() {
+        let repository = repository::<2>();
+        for from in [["a", "b"], ["b", "c"], ["c", "d"], ["d", "e"]] {
+            repository.increment_weight(link(from, "x")).unwrap();
+        }
+
+        let histogram = histogram(4000, || repository.random().unwrap());
+
+        assert_uniform(&histogram, 4, 4000);
+    }
+
+    #[test]
+    fn random_is_uniform_with_gaps_in_ids() {
+        let repository = repository::<2>();
+        for i in 0..100 {
+            let word = i.to_string();
+            repository
+                .increment_weight(link(["a", &word], "x"))
+                .unwrap();
+        }
+        // Keep only states with ids 1, 2, 50 and 100.
+        repository
+            .writer()
+            .execute_batch(
+                "DELETE FROM transition WHERE transition_from_id NOT IN (1, 2, 50, 100);
+                 DELETE FROM transition_from WHERE id NOT IN (1, 2, 50, 100);",
+            )
+            .unwrap();
+
+        let histogram = histogram(4000, || repository.random().unwrap());
+
+        assert_uniform(&histogram, 4, 4000);
+    }
+
+    #[test]
+    fn random_starting_with_is_uniform() {
+        let repository = repository::<2>();
+        for i in 0..20 {
+            let word = i.to_string();
+            repository
+                .increment_weight(link(["b", &word], "x"))
+                .unwrap();
+            if i % 5 == 0 {
+                repository
+                    .increment_weight(link(["a", &word], "x"))
+                    .unwrap();
+            }
+        }
+        let word = "a".to_string();
+
+        let histogram = histogram(4000, || repository.random_starting_with(&word).unwrap());
+
+        assert_uniform(&histogram, 4, 4000);
+        assert!(histogram.keys().all(|state| state[0] == "a"));
+    }
+
+    #[test]
+    fn random_starting_with_is_uniform_with_gaps_in_positions() {
+        let repository = repository::<2>();
+        for i in 0..100 {
+            let word = i.to_string();
+            repository
+                .increment_weight(link(["a", &word], "x"))
+                .unwrap();
+        }
+        repository.increment_weight(link(["b", "a"], "x")).unwrap();
+        // Keep only states starting with "a" at positions 0, 1, 49 and 99.
+        repository
+            .writer()
+            .execute_batch(
+                "DELETE FROM transition WHERE transition_from_id NOT IN (1, 2, 50, 100, 101);
+                 DELETE FROM transition_from WHERE id NOT IN (1, 2, 50, 100, 101);",
+            )
+            .unwrap();
+        let word = "a".to_string();
+
+        let histogram = histogram(4000, || repository.random_starting_with(&word).unwrap());
+
+        assert_uniform(&histogram, 4, 4000);
+        assert!(histogram.keys().all(|state| state[0] == "a"));
+    }
+
+    #[test]
+    fn random_starting_with_always_finds_match() {
+        let repository = repository::<2>();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+        for i in 0..100 {
+            let word = i.to_string();
+            repository
+                .increment_weight(link(["b", &word], "c"))
+                .unwrap();
+        }
+        let word = "a".to_string();
+
+        for _ in 0..100 {
+            let state = repository.random_starting_with(&word).unwrap();
+            assert_eq!(state, Some(["a".to_string(), "b".to_string()]));
+        }
+    }
+
+    #[test]
+    fn open_enables_wal_mode() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.db");
+        let repository = SqliteRepository::open::<2>(&path, SqliteOptions::default()).unwrap();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+        let journal_mode: String = repository
+            .writer()
+            .query_row("PRAGMA journal_mode;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 1);
+    }
+
+    #[test]
+    fn reads_are_not_blocked_by_pending_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.db");
+        let options = SqliteOptions {
+            busy_timeout: Duration::ZERO,
+            readers: 2,
+        };
+        let repository = SqliteRepository::open::<2>(&path, options).unwrap();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+        // Another process is in the middle of writing.
+        let writer = Connection::open(&path).unwrap();
+        writer
+            .execute_batch("BEGIN IMMEDIATE; UPDATE transition SET weight = weight + 1;")
+            .unwrap();
+
+        let from = ["a", "b"].map(str::to_string);
+        assert_eq!(repository.get(&from).unwrap()["c"], 1);
+        let stats = Repository::<String, 2>::stats(&repository, 1).unwrap();
+        assert_eq!(stats.total_weight, 1);
+        assert!(repository.increment_weight(link(["a", "b"], "c")).is_err());
+    }
+
+    #[test]
+    fn snapshot_reads_ignore_concurrent_commits() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chain.db");
+        let repository = SqliteRepository::open::<2>(&path, SqliteOptions::default()).unwrap();
+        repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+        let count = |connection: &Connection| -> i64 {
+            connection
+                .query_row("SELECT count(*) FROM transition_from", [], |row| row.get(0))
+                .unwrap()
+        };
+        let (before, after) = repository
+            .read_snapshot(|connection| {
+                let before = count(connection);
+                repository.increment_weight(link(["a", "x"], "c")).unwrap();
+                Ok((before, count(connection)))
+            })
+            .unwrap();
+        assert_eq!((before, after), (1, 1));
+        assert_eq!(
+            repository.read(|connection| Ok(count(connection))).unwrap(),
+            2
+        );
+    }
 }
@@ -1,21 +1,173 @@
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
 use cached::proc_macro::cached;
 use rusqlite::Connection;
 use sql_builder::{name, SqlBuilder, SqlName};
+use tracing::{info, instrument, warn};
 
 fn word_fk(nth: usize) -> String {
     format!("word_{}_id", nth)
 }
 
+/// Latest version of the schema, stored in `PRAGMA user_version`.
+pub const VERSION: u32 = 5;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Connection, usize) -> Result<()>,
+}
+
+/// Migrations in the order they are applied. Each of them brings the schema
+/// from the previous version to `version`. Existing migrations must never be
+/// changed, because they have already been applied to users' databases.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "store order of the chain",
+        apply: create_metadata,
+    },
+    Migration {
+        version: 3,
+        description: "index transitions by target word",
+        apply: create_transition_to_index,
+    },
+    Migration {
+        version: 4,
+        description: "track when transitions were learned",
+        apply: add_transition_updated_at,
+    },
+    Migration {
+        version: 5,
+        description: "number states starting with the same word",
+        apply: add_transition_from_position,
+    },
+];
+
+/// Creates the schema, or brings an existing one up to date, and checks that
+/// the database stores a chain of order `N`.
 pub fn setup<const N: usize>(connection: &Connection) -> Result<()> {
-    let word_fk_defs = (0..N)
+    migrate::<N>(connection)?;
+    check_order::<N>(connection)
+}
+
+/// Checks that the schema is up to date and the database stores a chain of
+/// order `N`, without changing anything, e.g. for read-only connections.
+pub fn check<const N: usize>(connection: &Connection) -> Result<()> {
+    let current = version(connection)?;
+    ensure!(
+        current <= VERSION,
+        "Database schema version {} is newer than supported version {}",
+        current,
+        VERSION
+    );
+    ensure!(
+        current == VERSION,
+        "Database schema version {} is outdated, migrate it to version {} first",
+        current,
+        VERSION
+    );
+    check_order::<N>(connection)
+}
+
+fn check_order<const N: usize>(connection: &Connection) -> Result<()> {
+    let order: String = connection.query_row(
+        "SELECT value FROM metadata WHERE key = 'order';",
+        [],
+        |row| row.get(0),
+    )?;
+    ensure!(
+        order == N.to_string(),
+        "Database was created with order {}, but order {} is used",
+        order,
+        N
+    );
+    Ok(())
+}
+
+/// Drops all tables, including the stored chain, and creates the schema again.
+#[instrument(skip_all)]
+pub fn reset<const N: usize>(connection: &Connection) -> Result<()> {
+    warn!("Dropping all data from the database");
+    connection.execute_batch(
+        "\
+BEGIN;
+DROP TABLE IF EXISTS metadata;
+DROP TABLE IF EXISTS transition;
+DROP TABLE IF EXISTS transition_from;
+DROP TABLE IF EXISTS word;
+PRAGMA user_version = 0;
+COMMIT;",
+    )?;
+    setup::<N>(connection)
+}
+
+/// Returns the version of the schema, or 0 if the database is empty.
+pub fn version(connection: &Connection) -> Result<u32> {
+    let version: u32 = connection.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+    if version == 0 && table_exists(connection, "word")? {
+        // Databases created before versioning was introduced.
+        return Ok(1);
+    }
+    Ok(version)
+}
+
+/// Applies pending migrations, each in its own transaction, and returns how
+/// many of them were applied.
+#[instrument(skip_all)]
+pub fn migrate<const N: usize>(connection: &Connection) -> Result<usize> {
+    let current = version(connection)?;
+    ensure!(
+        current <= VERSION,
+        "Database schema version {} is newer than supported version {}",
+        current,
+        VERSION
+    );
+
+    let pending: Vec<_> = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+        .collect();
+    for migration in &pending {
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "Applying migration"
+        );
+        let transaction = connection.unchecked_transaction()?;
+        (migration.apply)(&transaction, N).with_context(|| {
+            format!(
+                "Migration to version {} ({}) failed",
+                migration.version, migration.description
+            )
+        })?;
+        transaction.pragma_update(None, "user_version", migration.version)?;
+        transaction.commit()?;
+    }
+    Ok(pending.len())
+}
+
+fn table_exists(connection: &Connection, name: &str) -> Result<bool> {
+    let count: u32 = connection.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?;",
+        [name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn create_tables(connection: &Connection, order: usize) -> Result<()> {
+    let word_fk_defs = (0..order)
         .map(|i| format!("{} INTEGER NOT NULL REFERENCES word (id)", word_fk(i)))
         .collect::<Vec<_>>()
         .join(", ");
-    let word_fks = (0..N).map(word_fk).collect::<Vec<_>>().join(", ");
+    let word_fks = (0..order).map(word_fk).collect::<Vec<_>>().join(", ");
     let sql = format!(
         "\
-BEGIN;
 CREATE TABLE word (
     id INTEGER PRIMARY KEY,
     value TEXT NOT NULL UNIQUE
@@ -33,14 +185,90 @@ CREATE TABLE transition (
     weight INTEGER NOT NULL,
     PRIMARY KEY (transition_from_id, to_id),
     CHECK (weight > 0)
-);
-COMMIT;",
+);",
         word_fk_defs, word_fks
     );
     connection.execute_batch(&sql)?;
     Ok(())
 }
 
+fn create_metadata(connection: &Connection, order: usize) -> Result<()> {
+    // The order of existing databases is given by the number of word columns.
+    let columns: usize = connection.query_row(
+        "SELECT count(*) FROM pragma_table_info('transition_from') WHERE name LIKE 'word!_%!_id' ESCAPE '!';",
+        [],
+        |row| row.get(0),
+    )?;
+    ensure!(
+        columns == order,
+        "Database was created with order {}, but order {} is used",
+        columns,
+        order
+    );
+    connection.execute_batch(
+        "\
+CREATE TABLE metadata (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+) WITHOUT ROWID;",
+    )?;
+    connection.execute(
+        "INSERT INTO metadata (key, value) VALUES ('order', ?);",
+        [order.to_string()],
+    )?;
+    Ok(())
+}
+
+// Lookups by the first word of a state are served by the UNIQUE index of
+// `transition_from`, which starts with `word_0_id`.
+fn create_transition_to_index(connection: &Connection, _order: usize) -> Result<()> {
+    connection.execute_batch("CREATE INDEX transition_to_id ON transition (to_id);")?;
+    Ok(())
+}
+
+// Unix time of the last change of a weight. Transitions of existing databases
+// count as learned when the migration runs, since nothing better is known.
+// Decay only looks up transitions unchanged for a half-life by the index.
+fn add_transition_updated_at(connection: &Connection, _order: usize) -> Result<()> {
+    connection.execute_batch(
+        "\
+ALTER TABLE transition ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE transition SET updated_at = CAST(strftime('%s', 'now') AS INTEGER);
+CREATE INDEX transition_updated_at ON transition (updated_at);",
+    )?;
+    Ok(())
+}
+
+// Position of a state among the ones starting with the same word, in the
+// order they were created, so that a random one can be looked up by position
+// instead of skipping rows with OFFSET. Positions of deleted states are left
+// unused.
+fn add_transition_from_position(connection: &Connection, _order: usize) -> Result<()> {
+    connection.execute_batch(
+        "ALTER TABLE transition_from ADD COLUMN word_0_position INTEGER NOT NULL DEFAULT 0;",
+    )?;
+    let mut states =
+        connection.prepare("SELECT id, word_0_id FROM transition_from ORDER BY word_0_id, id;")?;
+    let mut rows = states.query([])?;
+    let mut update =
+        connection.prepare("UPDATE transition_from SET word_0_position = ? WHERE id = ?;")?;
+    let (mut word_id, mut position) = (None, 0);
+    while let Some(row) = rows.next()? {
+        let (id, word_0_id): (i64, i64) = (row.get(0)?, row.get(1)?);
+        if word_id != Some(word_0_id) {
+            word_id = Some(word_0_id);
+            position = 0;
+        }
+        update.execute([position, id])?;
+        position += 1;
+    }
+    connection.execute_batch(
+        "CREATE UNIQUE INDEX transition_from_word_0_position \
+         ON transition_from (word_0_id, word_0_position);",
+    )?;
+    Ok(())
+}
+
 #[cached]
 pub fn get_word() -> String {
     SqlBuilder::select_from("word")
@@ -70,32 +298,102 @@ pub fn get_transition_from(n: usize) -> String {
         .unwrap()
 }
 
+/// Inserts a state after the last one starting with the same word.
 #[cached]
 pub fn insert_transition_from(n: usize) -> String {
+    let position = SqlBuilder::select_from("transition_from")
+        .field("coalesce(max(word_0_position) + 1, 0)")
+        .and_where_eq(word_fk(0), "?1")
+        .subquery()
+        .unwrap();
+    let mut values: Vec<_> = (1..=n).map(|i| format!("?{}", i)).collect();
+    values.push(position);
     (0..n)
         .fold(
             &mut SqlBuilder::insert_into("transition_from"),
             |builder, i| builder.field(word_fk(i)),
         )
-        .values(&vec!["?"; n])
+        .field("word_0_position")
+        .values(&values)
         .sql()
         .unwrap()
 }
 
 #[cached]
-pub fn increment_weight() -> String {
+pub fn add_weight() -> String {
     let mut sql = SqlBuilder::insert_into("transition")
-        .fields(&["transition_from_id", "to_id", "weight"])
-        .values(&["?", "?", "1"])
+        .fields(&["transition_from_id", "to_id", "weight", "updated_at"])
+        .values(&["?", "?", "?", "?"])
         .sql()
         .unwrap();
     if sql.ends_with(';') {
         sql.pop();
     }
-    sql.push_str(" ON CONFLICT (transition_from_id, to_id) DO UPDATE SET weight = weight + 1;");
+    sql.push_str(
+        " ON CONFLICT (transition_from_id, to_id) DO UPDATE SET \
+            weight = weight + excluded.weight, \
+            updated_at = max(updated_at, excluded.updated_at);",
+    );
     sql
 }
 
+/// Selects transitions last changed at or before the time given by the
+/// parameter.
+#[cached]
+pub fn get_transitions_updated_before() -> String {
+    SqlBuilder::select_from("transition")
+        .fields(&["transition_from_id", "to_id", "weight", "updated_at"])
+        .and_where_le("updated_at", "?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn set_weight() -> String {
+    SqlBuilder::update_table("transition")
+        .set("weight", "?")
+        .set("updated_at", "?")
+        .and_where_eq("transition_from_id", "?")
+        .and_where_eq("to_id", "?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn delete_transition() -> String {
+    SqlBuilder::delete_from("transition")
+        .and_where_eq("transition_from_id", "?")
+        .and_where_eq("to_id", "?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn delete_unused_states() -> String {
+    SqlBuilder::delete_from("transition_from")
+        .and_where("id NOT IN (SELECT transition_from_id FROM transition)")
+        .sql()
+        .unwrap()
+}
+
+/// Deletes words neither a successor nor a word of a state, which must be
+/// deleted first.
+#[cached]
+pub fn delete_unused_words(n: usize) -> String {
+    (0..n)
+        .fold(
+            SqlBuilder::delete_from("word").and_where("id NOT IN (SELECT to_id FROM transition)"),
+            |builder, i| {
+                builder.and_where(format!(
+                    "id NOT IN (SELECT {} FROM transition_from)",
+                    word_fk(i)
+                ))
+            },
+        )
+        .sql()
+        .unwrap()
+}
+
 #[cached]
 pub fn get_weights(n: usize) -> String {
     (0..n)
@@ -119,24 +417,342 @@ pub fn get_weights(n: usize) -> String {
 }
 
 #[cached]
-pub fn get_random(n: usize, filter_starts_with: bool) -> String {
-    let max_rowid = SqlBuilder::select_from("transition_from")
-        .field("max(rowid)")
-        .subquery_as("max_rowid")
-        .unwrap();
-    let mut builder = SqlBuilder::select_from(name!("transition_from"; "tf"));
-    let mut builder = (0..n)
-        .fold(&mut builder, |builder, i| {
-            let alias = format!("w{}", i);
-            builder
-                .join(name!("word"; &alias))
-                .on_eq(format!("{}.id", &alias), format!("tf.{}", word_fk(i)))
-                .field(format!("{}.value", &alias))
-        })
-        .field(max_rowid)
-        .and_where("tf.rowid >= abs(random()) % max_rowid");
+pub fn get_max_state_id() -> String {
+    SqlBuilder::select_from("transition_from")
+        .field("max(id)")
+        .sql()
+        .unwrap()
+}
+
+/// Selects the highest position of states starting with the word of id given
+/// by the parameter.
+#[cached]
+pub fn get_max_state_position() -> String {
+    SqlBuilder::select_from("transition_from")
+        .field("max(word_0_position)")
+        .and_where_eq(word_fk(0), "?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn count_states(filter_starts_with: bool) -> String {
+    let mut builder = SqlBuilder::select_from("transition_from");
+    builder.field("count(*)");
     if filter_starts_with {
-        builder = builder.and_where_eq("w0.value", "?");
+        builder.and_where_eq(word_fk(0), "?");
     }
     builder.sql().unwrap()
 }
+
+/// Selects the state at position given by the last parameter, optionally
+/// among states starting with the word of id given by the first parameter.
+///
+/// SQLite steps over every skipped row, so the cost is linear in the position.
+#[cached]
+pub fn get_nth_state(n: usize, filter_starts_with: bool) -> String {
+    let mut builder = SqlBuilder::select_from(name!("transition_from"; "tf"));
+    (0..n).fold(&mut builder, |builder, i| {
+        let alias = format!("w{}", i);
+        builder
+            .join(name!("word"; &alias))
+            .on_eq(format!("{}.id", &alias), format!("tf.{}", word_fk(i)))
+            .field(format!("{}.value", &alias))
+    });
+    if filter_starts_with {
+        builder.and_where_eq(format!("tf.{}", word_fk(0)), "?");
+    }
+    builder.limit(1).offset("?").sql().unwrap()
+}
+
+#[cached]
+pub fn get_size() -> String {
+    "SELECT \
+        (SELECT count(*) FROM word), \
+        (SELECT count(*) FROM transition_from), \
+        (SELECT count(*) FROM transition);"
+        .to_string()
+}
+
+#[cached]
+pub fn get_total_weight() -> String {
+    SqlBuilder::select_from("transition")
+        .field("coalesce(sum(weight), 0)")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn get_top_words() -> String {
+    SqlBuilder::select_from(name!("transition"; "t"))
+        .join(name!("word"; "w"))
+        .on_eq("w.id", "t.to_id")
+        .fields(&["w.value", "sum(t.weight) AS total"])
+        .group_by("t.to_id")
+        .order_desc("total")
+        .limit("?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn get_state_weights() -> String {
+    SqlBuilder::select_from("transition")
+        .fields(&["transition_from_id", "weight"])
+        .order_asc("transition_from_id")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn get_state(n: usize) -> String {
+    (0..n)
+        .fold(
+            &mut SqlBuilder::select_from(name!("transition_from"; "tf")),
+            |builder, i| {
+                let alias = format!("w{}", i);
+                builder
+                    .join(name!("word"; &alias))
+                    .on_eq(format!("{}.id", &alias), format!("tf.{}", word_fk(i)))
+                    .field(format!("{}.value", &alias))
+            },
+        )
+        .and_where_eq("tf.id", "?")
+        .sql()
+        .unwrap()
+}
+
+/// Selects the state starting with the word of id given by the first
+/// parameter at position given by the second one.
+#[cached]
+pub fn get_state_at_position(n: usize) -> String {
+    (0..n)
+        .fold(
+            &mut SqlBuilder::select_from(name!("transition_from"; "tf")),
+            |builder, i| {
+                let alias = format!("w{}", i);
+                builder
+                    .join(name!("word"; &alias))
+                    .on_eq(format!("{}.id", &alias), format!("tf.{}", word_fk(i)))
+                    .field(format!("{}.value", &alias))
+            },
+        )
+        .and_where_eq(format!("tf.{}", word_fk(0)), "?")
+        .and_where_eq("tf.word_0_position", "?")
+        .sql()
+        .unwrap()
+}
+
+#[cached]
+pub fn get_transitions(n: usize) -> String {
+    (0..n)
+        .fold(
+            &mut SqlBuilder::select_from(name!("transition_from"; "tf")),
+            |builder, i| {
+                let alias = format!("w{}", i);
+                builder
+                    .join(name!("word"; &alias))
+                    .on_eq(format!("{}.id", &alias), format!("tf.{}", word_fk(i)))
+                    .field(format!("{}.value", &alias))
+            },
+        )
+        .join(name!("transition"; "t"))
+        .on_eq("t.transition_from_id", "tf.id")
+        .join(name!("word"; "w"))
+        .on_eq("w.id", "t.to_id")
+        .fields(&["w.value", "t.weight"])
+        .sql()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use rusqlite::Connection;
+
+    use super::{
+        count_states, get_max_state_position, get_nth_state, get_state, get_state_at_position,
+        get_top_words, get_transitions_updated_before, get_weights, get_word,
+        insert_transition_from, migrate, reset, setup, version, VERSION,
+    };
+    use crate::adapters::sqlite::repository::SqliteRepository;
+    use crate::markov::decay::{DecayPolicy, Decayed};
+    use crate::markov::repository::Repository;
+
+    fn v1_fixture() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(include_str!("fixtures/v1.sql"))
+            .unwrap();
+        connection
+    }
+
+    fn order(connection: &Connection) -> String {
+        connection
+            .query_row(
+                "SELECT value FROM metadata WHERE key = 'order';",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn creates_latest_schema() {
+        let connection = Connection::open_in_memory().unwrap();
+        assert_eq!(version(&connection).unwrap(), 0);
+
+        setup::<3>(&connection).unwrap();
+
+        assert_eq!(version(&connection).unwrap(), VERSION);
+        assert_eq!(order(&connection), "3");
+    }
+
+    #[test]
+    fn setup_is_idempotent() {
+        let connection = Connection::open_in_memory().unwrap();
+        setup::<2>(&connection).unwrap();
+
+        assert_eq!(migrate::<2>(&connection).unwrap(), 0);
+        assert_eq!(version(&connection).unwrap(), VERSION);
+    }
+
+    #[test]
+    fn upgrades_v1_database() {
+        let connection = v1_fixture();
+        assert_eq!(version(&connection).unwrap(), 1);
+
+        assert_eq!(migrate::<2>(&connection).unwrap(), VERSION as usize - 1);
+
+        assert_eq!(version(&connection).unwrap(), VERSION);
+        assert_eq!(order(&connection), "2");
+        let repository = SqliteRepository::new::<2>(connection).unwrap();
+        let from = ["hello", "there"].map(str::to_string);
+        let weights = Repository::<String, 2>::get(&repository, &from).unwrap();
+        assert_eq!(weights["friend"], 3);
+        assert_eq!(
+            Repository::<String, 2>::size(&repository)
+                .unwrap()
+                .transitions,
+            2
+        );
+        // Transitions learned before they were timestamped count as recent.
+        let policy = DecayPolicy {
+            half_life: Duration::from_secs(60 * 60),
+        };
+        let decayed = Repository::<String, 2>::decay(&repository, &policy, SystemTime::now());
+        assert_eq!(decayed.unwrap(), Decayed::default());
+    }
+
+    #[test]
+    fn numbers_states_by_first_word() {
+        let connection = v1_fixture();
+        connection
+            .execute_batch(
+                "INSERT INTO transition_from (id, word_0_id, word_1_id) \
+                 VALUES (3, 1, 3), (4, 2, 1), (5, 1, 1);",
+            )
+            .unwrap();
+        migrate::<2>(&connection).unwrap();
+        connection
+            .execute(&insert_transition_from(2), [1, 4])
+            .unwrap();
+
+        let positions: Vec<(i64, i64)> = connection
+            .prepare("SELECT id, word_0_position FROM transition_from ORDER BY id;")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(positions, [(1, 0), (2, 0), (3, 1), (4, 1), (5, 2), (6, 3)]);
+    }
+
+    #[test]
+    fn rejects_v1_database_of_different_order() {
+        let connection = v1_fixture();
+
+        let error = migrate::<3>(&connection).unwrap_err();
+
+        assert!(format!("{:#}", error).contains("created with order 2"));
+        assert_eq!(version(&connection).unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_database_of_different_order() {
+        let connection = Connection::open_in_memory().unwrap();
+        setup::<2>(&connection).unwrap();
+
+        let error = setup::<3>(&connection).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Database was created with order 2, but order 3 is used"
+        );
+    }
+
+    #[test]
+    fn reset_recreates_schema() {
+        let connection = v1_fixture();
+
+        reset::<3>(&connection).unwrap();
+
+        assert_eq!(version(&connection).unwrap(), VERSION);
+        assert_eq!(order(&connection), "3");
+        let words: u32 = connection
+            .query_row("SELECT count(*) FROM word;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(words, 0);
+    }
+
+    #[test]
+    fn rejects_newer_database() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "user_version", VERSION + 1)
+            .unwrap();
+
+        assert!(migrate::<2>(&connection).is_err());
+    }
+
+    fn query_plan(connection: &Connection, sql: &str) -> Vec<String> {
+        let mut statement = connection
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+            .unwrap();
+        // Parameters are left unbound, they don't affect the plan.
+        let mut rows = statement.raw_query();
+        let mut plan = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            plan.push(row.get(3).unwrap());
+        }
+        plan
+    }
+
+    #[test]
+    fn lookups_use_indexes() {
+        let connection = Connection::open_in_memory().unwrap();
+        setup::<3>(&connection).unwrap();
+
+        for sql in [
+            get_word(),
+            get_weights(3),
+            get_state(3),
+            get_max_state_position(),
+            get_state_at_position(3),
+            count_states(true),
+            get_nth_state(3, true),
+            get_transitions_updated_before(),
+        ] {
+            let plan = query_plan(&connection, &sql);
+            assert!(
+                plan.iter().all(|step| !step.starts_with("SCAN")),
+                "{} is executed with a full scan: {:?}",
+                sql,
+                plan
+            );
+        }
+        let plan = query_plan(&connection, &get_top_words());
+        assert!(plan.iter().any(|step| step.contains("transition_to_id")));
+    }
+}
@@ -0,0 +1,247 @@
+//! Stores tokens in repositories of strings, i.e. every backend persisting
+//! words. `End` is stored as "\0", as chains learned before tokens were
+//! introduced did, and words starting with NUL are escaped with another one,
+//! so that no word can collide with `Start` or `End`.
+//!
+//! Chains learned before tokens stored words unescaped. In them, the word
+//! "\0^" reads as `Start` and words starting with two NULs lose the first
+//! one. They never contain `Start`, so exporting them and escaping those words
+//! in the export migrates them, as described in the README.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::markov::choose::Choose;
+use crate::markov::decay::{DecayPolicy, Decayed};
+use crate::markov::repository::Repository;
+use crate::markov::stats::Stats;
+use crate::markov::types::{Link, Size, Token, WeightMap};
+
+const ESCAPE: &str = "\0";
+const START: &str = "\0^";
+const END: &str = "\0";
+
+pub fn encode(token: &Token<String>) -> String {
+    match token {
+        Token::Start => START.to_string(),
+        Token::End => END.to_string(),
+        Token::Word(word) if word.starts_with(ESCAPE) => format!("{}{}", ESCAPE, word),
+        Token::Word(word) => word.clone(),
+    }
+}
+
+pub fn decode(word: String) -> Token<String> {
+    match word.as_str() {
+        START => Token::Start,
+        END => Token::End,
+        // Unescaped words starting with NUL may have been learned before
+        // escaping, so they are taken as they are.
+        escaped if escaped.starts_with("\0\0") => Token::Word(escaped[ESCAPE.len()..].to_string()),
+        _ => Token::Word(word),
+    }
+}
+
+fn encode_link<const N: usize>(link: &Link<Token<String>, N>) -> Link<String, N> {
+    Link::new(link.from.each_ref().map(encode), encode(&link.to))
+}
+
+fn decode_link<const N: usize>(link: Link<String, N>) -> Link<Token<String>, N> {
+    Link::new(link.from.map(decode), decode(link.to))
+}
+
+// Lets the chooser of tokens pick among the encoded words.
+struct Encoded<'a>(&'a dyn Choose<Token<String>>);
+
+impl Choose<String> for Encoded<'_> {
+    fn generate_random(&self, upper_bound: u32) -> u32 {
+        self.0.generate_random(upper_bound)
+    }
+}
+
+/// Repository of tokens backed by a repository of strings.
+pub struct TokenRepository<R> {
+    repository: R,
+}
+
+impl<R> TokenRepository<R> {
+    pub fn new(repository: R) -> TokenRepository<R> {
+        TokenRepository { repository }
+    }
+}
+
+impl<R, const N: usize> Repository<Token<String>, N> for TokenRepository<R>
+where
+    R: Repository<String, N>,
+{
+    fn get(&self, from: &[Token<String>; N]) -> Result<WeightMap<Token<String>>> {
+        let weights = self.repository.get(&from.each_ref().map(encode))?;
+        Ok(weights
+            .into_iter()
+            .map(|(word, weight)| (decode(word), weight))
+            .collect())
+    }
+
+    fn sample(
+        &self,
+        from: &[Token<String>; N],
+        chooser: &dyn Choose<Token<String>>,
+    ) -> Result<Option<Token<String>>> {
+        let to = self
+            .repository
+            .sample(&from.each_ref().map(encode), &Encoded(chooser))?;
+        Ok(to.map(decode))
+    }
+
+    fn random(&self) -> Result<Option<[Token<String>; N]>> {
+        Ok(self.repository.random()?.map(|state| state.map(decode)))
+    }
+
+    fn random_starting_with(&self, state: &Token<String>) -> Result<Option<[Token<String>; N]>> {
+        let state = self.repository.random_starting_with(&encode(state))?;
+        Ok(state.map(|state| state.map(decode)))
+    }
+
+    fn add_weight(&self, link: Link<Token<String>, N>, weight: u32) -> Result<()> {
+        self.repository.add_weight(encode_link(&link), weight)
+    }
+
+    fn add_weights(&self, links: &[(Link<Token<String>, N>, u32)]) -> Result<()> {
+        let links: Vec<_> = links
+            .iter()
+            .map(|(link, weight)| (encode_link(link), *weight))
+            .collect();
+        self.repository.add_weights(&links)
+    }
+
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<Token<String>, N>, u32)>>,
+    ) -> Result<()> {
+        let mut links = links.map(|link| link.map(|(link, weight)| (encode_link(&link), weight)));
+        self.repository.add_all_weights(&mut links)
+    }
+
+    fn size(&self) -> Result<Size> {
+        self.repository.size()
+    }
+
+    fn stats(&self, top: usize) -> Result<Stats<Token<String>, N>> {
+        Ok(self.repository.stats(top)?.map(decode))
+    }
+
+    fn for_each_transition(
+        &self,
+        f: &mut dyn FnMut(Link<Token<String>, N>, u32) -> Result<()>,
+    ) -> Result<()> {
+        self.repository
+            .for_each_transition(&mut |link, weight| f(decode_link(link), weight))
+    }
+
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        self.repository.decay(policy, now)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.repository.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, TokenRepository};
+    use crate::adapters::memory::repository::MemoryRepository;
+    use crate::adapters::rand::choose::RandChoose;
+    use crate::adapters::rand::shuffle::RandShuffle;
+    use crate::markov::bot::Bot;
+    use crate::markov::chain::Chain;
+    use crate::markov::repository::Repository;
+    use crate::markov::tokenizer::Tokenizer;
+    use crate::markov::types::{Link, Token, WeightMap};
+
+    fn word(word: &str) -> Token<String> {
+        Token::Word(word.to_string())
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let tokens = [
+            Token::Start,
+            Token::End,
+            word("a"),
+            word(""),
+            word("\0"),
+            word("\0^"),
+            word("\0\0"),
+            word("a\0"),
+        ];
+        for token in tokens {
+            assert_eq!(decode(encode(&token)), token);
+        }
+    }
+
+    #[test]
+    fn words_never_collide_with_start_or_end() {
+        assert_ne!(encode(&word("\0")), encode(&Token::End));
+        assert_ne!(encode(&word("\0^")), encode(&Token::Start));
+    }
+
+    #[test]
+    fn stores_start_marker_word_escaped() {
+        let strings: MemoryRepository<String, 1> = MemoryRepository::new();
+        let repository = TokenRepository::new(strings);
+        repository
+            .increment_weight(Link::new([word("\0^")], Token::End))
+            .unwrap();
+
+        let escaped = "\0\0^".to_string();
+        assert_eq!(repository.repository.get(&[escaped]).unwrap().len(), 1);
+        assert!(repository
+            .repository
+            .get(&["\0^".to_string()])
+            .unwrap()
+            .is_empty());
+        assert_eq!(repository.random().unwrap(), Some([word("\0^")]));
+    }
+
+    #[test]
+    fn decodes_chains_learned_before_tokens() {
+        assert_eq!(decode("\0".to_string()), Token::End);
+        assert_eq!(decode("\0x".to_string()), word("\0x"));
+        // Indistinguishable from the marker, see the module documentation.
+        assert_eq!(decode("\0^".to_string()), Token::Start);
+    }
+
+    #[test]
+    fn stores_tokens_as_strings() {
+        let strings: MemoryRepository<String, 2> = MemoryRepository::new();
+        let repository = TokenRepository::new(strings);
+        repository
+            .increment_weight(Link::new([Token::Start, word("\0")], Token::End))
+            .unwrap();
+
+        assert_eq!(
+            repository.get(&[Token::Start, word("\0")]).unwrap(),
+            WeightMap::from([(Token::End, 1)])
+        );
+        assert_eq!(
+            repository.random_starting_with(&Token::Start).unwrap(),
+            Some([Token::Start, word("\0")])
+        );
+        assert_eq!(
+            repository.stats(1).unwrap().top_words,
+            vec![(Token::End, 1)]
+        );
+    }
+
+    #[test]
+    fn bot_learns_nul_as_a_word() {
+        let strings: MemoryRepository<String, 2> = MemoryRepository::new();
+        let chain = Chain::new(TokenRepository::new(strings), RandChoose::new());
+        let bot: Bot<_, _, _, _, 2> = Bot::new(chain, RandShuffle::new(), Tokenizer::Whitespace);
+        bot.learn("a \0 b").unwrap();
+
+        assert_eq!(bot.say().unwrap(), "a \0 b");
+        assert_eq!(bot.reply("\0").unwrap(), "\0 b");
+    }
+}
@@ -0,0 +1,13 @@
+use anyhow::Result;
+use markov::adapters::frozen::compile::compile;
+
+use crate::{DynRepository, ORDER, TOKENIZER};
+
+pub fn run(repository: &DynRepository, output: &str) -> Result<()> {
+    let size = compile::<_, ORDER>(repository, TOKENIZER, output)?;
+    println!(
+        "Compiled {} words, {} states and {} transitions to {}",
+        size.words, size.states, size.transitions, output
+    );
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use markov::markov::decay::DecayPolicy;
+use markov::markov::repository::Repository;
+
+use crate::{DynRepository, ORDER};
+
+pub fn run(repository: &DynRepository, policy: DecayPolicy) -> Result<()> {
+    let decayed = Repository::<String, ORDER>::decay(repository, &policy, SystemTime::now())?;
+    Repository::<String, ORDER>::flush(repository)?;
+    println!(
+        "Decayed {} transitions, {} of them were forgotten and removed",
+        decayed.decayed, decayed.removed
+    );
+    Ok(())
+}
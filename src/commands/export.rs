@@ -0,0 +1,19 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{Context, Result};
+use markov::adapters::interchange;
+
+use crate::{DynRepository, ORDER, TOKENIZER};
+
+pub fn run(repository: &DynRepository, output: Option<&str>) -> Result<()> {
+    let writer: Box<dyn Write> = match output {
+        Some(path) => {
+            let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+            Box::new(file)
+        }
+        None => Box::new(io::stdout()),
+    };
+    interchange::export::<String, _, ORDER>(repository, TOKENIZER, &mut BufWriter::new(writer))?;
+    Ok(())
+}
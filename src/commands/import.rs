@@ -0,0 +1,20 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use anyhow::{Context, Result};
+use markov::adapters::interchange;
+use markov::markov::repository::Repository;
+
+use crate::{DynRepository, ORDER, TOKENIZER};
+
+pub fn run(repository: DynRepository, input: Option<&str>) -> Result<()> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+            Box::new(BufReader::new(file))
+        }
+        None => Box::new(io::stdin().lock()),
+    };
+    interchange::import::<String, _, ORDER>(&repository, TOKENIZER, reader)?;
+    Repository::<String, ORDER>::flush(&repository)
+}
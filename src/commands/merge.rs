@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use markov::adapters::memory::repository::MemoryRepository;
+use markov::adapters::memory::snapshot::Snapshot;
+use markov::adapters::merge::merge;
+use markov::adapters::sqlite::repository::SqliteRepository;
+use markov::markov::repository::Repository;
+
+use crate::{DynRepository, ORDER, TOKENIZER};
+
+pub enum Source<'a> {
+    Sqlite(&'a str),
+    Snapshot(&'a str),
+}
+
+pub fn run(repository: DynRepository, sources: Vec<(Source, f64)>) -> Result<()> {
+    for (source, scale) in sources {
+        let (path, source) = open_source(&source)?;
+        merge::<String, _, _, ORDER>(&repository, &source, scale)
+            .with_context(|| format!("Failed to merge {}", path))?;
+    }
+    Repository::<String, ORDER>::flush(&repository)
+}
+
+/// Splits `PATH[:SCALE]` into the path and the scale, which defaults to 1.
+pub fn parse_source(source: &str) -> Result<(&str, f64)> {
+    let (path, scale) = match source.rsplit_once(':') {
+        Some((path, scale)) => match scale.parse::<f64>() {
+            Ok(scale) => (path, scale),
+            // Not a scale, e.g. a Windows drive letter.
+            Err(_) => (source, 1.0),
+        },
+        None => (source, 1.0),
+    };
+    ensure!(
+        scale.is_finite() && scale > 0.0,
+        "scale must be a positive number"
+    );
+    Ok((path, scale))
+}
+
+fn open_source<'a>(source: &Source<'a>) -> Result<(&'a str, DynRepository)> {
+    let (path, repository): (_, DynRepository) = match *source {
+        Source::Sqlite(path) => {
+            let repository = SqliteRepository::open_read_only::<ORDER>(path)
+                .with_context(|| format!("Failed to open {}", path))?;
+            (path, Box::new(repository))
+        }
+        Source::Snapshot(path) => {
+            ensure!(Path::new(path).exists(), "Snapshot {} doesn't exist", path);
+            let snapshot = Snapshot::new(path, TOKENIZER);
+            (path, Box::new(MemoryRepository::with_snapshot(snapshot)?))
+        }
+    };
+    Ok((path, repository))
+}
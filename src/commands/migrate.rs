@@ -0,0 +1,15 @@
+use anyhow::Result;
+use markov::adapters::sqlite::schema;
+use rusqlite::Connection;
+
+use crate::ORDER;
+
+pub fn run(connection: &Connection) -> Result<()> {
+    let applied = schema::migrate::<ORDER>(connection)?;
+    println!(
+        "Applied {} migrations, schema is at version {}",
+        applied,
+        schema::version(connection)?
+    );
+    Ok(())
+}
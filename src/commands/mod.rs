@@ -0,0 +1,9 @@
+pub mod compile;
+pub mod decay;
+pub mod export;
+pub mod import;
+pub mod merge;
+pub mod migrate;
+pub mod names;
+pub mod run;
+pub mod stats;
@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use anyhow::{ensure, Context, Result};
+use markov::adapters::memory::repository::MemoryRepository;
+use markov::adapters::rand::choose::RandChoose;
+use markov::markov::chain::Chain;
+use markov::markov::names::{NameGenerator, NameOptions};
+
+/// Generates `count` distinct names with a chain of order `N`, i.e. of `N`
+/// characters of context.
+pub fn run<const N: usize>(input: Option<&str>, count: usize, options: NameOptions) -> Result<()> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+            Box::new(BufReader::new(file))
+        }
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let chain = Chain::new(MemoryRepository::new(), RandChoose::new());
+    let names: NameGenerator<_, _, N> = NameGenerator::new(chain, options);
+    for line in reader.lines() {
+        for word in line?.split_whitespace() {
+            names.learn(word)?;
+        }
+    }
+
+    let mut generated = HashSet::new();
+    // Small lists of examples allow only a few names, so stop once no new one
+    // is found for a while.
+    let mut duplicates = 0;
+    while generated.len() < count {
+        let name = names
+            .generate()?
+            .context("Failed to generate a name, try a lower order or other lengths")?;
+        if generated.insert(name.clone()) {
+            println!("{}", name);
+            duplicates = 0;
+        } else {
+            duplicates += 1;
+            ensure!(
+                duplicates < options.attempts,
+                "Generated only {} distinct names, try a lower order or more examples",
+                generated.len()
+            );
+        }
+    }
+    Ok(())
+}
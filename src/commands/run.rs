@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use clap::ArgMatches;
+use markov::adapters::discord::bot::DiscordBot;
+use markov::adapters::metrics::Metrics;
+use markov::adapters::rand::choose::RandChoose;
+use markov::adapters::rand::shuffle::RandShuffle;
+use markov::adapters::token::TokenRepository;
+use markov::markov::bot::Bot;
+use markov::markov::chain::Chain;
+use markov::markov::repository::Repository;
+use tracing::info;
+
+use crate::{decay_policy, DynRepository, ORDER, TOKENIZER};
+
+pub async fn run(repository: DynRepository, matches: &ArgMatches<'_>) -> Result<()> {
+    let token = matches.value_of("token").unwrap();
+    let verbosity = matches
+        .value_of("verbosity")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap();
+
+    #[cfg(feature = "metrics")]
+    let metrics = match matches.value_of("metrics-addr") {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new()?);
+            markov::adapters::metrics::serve(addr.parse().unwrap(), metrics.clone())?;
+            metrics
+        }
+        None => Arc::new(Metrics::disabled()),
+    };
+    #[cfg(not(feature = "metrics"))]
+    let metrics = Arc::new(Metrics::disabled());
+
+    // Decaying once before connecting catches backends not supporting it, and
+    // catches up on the time the bot wasn't running.
+    let decay = match decay_policy(matches) {
+        Some(policy) => {
+            let decayed = repository.decay(&policy, SystemTime::now())?;
+            info!(
+                decayed = decayed.decayed,
+                removed = decayed.removed,
+                "Decayed weights"
+            );
+            let secs = matches.value_of("decay-interval").unwrap().parse().unwrap();
+            Some((policy, Duration::from_secs(secs)))
+        }
+        None => None,
+    };
+
+    let chooser = RandChoose::new();
+    let shuffler = RandShuffle::new();
+    let chain = Chain::new(TokenRepository::new(repository), chooser);
+    let bot: Bot<_, _, _, _, ORDER> = Bot::new(chain, shuffler, TOKENIZER);
+    let mut discord = DiscordBot::new(token, verbosity, metrics);
+    // Both keep recent changes in memory until flushed.
+    if matches.is_present("snapshot-path") || matches.is_present("kv-path") {
+        let secs = matches
+            .value_of("autosave-interval")
+            .unwrap()
+            .parse()
+            .unwrap();
+        discord = discord.autosave(Duration::from_secs(secs));
+    }
+    if let Some((policy, interval)) = decay {
+        discord = discord.decay(policy, interval);
+    }
+    discord.run(bot).await
+}
@@ -0,0 +1,12 @@
+use anyhow::Result;
+use markov::adapters::token::TokenRepository;
+use markov::markov::repository::Repository;
+use markov::markov::types::Token;
+
+use crate::{DynRepository, ORDER};
+
+pub fn run(repository: DynRepository, top: usize) -> Result<()> {
+    let stats = Repository::<Token<String>, ORDER>::stats(&TokenRepository::new(repository), top)?;
+    print!("{}", stats);
+    Ok(())
+}
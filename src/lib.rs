@@ -0,0 +1,28 @@
+//! Markov chain text generator. The engine in [`markov`] is independent of
+//! storage and randomness, which are provided by [`adapters`].
+//!
+//! ```
+//! use markov::adapters::memory::repository::MemoryRepository;
+//! use markov::adapters::rand::choose::RandChoose;
+//! use markov::adapters::rand::shuffle::RandShuffle;
+//! use markov::markov::tokenizer::Tokenizer;
+//! use markov::markov::{Bot, Chain};
+//!
+//! let chain = Chain::new(MemoryRepository::new(), RandChoose::new());
+//! let bot: Bot<_, _, _, _, 2> = Bot::new(chain, RandShuffle::new(), Tokenizer::Whitespace);
+//! bot.learn("the cat sat on the mat")?;
+//!
+//! assert_eq!(bot.reply("a cat")?, "cat sat on the mat");
+//! # anyhow::Ok(())
+//! ```
+//!
+//! # Features
+//!
+//! - `sqlite` (default): `adapters::sqlite` repository.
+//! - `discord` (default): `adapters::discord` bot.
+//! - `cli` (default): dependencies of the `markov` binary.
+//! - `postgres`: `adapters::postgres` repository.
+//! - `metrics`: Prometheus metrics of the Discord bot.
+
+pub mod adapters;
+pub mod markov;
@@ -0,0 +1,22 @@
+use anyhow::{anyhow, Result};
+use tracing_subscriber::EnvFilter;
+
+pub const FORMATS: &[&str] = &["text", "json"];
+
+/// Installs the global subscriber. An explicit `level` takes precedence over
+/// `RUST_LOG`, which in turn falls back to `info`.
+pub fn init(level: Option<&str>, format: &str) -> Result<()> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        "json" => builder.json().try_init(),
+        _ => builder.try_init(),
+    }
+    .map_err(|e| anyhow!(e))
+}
@@ -1,34 +1,130 @@
-use anyhow::Result;
-use clap::{App, Arg};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use markov::adapters::frozen::repository::FrozenRepository;
+use markov::adapters::kv::repository::KvRepository;
+use markov::adapters::memory::repository::MemoryRepository;
+use markov::adapters::memory::snapshot::Snapshot;
+#[cfg(feature = "postgres")]
+use markov::adapters::postgres::repository::PostgresRepository;
+use markov::adapters::retry::{RetryPolicy, RetryingRepository};
+use markov::adapters::sqlite::repository::{SqliteOptions, SqliteRepository};
+use markov::adapters::sqlite::schema;
+use markov::markov::decay::DecayPolicy;
+use markov::markov::names::NameOptions;
+use markov::markov::repository::Repository;
+use markov::markov::tokenizer::Tokenizer;
 use rusqlite::Connection;
 
-use crate::adapters::discord::bot::DiscordBot;
-use crate::adapters::memory::MemoryRepository;
-use crate::adapters::rand::choose::RandChoose;
-use crate::adapters::rand::shuffle::RandShuffle;
-use crate::adapters::sqlite::repository::SqliteRepository;
-use crate::adapters::sqlite::schema::setup;
-use crate::markov::bot::Bot;
-use crate::markov::chain::Chain;
+use crate::commands::merge::Source;
 
-mod adapters;
-mod markov;
+mod commands;
+mod logging;
 
 const ORDER: usize = 2;
+const TOKENIZER: Tokenizer = Tokenizer::Whitespace;
+
+type DynRepository = Box<dyn Repository<String, ORDER> + Send + Sync>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let matches = App::new("markov")
+    let app = App::new("markov")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("sqlite-path")
                 .long("sqlite-path")
                 .takes_value(true)
+                .global(true)
                 .help("Path to SQLite database"),
         )
+        .arg(
+            Arg::with_name("sqlite-busy-timeout")
+                .long("sqlite-busy-timeout")
+                .takes_value(true)
+                .default_value("5000")
+                .global(true)
+                .validator(|ms| ms.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Milliseconds to wait for a locked SQLite database"),
+        )
+        .arg(
+            Arg::with_name("sqlite-readers")
+                .long("sqlite-readers")
+                .takes_value(true)
+                .default_value("4")
+                .global(true)
+                .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Number of read-only SQLite connections (0 to read through the writer)"),
+        )
+        .arg(
+            Arg::with_name("snapshot-path")
+                .long("snapshot-path")
+                .takes_value(true)
+                .global(true)
+                .conflicts_with("sqlite-path")
+                .help("Path to snapshot file persisting the in-memory chain"),
+        )
+        .arg(
+            Arg::with_name("kv-path")
+                .long("kv-path")
+                .takes_value(true)
+                .global(true)
+                .conflicts_with_all(&["sqlite-path", "snapshot-path"])
+                .help("Path to embedded key-value database"),
+        )
+        .arg(
+            Arg::with_name("frozen-path")
+                .long("frozen-path")
+                .takes_value(true)
+                .global(true)
+                .conflicts_with_all(&["sqlite-path", "snapshot-path", "kv-path"])
+                .help("Path to read-only chain written by `compile`"),
+        )
+        .arg(
+            Arg::with_name("autosave-interval")
+                .long("autosave-interval")
+                .takes_value(true)
+                .default_value("300")
+                .validator(|secs| match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Ok(()),
+                    _ => Err("must be a positive number of seconds".to_string()),
+                })
+                .help("Seconds between saves (used with --snapshot-path and --kv-path)"),
+        )
+        .arg(
+            Arg::with_name("decay-half-life")
+                .long("decay-half-life")
+                .takes_value(true)
+                .global(true)
+                .validator(|hours| match hours.parse::<f64>() {
+                    Ok(hours)
+                        if hours > 0.0 && Duration::try_from_secs_f64(hours * 3600.0).is_ok() =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err("must be a positive number of hours".to_string()),
+                })
+                .help(
+                    "Hours after which weights of transitions not learned again are halved \
+                     (fails at startup with --frozen-path)",
+                ),
+        )
+        .arg(
+            Arg::with_name("decay-interval")
+                .long("decay-interval")
+                .takes_value(true)
+                .default_value("3600")
+                .validator(|secs| match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Ok(()),
+                    _ => Err("must be a positive number of seconds".to_string()),
+                })
+                .help("Seconds between decays of weights (used with --decay-half-life)"),
+        )
         .arg(
             Arg::with_name("setup-db")
                 .long("setup-db")
-                .requires("sqlite-path"),
+                .requires("sqlite-path")
+                .help("Delete the stored chain and recreate the SQLite schema"),
         )
         .arg(
             Arg::with_name("token")
@@ -49,38 +145,340 @@ async fn main() -> Result<()> {
                     _ => Ok(()),
                 }),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .takes_value(true)
+                .global(true)
+                .help("Log filter, e.g. \"debug\" or \"markov=trace,warn\" (overrides RUST_LOG)"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(logging::FORMATS)
+                .default_value("text")
+                .global(true)
+                .help("Log output format"),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print statistics of the Markov chain")
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(|top| {
+                            top.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())
+                        })
+                        .help("Number of most frequent words and states to show"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the Markov chain as JSON Lines")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("File to write to (defaults to stdout)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compile")
+                .about("Write the Markov chain to a read-only file served with --frozen-path")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("File to write to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("decay")
+                .about("Decay weights by --decay-half-life and remove forgotten transitions"),
+        )
+        .subcommand(
+            SubCommand::with_name("import-chain")
+                .about("Add transitions exported with `export` to the Markov chain")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("i")
+                        .takes_value(true)
+                        .help("File to read from (defaults to stdin)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Add transitions of other chains to the Markov chain")
+                .arg(
+                    Arg::with_name("sqlite")
+                        .long("sqlite")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATH[:SCALE]")
+                        .validator(validate_source)
+                        .help("SQLite database to merge, optionally with a weight multiplier"),
+                )
+                .arg(
+                    Arg::with_name("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATH[:SCALE]")
+                        .validator(validate_source)
+                        .help("Snapshot file to merge, optionally with a weight multiplier"),
+                )
+                .group(
+                    ArgGroup::with_name("sources")
+                        .args(&["sqlite", "snapshot"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Apply pending migrations to the SQLite database schema"),
+        )
+        .subcommand(
+            SubCommand::with_name("names")
+                .about("Generate made-up words resembling a list of example words")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("i")
+                        .takes_value(true)
+                        .help("File with whitespace separated words (defaults to stdin)"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(validate_number)
+                        .help("Number of distinct words to generate"),
+                )
+                .arg(
+                    Arg::with_name("min-length")
+                        .long("min-length")
+                        .takes_value(true)
+                        .default_value("3")
+                        .validator(validate_number)
+                        .help("Minimum number of characters"),
+                )
+                .arg(
+                    Arg::with_name("max-length")
+                        .long("max-length")
+                        .takes_value(true)
+                        .default_value("12")
+                        .validator(validate_number)
+                        .help("Maximum number of characters"),
+                )
+                .arg(
+                    Arg::with_name("order")
+                        .long("order")
+                        .takes_value(true)
+                        .possible_values(&["1", "2", "3", "4"])
+                        .default_value("3")
+                        .help("Characters of context, higher orders resemble the examples more"),
+                ),
+        );
+    #[cfg(feature = "metrics")]
+    let app = app.arg(
+        Arg::with_name("metrics-addr")
+            .long("metrics-addr")
+            .takes_value(true)
+            .help("Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100")
+            .validator(|addr| {
+                addr.parse::<std::net::SocketAddr>()
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+    );
+    #[cfg(feature = "postgres")]
+    let app = app.arg(
+        Arg::with_name("postgres-url")
+            .long("postgres-url")
+            .takes_value(true)
+            .global(true)
+            .conflicts_with_all(&["sqlite-path", "snapshot-path", "kv-path", "frozen-path"])
+            .help("PostgreSQL connection string, e.g. postgresql://user@localhost/markov"),
+    );
+    let matches = app.get_matches();
+
+    logging::init(
+        matches.value_of("log-level"),
+        matches.value_of("log-format").unwrap(),
+    )?;
 
-    let connection = matches.value_of("sqlite-path").map(Connection::open);
+    let sqlite_path = matches.value_of("sqlite-path");
 
     if matches.is_present("setup-db") {
-        return setup::<ORDER>(&connection.unwrap()?);
+        return schema::reset::<ORDER>(&Connection::open(sqlite_path.unwrap())?);
+    }
+    if let ("migrate", Some(_)) = matches.subcommand() {
+        let path = sqlite_path.context("migrate requires --sqlite-path")?;
+        return commands::migrate::run(&Connection::open(path)?);
+    }
+
+    if let ("names", Some(matches)) = matches.subcommand() {
+        let number = |arg| matches.value_of(arg).unwrap().parse::<usize>().unwrap();
+        let options = NameOptions {
+            min_length: number("min-length"),
+            max_length: number("max-length"),
+            ..NameOptions::default()
+        };
+        if options.min_length > options.max_length {
+            bail!("--min-length must not be greater than --max-length");
+        }
+        let (input, count) = (matches.value_of("input"), number("count"));
+        return match number("order") {
+            1 => commands::names::run::<1>(input, count, options),
+            2 => commands::names::run::<2>(input, count, options),
+            3 => commands::names::run::<3>(input, count, options),
+            _ => commands::names::run::<4>(input, count, options),
+        };
     }
 
-    let token = matches.value_of("token").unwrap();
-    let verbosity = matches
-        .value_of("verbosity")
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-
-    let chooser = RandChoose::new();
-    let shuffler = RandShuffle::new();
-
-    match connection {
-        Some(connection) => {
-            let repository = SqliteRepository::new(connection?);
-            let chain = Chain::new(repository, chooser);
-            let bot: Bot<_, _, _, 2> = Bot::new(chain, shuffler);
-            let discord = DiscordBot::new(token, verbosity);
-            discord.run(bot).await
+    let repository = open_repository(storage(&matches)?)?;
+
+    match matches.subcommand() {
+        ("stats", Some(matches)) => {
+            let top = matches.value_of("top").unwrap().parse().unwrap();
+            commands::stats::run(repository, top)
+        }
+        ("export", Some(matches)) => commands::export::run(&repository, matches.value_of("output")),
+        ("compile", Some(matches)) => {
+            require_persistent_storage(matches, "compile")?;
+            commands::compile::run(&repository, matches.value_of("output").unwrap())
+        }
+        ("decay", Some(matches)) => {
+            require_persistent_storage(matches, "decay")?;
+            let policy = decay_policy(matches).context("decay requires --decay-half-life")?;
+            commands::decay::run(&repository, policy)
         }
-        None => {
-            let repository = MemoryRepository::new();
-            let chain = Chain::new(repository, chooser);
-            let bot: Bot<_, _, _, 2> = Bot::new(chain, shuffler);
-            let discord = DiscordBot::new(token, verbosity);
-            discord.run(bot).await
+        ("import-chain", Some(matches)) => {
+            require_persistent_storage(matches, "import-chain")?;
+            commands::import::run(repository, matches.value_of("input"))
         }
+        ("merge", Some(matches)) => {
+            require_persistent_storage(matches, "merge")?;
+            let mut sources = Vec::new();
+            for source in matches.values_of("sqlite").into_iter().flatten() {
+                let (path, scale) = commands::merge::parse_source(source)?;
+                sources.push((Source::Sqlite(path), scale));
+            }
+            for source in matches.values_of("snapshot").into_iter().flatten() {
+                let (path, scale) = commands::merge::parse_source(source)?;
+                sources.push((Source::Snapshot(path), scale));
+            }
+            commands::merge::run(repository, sources)
+        }
+        _ => commands::run::run(repository, &matches).await,
     }
 }
+
+fn validate_number(number: String) -> Result<(), String> {
+    number
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn validate_source(source: String) -> Result<(), String> {
+    commands::merge::parse_source(&source)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn decay_policy(matches: &ArgMatches) -> Option<DecayPolicy> {
+    let hours: f64 = matches.value_of("decay-half-life")?.parse().unwrap();
+    Some(DecayPolicy {
+        half_life: Duration::from_secs_f64(hours * 3600.0),
+    })
+}
+
+fn require_persistent_storage(matches: &ArgMatches, command: &str) -> Result<()> {
+    if ![
+        "sqlite-path",
+        "snapshot-path",
+        "kv-path",
+        "frozen-path",
+        "postgres-url",
+    ]
+    .iter()
+    .any(|arg| matches.is_present(arg))
+    {
+        bail!(
+            "{} requires persistent storage, e.g. --sqlite-path",
+            command
+        );
+    }
+    Ok(())
+}
+
+enum Storage<'a> {
+    Memory,
+    Snapshot(&'a str),
+    Sqlite(&'a str, SqliteOptions),
+    Kv(&'a str),
+    Frozen(&'a str),
+    #[cfg(feature = "postgres")]
+    Postgres(&'a str),
+}
+
+fn storage<'a>(matches: &'a ArgMatches) -> Result<Storage<'a>> {
+    #[cfg(feature = "postgres")]
+    if let Some(url) = matches.value_of("postgres-url") {
+        return Ok(Storage::Postgres(url));
+    }
+    if let Some(path) = matches.value_of("sqlite-path") {
+        let options = SqliteOptions {
+            busy_timeout: Duration::from_millis(
+                matches.value_of("sqlite-busy-timeout").unwrap().parse()?,
+            ),
+            readers: matches.value_of("sqlite-readers").unwrap().parse()?,
+        };
+        return Ok(Storage::Sqlite(path, options));
+    }
+    if let Some(path) = matches.value_of("kv-path") {
+        return Ok(Storage::Kv(path));
+    }
+    if let Some(path) = matches.value_of("frozen-path") {
+        return Ok(Storage::Frozen(path));
+    }
+    Ok(match matches.value_of("snapshot-path") {
+        Some(path) => Storage::Snapshot(path),
+        None => Storage::Memory,
+    })
+}
+
+fn open_repository(storage: Storage) -> Result<DynRepository> {
+    let repository: DynRepository = match storage {
+        Storage::Memory => Box::new(MemoryRepository::new()),
+        Storage::Snapshot(path) => {
+            let snapshot = Snapshot::new(path, TOKENIZER);
+            Box::new(MemoryRepository::with_snapshot(snapshot)?)
+        }
+        Storage::Sqlite(path, options) => {
+            let repository = SqliteRepository::open::<ORDER>(path, options)?;
+            Box::new(RetryingRepository::new(repository, RetryPolicy::default()))
+        }
+        Storage::Kv(path) => Box::new(KvRepository::open::<ORDER>(path)?),
+        Storage::Frozen(path) => Box::new(FrozenRepository::open::<ORDER>(path, TOKENIZER)?),
+        #[cfg(feature = "postgres")]
+        Storage::Postgres(url) => {
+            let repository = PostgresRepository::connect::<ORDER>(url)?;
+            Box::new(RetryingRepository::new(repository, RetryPolicy::default()))
+        }
+    };
+    Ok(repository)
+}
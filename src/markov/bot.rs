@@ -1,73 +1,194 @@
-use std::iter;
+use std::time::{Instant, SystemTime};
+use std::{array, iter};
 
 use anyhow::{Context, Result};
+use tracing::{debug, instrument};
 
 use super::chain::Chain;
 use super::choose::Choose;
+use super::decay::{DecayPolicy, Decayed};
 use super::repository::Repository;
 use super::shuffle::Shuffle;
+use super::stats::Stats;
+use super::tokenizer::Tokenizer;
+use super::types::{Size, Token};
 
-static END: &str = "\0";
-
-pub struct Bot<R, C, S, const N: usize>
+/// Learns messages and generates sentences, either random ones or replies to a
+/// message. A sentence ends where a learned message ended.
+///
+/// Messages are sequences of words `T`. Text is split into words by the
+/// tokenizer when `T` is `String`, other words, e.g. notes or ids, are learned
+/// and generated as tokens.
+///
+/// ```
+/// use markov::adapters::memory::repository::MemoryRepository;
+/// use markov::adapters::rand::choose::RandChoose;
+/// use markov::adapters::rand::shuffle::RandShuffle;
+/// use markov::markov::tokenizer::Tokenizer;
+/// use markov::markov::{Bot, Chain};
+///
+/// let chain = Chain::new(MemoryRepository::new(), RandChoose::new());
+/// let bot: Bot<_, _, _, _, 1> = Bot::new(chain, RandShuffle::new(), Tokenizer::Whitespace);
+/// bot.learn("hello world")?;
+///
+/// assert_eq!(bot.reply("hello there")?, "hello world");
+/// # anyhow::Ok(())
+/// ```
+pub struct Bot<T, R, C, S, const N: usize>
 where
-    R: Repository<String, N>,
-    C: Choose<String>,
+    R: Repository<Token<T>, N>,
+    C: Choose<Token<T>>,
 {
-    chain: Chain<String, R, C, N>,
+    chain: Chain<Token<T>, R, C, N>,
     shuffler: S,
+    tokenizer: Tokenizer,
 }
 
-impl<R, C, S, const N: usize> Bot<R, C, S, N>
+impl<T, R, C, S, const N: usize> Bot<T, R, C, S, N>
 where
-    R: Repository<String, N>,
-    C: Choose<String>,
+    T: Clone,
+    R: Repository<Token<T>, N>,
+    C: Choose<Token<T>>,
 {
-    pub fn new(chain: Chain<String, R, C, N>, shuffler: S) -> Bot<R, C, S, N> {
-        Bot { chain, shuffler }
+    pub fn new(
+        chain: Chain<Token<T>, R, C, N>,
+        shuffler: S,
+        tokenizer: Tokenizer,
+    ) -> Bot<T, R, C, S, N> {
+        Bot {
+            chain,
+            shuffler,
+            tokenizer,
+        }
     }
 
-    pub fn learn(&mut self, message: &str) -> Result<()> {
-        let words = message
-            .split_whitespace()
-            .chain(iter::once(END))
-            .map(str::to_string);
-        self.chain.feed(words)?;
+    /// Learns a message given as words. Empty messages are ignored.
+    #[instrument(skip_all)]
+    pub fn learn_tokens(&self, words: impl IntoIterator<Item = T>) -> Result<()> {
+        let started = Instant::now();
+        let mut words = words.into_iter().peekable();
+        if words.peek().is_none() {
+            return Ok(());
+        }
+        let tokens = iter::repeat_n(Token::Start, N)
+            .chain(words.map(Token::Word))
+            .chain(iter::once(Token::End));
+        self.chain.feed(tokens)?;
+        debug!(elapsed = ?started.elapsed(), "Learned message");
         Ok(())
     }
 
-    fn build_sentence(&self, start: [String; N]) -> Result<String> {
+    #[instrument(skip_all)]
+    pub fn flush(&self) -> Result<()> {
+        self.chain.flush()
+    }
+
+    /// Decays weights of transitions not learned recently, see
+    /// [`Repository::decay`].
+    #[instrument(skip_all)]
+    pub fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        self.chain.decay(policy, now)
+    }
+
+    pub fn size(&self) -> Result<Size> {
+        self.chain.size()
+    }
+
+    pub fn stats(&self, top: usize) -> Result<Stats<Token<T>, N>> {
+        self.chain.stats(top)
+    }
+
+    fn build_sentence(&self, start: [Token<T>; N]) -> Result<Vec<T>> {
         start
             .clone()
             .into_iter()
             .map(Ok)
             .chain(self.chain.iter_from(start))
-            .take_while(|word| !matches!(word, Ok(word) if word == END))
-            .collect::<Result<Vec<_>>>()
-            .map(|words| words.join(" "))
+            .take_while(|token| !matches!(token, Ok(Token::End)))
+            .filter_map(|token| token.map(Token::word).transpose())
+            .collect()
     }
 
-    pub fn say(&self) -> Result<String> {
-        self.chain
-            .random()?
-            .context("Failed to build random sentence.")
-            .and_then(|start| self.build_sentence(start))
+    /// Generates a sentence beginning like one of the learned messages.
+    ///
+    /// ```
+    /// use markov::adapters::memory::repository::MemoryRepository;
+    /// use markov::adapters::rand::choose::RandChoose;
+    /// use markov::adapters::rand::shuffle::RandShuffle;
+    /// use markov::markov::tokenizer::Tokenizer;
+    /// use markov::markov::{Bot, Chain};
+    ///
+    /// let chain = Chain::new(MemoryRepository::new(), RandChoose::new());
+    /// let bot: Bot<u8, _, _, _, 2> = Bot::new(chain, RandShuffle::new(), Tokenizer::Whitespace);
+    /// bot.learn_tokens([60, 62, 64, 65, 67])?;
+    ///
+    /// assert_eq!(bot.generate()?, [60, 62, 64, 65, 67]);
+    /// # anyhow::Ok(())
+    /// ```
+    #[instrument(skip_all)]
+    pub fn generate(&self) -> Result<Vec<T>> {
+        let started = Instant::now();
+        let mut sentence = self.build_sentence(array::from_fn(|_| Token::Start))?;
+        if sentence.is_empty() {
+            // Chains learned before messages were preceded by `Start` have no
+            // such state, so they start anywhere.
+            sentence = self
+                .chain
+                .random()?
+                .context("Failed to build random sentence.")
+                .and_then(|start| self.build_sentence(start))?;
+        }
+        debug!(elapsed = ?started.elapsed(), "Generated sentence");
+        Ok(sentence)
     }
 
-    pub fn reply(&self, message: &str) -> Result<String>
+    /// Generates a sentence starting with one of `words`, or any sentence if
+    /// none of them was learned.
+    #[instrument(skip_all, fields(len = words.len()))]
+    pub fn generate_reply(&self, mut words: Vec<T>) -> Result<Vec<T>>
     where
-        S: Shuffle<String>,
+        S: Shuffle<T>,
     {
-        let mut words: Vec<_> = message.split_whitespace().map(str::to_string).collect();
+        let started = Instant::now();
         self.shuffler.shuffle(&mut words);
 
         for word in words {
-            let start = self.chain.random_starting_with(&word)?;
+            let start = self.chain.random_starting_with(&Token::Word(word))?;
             if let Some(start) = start {
-                return self.build_sentence(start);
+                let sentence = self.build_sentence(start)?;
+                debug!(elapsed = ?started.elapsed(), "Generated reply");
+                return Ok(sentence);
             }
         }
 
-        self.say()
+        debug!("No known word in message, falling back to random sentence");
+        self.generate()
+    }
+}
+
+impl<R, C, S, const N: usize> Bot<String, R, C, S, N>
+where
+    R: Repository<Token<String>, N>,
+    C: Choose<Token<String>>,
+{
+    #[instrument(skip_all, fields(len = message.len()))]
+    pub fn learn(&self, message: &str) -> Result<()> {
+        let words = self.tokenizer.tokenize(message).map(str::to_string);
+        self.learn_tokens(words)
+    }
+
+    pub fn say(&self) -> Result<String> {
+        let words = self.generate()?;
+        Ok(self.tokenizer.join(&words))
+    }
+
+    #[instrument(skip_all, fields(len = message.len()))]
+    pub fn reply(&self, message: &str) -> Result<String>
+    where
+        S: Shuffle<String>,
+    {
+        let words = self.tokenizer.tokenize(message).map(str::to_string);
+        let words = self.generate_reply(words.collect())?;
+        Ok(self.tokenizer.join(&words))
     }
 }
@@ -1,11 +1,31 @@
 use std::marker::PhantomData;
+use std::time::SystemTime;
 
 use anyhow::Result;
+use tracing::{instrument, trace};
 
 use super::choose::Choose;
+use super::decay::{DecayPolicy, Decayed};
 use super::links::LinkIterator;
 use super::repository::Repository;
+use super::stats::Stats;
+use super::types::Size;
 
+/// Markov chain of order `N` over tokens `T`, stored in a [`Repository`] and
+/// walked with a [`Choose`].
+///
+/// ```
+/// use markov::adapters::memory::repository::MemoryRepository;
+/// use markov::adapters::rand::choose::RandChoose;
+/// use markov::markov::Chain;
+///
+/// let chain: Chain<_, _, _, 2> = Chain::new(MemoryRepository::new(), RandChoose::new());
+/// chain.feed([1, 2, 3, 4])?;
+///
+/// let generated = chain.iter_from([1, 2]).collect::<anyhow::Result<Vec<_>>>()?;
+/// assert_eq!(generated, [3, 4]);
+/// # anyhow::Ok(())
+/// ```
 pub struct Chain<T, R, C, const N: usize>
 where
     R: Repository<T, N>,
@@ -25,22 +45,34 @@ where
         Chain {
             repository,
             chooser,
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
         }
     }
 
-    pub fn feed<I>(&mut self, iter: I) -> Result<()>
+    #[instrument(level = "trace", skip_all)]
+    pub fn feed<I>(&self, iter: I) -> Result<()>
     where
         T: Clone,
         I: IntoIterator<Item = T>,
     {
+        let mut links = 0;
         for link in iter.into_iter().links() {
             self.repository.increment_weight(link)?;
+            links += 1;
         }
+        trace!(links, "Fed chain");
         Ok(())
     }
 
-    pub fn iter_from(&self, start: [T; N]) -> ChainIterator<T, N> {
+    pub fn flush(&self) -> Result<()> {
+        self.repository.flush()
+    }
+
+    pub fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        self.repository.decay(policy, now)
+    }
+
+    pub fn iter_from(&self, start: [T; N]) -> ChainIterator<'_, T, N> {
         ChainIterator {
             repository: &self.repository,
             chooser: &self.chooser,
@@ -48,6 +80,14 @@ where
         }
     }
 
+    pub fn size(&self) -> Result<Size> {
+        self.repository.size()
+    }
+
+    pub fn stats(&self, top: usize) -> Result<Stats<T, N>> {
+        self.repository.stats(top)
+    }
+
     pub fn random(&self) -> Result<Option<[T; N]>> {
         self.repository.random()
     }
@@ -70,16 +110,14 @@ where
     type Item = Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let weights = self.repository.get(&self.previous);
-        match weights {
-            Ok(weights) if !weights.is_empty() => {
-                let state = self.chooser.choose(weights);
+        match self.repository.sample(&self.previous, self.chooser) {
+            Ok(Some(state)) => {
                 self.previous.rotate_left(1);
                 self.previous[N - 1] = state.clone();
                 Some(Ok(state))
             }
+            Ok(None) => None,
             Err(e) => Some(Err(e)),
-            _ => None,
         }
     }
 }
@@ -1,5 +1,23 @@
 use crate::markov::types::WeightMap;
 
+/// Picks the next token of a chain with probability proportional to its
+/// weight. Implementors only provide the source of randomness.
+///
+/// ```
+/// use markov::markov::types::WeightMap;
+/// use markov::markov::Choose;
+///
+/// // Always picks the first token in iteration order.
+/// struct First;
+///
+/// impl<T> Choose<T> for First {
+///     fn generate_random(&self, _upper_bound: u32) -> u32 {
+///         0
+///     }
+/// }
+///
+/// assert_eq!(First.choose(WeightMap::from([("a", 3)])), "a");
+/// ```
 pub trait Choose<T> {
     fn choose(&self, map: WeightMap<T>) -> T {
         let sum = map.values().sum();
@@ -0,0 +1,612 @@
+//! Tests every [`Repository`](super::repository::Repository) backend has to
+//! pass, so they are interchangeable.
+
+/// Generates a `conformance` module of tests for a backend. `$repository`
+/// names a function generic over the order `N`, returning an empty
+/// `Repository<String, N>`. `$requirement` optionally names a function
+/// returning something each test holds while it runs, e.g. a database server,
+/// or `None` to skip the test.
+macro_rules! conformance_tests {
+    ($repository:ident $(, $requirement:ident)?) => {
+        mod conformance {
+            use std::cell::Cell;
+            use std::collections::{HashMap, HashSet};
+            use std::thread;
+
+            use $crate::markov::choose::Choose;
+            use $crate::markov::repository::Repository;
+            use $crate::markov::types::{Link, Size, WeightMap};
+
+            fn repository<const N: usize>() -> impl Repository<String, N> + Sync {
+                super::$repository::<N>()
+            }
+
+            fn requirement() -> Option<impl Sized> {
+                $crate::markov::conformance::requirement!($(super::$requirement)?)
+            }
+
+            fn state<const N: usize>(words: [&str; N]) -> [String; N] {
+                words.map(str::to_string)
+            }
+
+            fn link<const N: usize>(from: [&str; N], to: &str) -> Link<String, N> {
+                Link::new(state(from), to.to_string())
+            }
+
+            fn weights(weights: &[(&str, u32)]) -> WeightMap<String> {
+                weights
+                    .iter()
+                    .map(|&(word, weight)| (word.to_string(), weight))
+                    .collect()
+            }
+
+            fn transitions<const N: usize>(
+                repository: &impl Repository<String, N>,
+            ) -> HashSet<([String; N], String, u32)> {
+                let mut transitions = HashSet::new();
+                repository
+                    .for_each_transition(&mut |link, weight| {
+                        assert!(transitions.insert((link.from, link.to, weight)));
+                        Ok(())
+                    })
+                    .unwrap();
+                transitions
+            }
+
+            // Draws `samples` states, so that each of a few states is almost
+            // certainly drawn at least once.
+            fn draw<const N: usize>(
+                samples: usize,
+                mut random: impl FnMut() -> Option<[String; N]>,
+            ) -> HashMap<[String; N], usize> {
+                let mut drawn = HashMap::new();
+                for _ in 0..samples {
+                    let state = random().expect("a state should always be found");
+                    *drawn.entry(state).or_default() += 1;
+                }
+                drawn
+            }
+
+            #[test]
+            fn get_returns_empty_if_missing() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["b", "a"])).unwrap(),
+                    WeightMap::new()
+                );
+                assert_eq!(
+                    repository.get(&state(["x", "y"])).unwrap(),
+                    WeightMap::new()
+                );
+            }
+
+            #[test]
+            fn increment_weight_sets_weight_to_1_if_missing() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["a", "b"])).unwrap(),
+                    weights(&[("c", 1)])
+                );
+            }
+
+            #[test]
+            fn weights_accumulate() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.add_weight(link(["a", "b"], "c"), 5).unwrap();
+                repository.increment_weight(link(["a", "b"], "d")).unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["a", "b"])).unwrap(),
+                    weights(&[("c", 7), ("d", 1)])
+                );
+            }
+
+            #[test]
+            fn states_are_independent() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "bc"], "d")).unwrap();
+                repository.add_weight(link(["ab", "c"], "d"), 2).unwrap();
+                repository.add_weight(link(["a", "a"], "a"), 3).unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["a", "bc"])).unwrap(),
+                    weights(&[("d", 1)])
+                );
+                assert_eq!(
+                    repository.get(&state(["ab", "c"])).unwrap(),
+                    weights(&[("d", 2)])
+                );
+                assert_eq!(
+                    repository.get(&state(["a", "a"])).unwrap(),
+                    weights(&[("a", 3)])
+                );
+            }
+
+            #[test]
+            fn add_weights_stores_batch() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weights(&[]).unwrap();
+                repository
+                    .add_weights(&[
+                        (link(["a", "b"], "c"), 2),
+                        (link(["b", "c"], "d"), 3),
+                        (link(["a", "b"], "c"), 4),
+                    ])
+                    .unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["a", "b"])).unwrap(),
+                    weights(&[("c", 6)])
+                );
+                assert_eq!(
+                    repository.get(&state(["b", "c"])).unwrap(),
+                    weights(&[("d", 3)])
+                );
+            }
+
+            #[test]
+            fn add_all_weights_stores_nothing_on_error() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 1).unwrap();
+                let before = transitions(&repository);
+                let mut links = vec![
+                    Ok((link(["a", "b"], "c"), 2)),
+                    Ok((link(["b", "c"], "d"), 3)),
+                    Err(anyhow::anyhow!("invalid link")),
+                ]
+                .into_iter();
+
+                assert!(repository.add_all_weights(&mut links).is_err());
+                assert_eq!(transitions(&repository), before);
+                assert_eq!(repository.size().unwrap().transitions, 1);
+
+                let mut links = vec![Ok((link(["b", "c"], "d"), 3))].into_iter();
+                repository.add_all_weights(&mut links).unwrap();
+                assert_eq!(
+                    repository.get(&state(["b", "c"])).unwrap(),
+                    weights(&[("d", 3)])
+                );
+            }
+
+            // Reproducibly draws numbers spread evenly over the whole range,
+            // by multiplying a counter with the golden ratio.
+            #[derive(Default)]
+            struct Sequence(Cell<u32>);
+
+            impl<T> Choose<T> for Sequence {
+                fn generate_random(&self, upper_bound: u32) -> u32 {
+                    let i = self.0.get();
+                    self.0.set(i + 1);
+                    i.wrapping_mul(0x9e37_79b9) % upper_bound
+                }
+            }
+
+            // Successors aren't returned in a fixed order by every backend, so
+            // only frequencies are checked, with enough samples for the checks
+            // to almost certainly hold.
+            fn sample<const N: usize>(
+                repository: &impl Repository<String, N>,
+                from: [&str; N],
+                samples: usize,
+            ) -> HashMap<String, usize> {
+                let chooser = Sequence::default();
+                let mut sampled = HashMap::new();
+                for _ in 0..samples {
+                    let to = repository.sample(&state(from), &chooser).unwrap();
+                    *sampled.entry(to.expect("a successor should be found")).or_default() += 1;
+                }
+                sampled
+            }
+
+            #[test]
+            fn sample_picks_successors_by_weight() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 3).unwrap();
+                repository.add_weight(link(["a", "b"], "d"), 1).unwrap();
+
+                let sampled = sample(&repository, ["a", "b"], 200);
+
+                assert_eq!(sampled.len(), 2);
+                assert!(sampled["c"] > sampled["d"]);
+                assert_eq!(
+                    repository
+                        .sample(&state(["b", "c"]), &Sequence::default())
+                        .unwrap(),
+                    None
+                );
+            }
+
+            #[test]
+            fn sample_follows_learning_of_high_branching_states() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<1>();
+                let links: Vec<_> = (0..100)
+                    .map(|i| (link(["the"], &format!("w{}", i)), 1))
+                    .collect();
+                repository.add_weights(&links).unwrap();
+                let successors = repository.get(&state(["the"])).unwrap();
+                let sampled = sample(&repository, ["the"], 50);
+                assert!(sampled.keys().all(|word| successors.contains_key(word)));
+
+                repository.add_weight(link(["the"], "new"), 10_000).unwrap();
+                let sampled = sample(&repository, ["the"], 20);
+                assert!(sampled["new"] > 10);
+            }
+
+            #[test]
+            fn random_of_empty_repository() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+
+                assert_eq!(repository.random().unwrap(), None);
+                assert_eq!(
+                    repository.random_starting_with(&"a".to_string()).unwrap(),
+                    None
+                );
+            }
+
+            #[test]
+            fn random_draws_every_stored_state() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.increment_weight(link(["b", "c"], "d")).unwrap();
+                repository.increment_weight(link(["c", "d"], "a")).unwrap();
+
+                let drawn = draw(200, || repository.random().unwrap());
+
+                let drawn: HashSet<_> = drawn.into_keys().collect();
+                assert_eq!(
+                    drawn,
+                    HashSet::from([state(["a", "b"]), state(["b", "c"]), state(["c", "d"])])
+                );
+            }
+
+            #[test]
+            fn random_starting_with_draws_only_matching_states() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.increment_weight(link(["b", "c"], "d")).unwrap();
+                repository.increment_weight(link(["b", "d"], "a")).unwrap();
+                repository.increment_weight(link(["b", "b"], "b")).unwrap();
+                repository.increment_weight(link(["c", "b"], "e")).unwrap();
+
+                let word = "b".to_string();
+                let drawn = draw(200, || repository.random_starting_with(&word).unwrap());
+
+                let drawn: HashSet<_> = drawn.into_keys().collect();
+                assert_eq!(
+                    drawn,
+                    HashSet::from([state(["b", "c"]), state(["b", "d"]), state(["b", "b"])])
+                );
+            }
+
+            #[test]
+            fn random_starting_with_ignores_words_not_starting_states() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+
+                for word in ["b", "c", "x", ""] {
+                    assert_eq!(
+                        repository.random_starting_with(&word.to_string()).unwrap(),
+                        None,
+                        "{:?} doesn't start any state",
+                        word
+                    );
+                }
+            }
+
+            #[test]
+            fn size_counts_distinct_words_states_and_transitions() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                assert_eq!(repository.size().unwrap(), Size::default());
+
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.increment_weight(link(["a", "b"], "c")).unwrap();
+                repository.increment_weight(link(["a", "b"], "d")).unwrap();
+                repository.increment_weight(link(["b", "c"], "a")).unwrap();
+
+                assert_eq!(
+                    repository.size().unwrap(),
+                    Size {
+                        words: 4,
+                        states: 2,
+                        transitions: 3
+                    }
+                );
+            }
+
+            #[test]
+            fn stats_summarize_chain() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<1>();
+                for (from, to) in [("a", "b"), ("a", "c"), ("a", "b"), ("b", "c"), ("a", "b")] {
+                    repository.increment_weight(link([from], to)).unwrap();
+                }
+
+                let stats = repository.stats(1).unwrap();
+
+                assert_eq!(stats.total_weight, 5);
+                assert_eq!(stats.top_words, vec![("b".to_string(), 3)]);
+                assert_eq!(stats.top_states.len(), 1);
+                assert_eq!(stats.top_states[0].state, state(["a"]));
+                assert_eq!(stats.top_states[0].weight, 4);
+                assert!((stats.top_states[0].entropy - 0.811).abs() < 1e-3);
+                assert!((stats.mean_entropy - 4.0 * 0.811 / 5.0).abs() < 1e-3);
+            }
+
+            #[test]
+            fn for_each_transition_visits_every_transition_once() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                assert!(transitions(&repository).is_empty());
+
+                repository.add_weight(link(["a", "b"], "c"), 2).unwrap();
+                repository.add_weight(link(["a", "b"], "d"), 1).unwrap();
+                repository.add_weight(link(["b", "c"], "a"), 3).unwrap();
+
+                assert_eq!(
+                    transitions(&repository),
+                    HashSet::from([
+                        (state(["a", "b"]), "c".to_string(), 2),
+                        (state(["a", "b"]), "d".to_string(), 1),
+                        (state(["b", "c"]), "a".to_string(), 3),
+                    ])
+                );
+            }
+
+            #[test]
+            fn concurrent_writes_are_not_lost() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<1>();
+                thread::scope(|scope| {
+                    for i in 0..4 {
+                        let repository = &repository;
+                        scope.spawn(move || {
+                            let to = format!("b{}", i % 2);
+                            for _ in 0..25 {
+                                repository.increment_weight(link(["a"], &to)).unwrap();
+                                repository.get(&state(["a"])).unwrap();
+                            }
+                        });
+                    }
+                });
+
+                assert_eq!(
+                    repository.get(&state(["a"])).unwrap(),
+                    weights(&[("b0", 50), ("b1", 50)])
+                );
+            }
+
+            #[test]
+            fn unicode_and_unusual_words() {
+                let Some(_requirement) = requirement() else { return };
+                let words = ["zażółć", "日本語", "🦀🦀", "\0", "", " ", "a b", "'\"\\%_"];
+                let repository = repository::<2>();
+                for pair in words.windows(3) {
+                    repository
+                        .increment_weight(link([pair[0], pair[1]], pair[2]))
+                        .unwrap();
+                }
+
+                for pair in words.windows(3) {
+                    assert_eq!(
+                        repository.get(&state([pair[0], pair[1]])).unwrap(),
+                        weights(&[(pair[2], 1)]),
+                        "successors of {:?}",
+                        &pair[..2]
+                    );
+                    assert_eq!(
+                        repository.random_starting_with(&pair[0].to_string()).unwrap(),
+                        Some(state([pair[0], pair[1]]))
+                    );
+                }
+                assert_eq!(repository.size().unwrap().words, words.len() as u64);
+            }
+
+            #[test]
+            fn order_one() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<1>();
+                repository.increment_weight(link(["a"], "b")).unwrap();
+                repository.increment_weight(link(["a"], "a")).unwrap();
+                repository.add_weight(link(["b"], "a"), 2).unwrap();
+
+                assert_eq!(
+                    repository.get(&state(["a"])).unwrap(),
+                    weights(&[("a", 1), ("b", 1)])
+                );
+                assert_eq!(repository.get(&state(["b"])).unwrap(), weights(&[("a", 2)]));
+                assert_eq!(
+                    repository.random_starting_with(&"b".to_string()).unwrap(),
+                    Some(state(["b"]))
+                );
+                let drawn: HashSet<_> = draw(100, || repository.random().unwrap())
+                    .into_keys()
+                    .collect();
+                assert_eq!(drawn, HashSet::from([state(["a"]), state(["b"])]));
+                assert_eq!(
+                    repository.size().unwrap(),
+                    Size {
+                        words: 2,
+                        states: 2,
+                        transitions: 3
+                    }
+                );
+            }
+
+            #[test]
+            fn large_order() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<6>();
+                let words = ["a", "b", "c", "d", "e", "f", "g", "a", "b"];
+                for window in words.windows(7) {
+                    let from = [window[0], window[1], window[2], window[3], window[4], window[5]];
+                    repository.increment_weight(link(from, window[6])).unwrap();
+                }
+
+                assert_eq!(
+                    repository
+                        .get(&state(["a", "b", "c", "d", "e", "f"]))
+                        .unwrap(),
+                    weights(&[("g", 1)])
+                );
+                assert_eq!(
+                    repository
+                        .get(&state(["c", "d", "e", "f", "g", "a"]))
+                        .unwrap(),
+                    weights(&[("b", 1)])
+                );
+                assert_eq!(
+                    repository.random_starting_with(&"b".to_string()).unwrap(),
+                    Some(state(["b", "c", "d", "e", "f", "g"]))
+                );
+                assert_eq!(transitions(&repository).len(), 3);
+                assert_eq!(repository.size().unwrap().states, 3);
+            }
+        }
+    };
+}
+
+pub(crate) use conformance_tests;
+
+/// Calls the requirement of generated tests, if any.
+macro_rules! requirement {
+    () => {
+        Some(())
+    };
+    ($requirement:path) => {
+        $requirement()
+    };
+}
+
+pub(crate) use requirement;
+
+/// Generates a `decay` module of tests for a backend supporting
+/// [`Repository::decay`](super::repository::Repository::decay), taking the
+/// same arguments as [`conformance_tests`].
+macro_rules! decay_tests {
+    ($repository:ident $(, $requirement:ident)?) => {
+        mod decay {
+            use std::time::{Duration, SystemTime};
+
+            use $crate::markov::decay::{DecayPolicy, Decayed};
+            use $crate::markov::repository::Repository;
+            use $crate::markov::types::{Link, Size, WeightMap};
+
+            const HOUR: Duration = Duration::from_secs(60 * 60);
+            const POLICY: DecayPolicy = DecayPolicy { half_life: HOUR };
+
+            fn repository<const N: usize>() -> impl Repository<String, N> {
+                super::$repository::<N>()
+            }
+
+            fn requirement() -> Option<impl Sized> {
+                $crate::markov::conformance::requirement!($(super::$requirement)?)
+            }
+
+            fn link(from: [&str; 2], to: &str) -> Link<String, 2> {
+                Link::new(from.map(str::to_string), to.to_string())
+            }
+
+            fn get(repository: &impl Repository<String, 2>, from: [&str; 2]) -> WeightMap<String> {
+                repository.get(&from.map(str::to_string)).unwrap()
+            }
+
+            #[test]
+            fn halves_weights_after_half_life() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 100).unwrap();
+                repository.add_weight(link(["a", "b"], "d"), 8).unwrap();
+
+                let decayed = repository.decay(&POLICY, SystemTime::now() + HOUR).unwrap();
+
+                assert_eq!(decayed, Decayed { decayed: 2, removed: 0 });
+                assert_eq!(
+                    get(&repository, ["a", "b"]),
+                    WeightMap::from([("c".to_string(), 50), ("d".to_string(), 4)])
+                );
+            }
+
+            #[test]
+            fn keeps_recent_weights() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 100).unwrap();
+
+                // Weights decay only once unchanged for a half-life.
+                let decayed = repository.decay(&POLICY, SystemTime::now() + HOUR / 2).unwrap();
+
+                assert_eq!(decayed, Decayed::default());
+                assert_eq!(
+                    get(&repository, ["a", "b"]),
+                    WeightMap::from([("c".to_string(), 100)])
+                );
+            }
+
+            #[test]
+            fn removes_forgotten_transitions() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 1).unwrap();
+                repository.add_weight(link(["a", "b"], "d"), 100).unwrap();
+                repository.add_weight(link(["b", "c"], "d"), 1).unwrap();
+
+                let decayed = repository
+                    .decay(&POLICY, SystemTime::now() + 4 * HOUR)
+                    .unwrap();
+
+                assert_eq!(decayed, Decayed { decayed: 3, removed: 2 });
+                assert_eq!(
+                    get(&repository, ["a", "b"]),
+                    WeightMap::from([("d".to_string(), 6)])
+                );
+                assert_eq!(get(&repository, ["b", "c"]), WeightMap::new());
+                assert_eq!(
+                    repository.size().unwrap(),
+                    Size {
+                        words: 3,
+                        states: 1,
+                        transitions: 1
+                    }
+                );
+                assert_eq!(repository.random_starting_with(&"b".to_string()).unwrap(), None);
+            }
+
+            #[test]
+            fn decaying_twice_at_the_same_time_changes_nothing() {
+                let Some(_requirement) = requirement() else { return };
+                let repository = repository::<2>();
+                repository.add_weight(link(["a", "b"], "c"), 3).unwrap();
+                repository.add_weight(link(["a", "b"], "d"), 5).unwrap();
+                let now = SystemTime::now() + 3 * HOUR / 2;
+
+                assert_eq!(repository.decay(&POLICY, now).unwrap().decayed, 2);
+                assert_eq!(repository.decay(&POLICY, now).unwrap(), Decayed::default());
+                assert_eq!(
+                    get(&repository, ["a", "b"]),
+                    WeightMap::from([("c".to_string(), 1), ("d".to_string(), 2)])
+                );
+            }
+        }
+    };
+}
+
+pub(crate) use decay_tests;
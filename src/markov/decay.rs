@@ -0,0 +1,109 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential decay of weights by how long ago a transition was last
+/// learned, so that recent conversations influence generated messages more
+/// than old ones.
+///
+/// Weights are whole numbers, so decayed weights are rounded. They decay only
+/// once their transition wasn't changed for a half-life, which lets backends
+/// skip recently changed ones. A transition learned once is forgotten after
+/// about one half-life, unless it's learned again, which makes its whole
+/// weight count as recent.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use markov::markov::decay::DecayPolicy;
+///
+/// let day = Duration::from_secs(24 * 60 * 60);
+/// let policy = DecayPolicy { half_life: day };
+///
+/// assert_eq!(policy.decay(100, day).0, 50);
+/// assert_eq!(policy.decay(100, 2 * day).0, 25);
+/// assert_eq!(policy.decay(1, 2 * day).0, 0);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct DecayPolicy {
+    /// Time after which a weight is halved.
+    pub half_life: Duration,
+}
+
+impl DecayPolicy {
+    /// Decays `weight` last changed `elapsed` ago. Returns the decayed weight
+    /// and how much of `elapsed` it accounts for, i.e. after how long the
+    /// exact decayed weight equals the rounded one. Backends move the time of
+    /// the last change forward by it, so that rounding errors don't add up
+    /// over repeated decays.
+    pub fn decay(&self, weight: u32, elapsed: Duration) -> (u32, Duration) {
+        if elapsed < self.half_life {
+            return (weight, Duration::ZERO);
+        }
+        let half_lives = elapsed.as_secs_f64() / self.half_life.as_secs_f64();
+        let decayed = (f64::from(weight) * 0.5f64.powf(half_lives)).round() as u32;
+        if decayed == weight {
+            (weight, Duration::ZERO)
+        } else if decayed == 0 {
+            (0, elapsed)
+        } else {
+            let half_lives = (f64::from(weight) / f64::from(decayed)).log2();
+            (decayed, self.half_life.mul_f64(half_lives))
+        }
+    }
+}
+
+/// Outcome of decaying weights of a repository.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Decayed {
+    /// Transitions whose weight was lowered, including the removed ones.
+    pub decayed: u64,
+    /// Transitions removed, because their weight decayed to zero.
+    pub removed: u64,
+}
+
+/// Seconds since the Unix epoch, as timestamps are stored by backends.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DecayPolicy;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn recent_weights_are_kept() {
+        let policy = DecayPolicy { half_life: HOUR };
+
+        assert_eq!(policy.decay(10, Duration::ZERO), (10, Duration::ZERO));
+        assert_eq!(policy.decay(10, HOUR / 60), (10, Duration::ZERO));
+        // 1000 would decay to 999 after a minute, but waits for a half-life.
+        assert_eq!(policy.decay(1000, HOUR / 2), (1000, Duration::ZERO));
+    }
+
+    #[test]
+    fn rounding_is_carried_over() {
+        let policy = DecayPolicy { half_life: HOUR };
+
+        // 5 decays to 2.5 after an hour, rounded to 3, which it exactly
+        // reaches after 44 minutes.
+        let (weight, consumed) = policy.decay(5, HOUR);
+        assert_eq!(weight, 3);
+        assert_eq!(consumed.as_secs(), 2653);
+        // Decaying the rest of the time gives the same weight as decaying all
+        // of it at once.
+        let (weight, _) = policy.decay(weight, 3 * HOUR - consumed);
+        assert_eq!(weight, policy.decay(5, 3 * HOUR).0);
+    }
+
+    #[test]
+    fn forgotten_weights_decay_to_zero() {
+        let policy = DecayPolicy { half_life: HOUR };
+
+        assert_eq!(policy.decay(1, 2 * HOUR), (0, 2 * HOUR));
+        assert_eq!(policy.decay(u32::MAX, 64 * HOUR).0, 0);
+    }
+}
@@ -18,7 +18,7 @@ pub struct Links<T, I, const N: usize> {
     from: ArrayVec<T, N>,
 }
 
-impl<'a, T, I, const N: usize> Links<T, I, N>
+impl<T, I, const N: usize> Links<T, I, N>
 where
     I: Iterator<Item = T>,
 {
@@ -73,7 +73,7 @@ mod tests {
 
     #[test]
     fn iterator() {
-        let mut iter: Links<_, _, 3> = (0..6).into_iter().links();
+        let mut iter: Links<_, _, 3> = (0..6).links();
 
         assert_eq!(iter.next(), Some(Link::new([0, 1, 2], 3)));
         assert_eq!(iter.next(), Some(Link::new([1, 2, 3], 4)));
@@ -90,14 +90,14 @@ mod tests {
 
     #[test]
     fn zero_length_window() {
-        let mut iter: Links<_, _, 0> = (0..5).into_iter().links();
+        let mut iter: Links<_, _, 0> = (0..5).links();
 
         assert!(iter.next().is_none());
     }
 
     #[test]
     fn window_longer_than_iterator() {
-        let mut iter: Links<_, _, 10> = (0..5).into_iter().links();
+        let mut iter: Links<_, _, 10> = (0..5).links();
 
         assert!(iter.next().is_none());
     }
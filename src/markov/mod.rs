@@ -1,7 +1,19 @@
 pub mod bot;
 pub mod chain;
 pub mod choose;
+#[cfg(test)]
+pub(crate) mod conformance;
+pub mod decay;
 mod links;
+pub mod names;
 pub mod repository;
 pub mod shuffle;
+pub mod stats;
+pub mod tokenizer;
 pub mod types;
+
+pub use bot::Bot;
+pub use chain::Chain;
+pub use choose::Choose;
+pub use repository::Repository;
+pub use shuffle::Shuffle;
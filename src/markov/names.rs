@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+use std::sync::{PoisonError, RwLock};
+use std::{array, iter};
+
+use anyhow::Result;
+use tracing::{debug, instrument};
+
+use super::chain::Chain;
+use super::choose::Choose;
+use super::repository::Repository;
+use super::types::Token;
+
+#[derive(Clone, Copy, Debug)]
+pub struct NameOptions {
+    /// Shortest accepted name, in characters.
+    pub min_length: usize,
+    /// Longest accepted name, in characters.
+    pub max_length: usize,
+    /// How many names are generated before giving up on finding one that is
+    /// long enough and not learned.
+    pub attempts: usize,
+}
+
+impl Default for NameOptions {
+    fn default() -> NameOptions {
+        NameOptions {
+            min_length: 3,
+            max_length: 12,
+            attempts: 100,
+        }
+    }
+}
+
+/// Generates made-up words, e.g. fantasy names or nicknames, one character at
+/// a time from a chain of order `N` learned from example words. Generated
+/// names that are one of the examples are rejected.
+///
+/// ```
+/// use markov::adapters::memory::repository::MemoryRepository;
+/// use markov::adapters::rand::choose::RandChoose;
+/// use markov::markov::names::{NameGenerator, NameOptions};
+/// use markov::markov::Chain;
+///
+/// let chain = Chain::new(MemoryRepository::new(), RandChoose::new());
+/// let options = NameOptions {
+///     min_length: 4,
+///     ..NameOptions::default()
+/// };
+/// let names: NameGenerator<_, _, 2> = NameGenerator::new(chain, options);
+/// names.learn("Arwen")?;
+/// // The only name this chain can generate is the learned one.
+/// assert_eq!(names.generate()?, None);
+///
+/// names.learn("Erwin")?;
+/// for _ in 0..10 {
+///     if let Some(name) = names.generate()? {
+///         assert!(["Arwin", "Erwen"].contains(&name.as_str()));
+///     }
+/// }
+/// # anyhow::Ok(())
+/// ```
+pub struct NameGenerator<R, C, const N: usize>
+where
+    R: Repository<Token<char>, N>,
+    C: Choose<Token<char>>,
+{
+    chain: Chain<Token<char>, R, C, N>,
+    options: NameOptions,
+    // Lowercase, so that names differing only in case are rejected too.
+    learned: RwLock<HashSet<String>>,
+}
+
+impl<R, C, const N: usize> NameGenerator<R, C, N>
+where
+    R: Repository<Token<char>, N>,
+    C: Choose<Token<char>>,
+{
+    pub fn new(chain: Chain<Token<char>, R, C, N>, options: NameOptions) -> NameGenerator<R, C, N> {
+        NameGenerator {
+            chain,
+            options,
+            learned: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Learns an example name. Surrounding whitespace is ignored.
+    pub fn learn(&self, name: &str) -> Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Ok(());
+        }
+        let tokens = iter::repeat_n(Token::Start, N)
+            .chain(name.chars().map(Token::Word))
+            .chain(iter::once(Token::End));
+        self.chain.feed(tokens)?;
+        self.learned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_lowercase());
+        Ok(())
+    }
+
+    /// Generates a name of accepted length which wasn't learned, or `None` if
+    /// none was found within the configured number of attempts.
+    #[instrument(skip_all)]
+    pub fn generate(&self) -> Result<Option<String>> {
+        for attempt in 1..=self.options.attempts {
+            if let Some(name) = self.generate_once()? {
+                let learned = self.learned.read().unwrap_or_else(PoisonError::into_inner);
+                if !learned.contains(&name.to_lowercase()) {
+                    debug!(attempt, "Generated name");
+                    return Ok(Some(name));
+                }
+            }
+        }
+        debug!(attempts = self.options.attempts, "Failed to generate name");
+        Ok(None)
+    }
+
+    // Stops as soon as the name is too long, so that runaway names don't take
+    // long to reject.
+    fn generate_once(&self) -> Result<Option<String>> {
+        let mut name = String::new();
+        let mut length = 0;
+        for token in self.chain.iter_from(array::from_fn(|_| Token::Start)) {
+            match token? {
+                Token::Word(c) => {
+                    length += 1;
+                    if length > self.options.max_length {
+                        return Ok(None);
+                    }
+                    name.push(c);
+                }
+                Token::End => break,
+                Token::Start => {}
+            }
+        }
+        Ok(Some(name).filter(|_| length >= self.options.min_length.max(1)))
+    }
+}
@@ -1,10 +1,152 @@
-use anyhow::Result;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::SystemTime;
 
-use super::types::{Link, WeightMap};
+use anyhow::{bail, Result};
 
+use super::choose::Choose;
+use super::decay::{DecayPolicy, Decayed};
+use super::stats::Stats;
+use super::types::{Link, Size, WeightMap};
+
+/// Storage of a chain. Methods take `&self`, so that a repository can be
+/// shared by threads generating and learning at the same time; backends
+/// synchronize access internally.
+///
+/// ```
+/// use markov::adapters::memory::repository::MemoryRepository;
+/// use markov::markov::types::{Link, WeightMap};
+/// use markov::markov::Repository;
+///
+/// let repository = MemoryRepository::new();
+/// repository.increment_weight(Link::new(["a"], "b"))?;
+/// repository.add_weight(Link::new(["a"], "b"), 2)?;
+///
+/// assert_eq!(repository.get(&["a"])?, WeightMap::from([("b", 3)]));
+/// assert_eq!(repository.random_starting_with(&"a")?, Some(["a"]));
+/// # anyhow::Ok(())
+/// ```
 pub trait Repository<T, const N: usize> {
     fn get(&self, from: &[T; N]) -> Result<WeightMap<T>>;
     fn random(&self) -> Result<Option<[T; N]>>;
     fn random_starting_with(&self, state: &T) -> Result<Option<[T; N]>>;
-    fn increment_weight(&mut self, link: Link<T, N>) -> Result<()>;
+    fn add_weight(&self, link: Link<T, N>, weight: u32) -> Result<()>;
+    fn size(&self) -> Result<Size>;
+    fn stats(&self, top: usize) -> Result<Stats<T, N>>;
+
+    /// Calls `f` with every transition and its weight, in no particular order.
+    fn for_each_transition(&self, f: &mut dyn FnMut(Link<T, N>, u32) -> Result<()>) -> Result<()>;
+
+    /// Picks a successor of `from` with probability proportional to its
+    /// weight, or returns `None` if `from` has no successors. Backends may
+    /// override it to pick one without copying every successor like `get`.
+    fn sample(&self, from: &[T; N], chooser: &dyn Choose<T>) -> Result<Option<T>> {
+        let weights = self.get(from)?;
+        Ok((!weights.is_empty()).then(|| chooser.choose(weights)))
+    }
+
+    fn increment_weight(&self, link: Link<T, N>) -> Result<()> {
+        self.add_weight(link, 1)
+    }
+
+    /// Adds weights of many links at once, e.g. in a single transaction. The
+    /// batch is borrowed, so that callers may reuse it, e.g. to retry.
+    fn add_weights(&self, links: &[(Link<T, N>, u32)]) -> Result<()>;
+
+    /// Adds weights of every link yielded by `links`, all or nothing: if
+    /// `links` yields an error or storing fails, nothing is stored. Backends
+    /// may override it to store links as they're yielded instead of
+    /// collecting them first.
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<T, N>, u32)>>,
+    ) -> Result<()> {
+        let links = links.collect::<Result<Vec<_>>>()?;
+        self.add_weights(&links)
+    }
+
+    /// Decays weights by how long ago their transitions were last learned,
+    /// as of `now`, and removes transitions whose weight decays to zero. Only
+    /// backends keeping track of when transitions were learned support it.
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        let _ = (policy, now);
+        bail!("Weight decay is not supported by this storage")
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Error of writes to a repository that can only be read, e.g. a chain
+/// compiled for deployment.
+#[derive(Debug)]
+pub struct ReadOnly;
+
+impl Display for ReadOnly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Repository is read-only")
+    }
+}
+
+impl Error for ReadOnly {}
+
+impl<T, R, const N: usize> Repository<T, N> for Box<R>
+where
+    R: Repository<T, N> + ?Sized,
+{
+    fn get(&self, from: &[T; N]) -> Result<WeightMap<T>> {
+        (**self).get(from)
+    }
+
+    fn random(&self) -> Result<Option<[T; N]>> {
+        (**self).random()
+    }
+
+    fn random_starting_with(&self, state: &T) -> Result<Option<[T; N]>> {
+        (**self).random_starting_with(state)
+    }
+
+    fn add_weight(&self, link: Link<T, N>, weight: u32) -> Result<()> {
+        (**self).add_weight(link, weight)
+    }
+
+    fn size(&self) -> Result<Size> {
+        (**self).size()
+    }
+
+    fn stats(&self, top: usize) -> Result<Stats<T, N>> {
+        (**self).stats(top)
+    }
+
+    fn for_each_transition(&self, f: &mut dyn FnMut(Link<T, N>, u32) -> Result<()>) -> Result<()> {
+        (**self).for_each_transition(f)
+    }
+
+    fn sample(&self, from: &[T; N], chooser: &dyn Choose<T>) -> Result<Option<T>> {
+        (**self).sample(from, chooser)
+    }
+
+    fn increment_weight(&self, link: Link<T, N>) -> Result<()> {
+        (**self).increment_weight(link)
+    }
+
+    fn add_weights(&self, links: &[(Link<T, N>, u32)]) -> Result<()> {
+        (**self).add_weights(links)
+    }
+
+    fn add_all_weights(
+        &self,
+        links: &mut dyn Iterator<Item = Result<(Link<T, N>, u32)>>,
+    ) -> Result<()> {
+        (**self).add_all_weights(links)
+    }
+
+    fn decay(&self, policy: &DecayPolicy, now: SystemTime) -> Result<Decayed> {
+        (**self).decay(policy, now)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
 }
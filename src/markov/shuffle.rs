@@ -1,3 +1,20 @@
+/// Orders the words of a message a reply is tried to start with.
+///
+/// ```
+/// use markov::markov::Shuffle;
+///
+/// struct Reverse;
+///
+/// impl<T> Shuffle<T> for Reverse {
+///     fn shuffle(&self, slice: &mut [T]) {
+///         slice.reverse();
+///     }
+/// }
+///
+/// let mut words = ["a", "b", "c"];
+/// Reverse.shuffle(&mut words);
+/// assert_eq!(words, ["c", "b", "a"]);
+/// ```
 pub trait Shuffle<T> {
     fn shuffle(&self, slice: &mut [T]);
 }
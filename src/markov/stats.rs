@@ -0,0 +1,142 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::types::Size;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats<T, const N: usize> {
+    pub size: Size,
+    pub total_weight: u64,
+    /// Words ordered by how many times they were seen following some state.
+    pub top_words: Vec<(T, u64)>,
+    /// States ordered by the total weight of their transitions.
+    pub top_states: Vec<StateStats<T, N>>,
+    /// Entropy of the next word in bits, averaged over states weighted by how
+    /// often they occur.
+    pub mean_entropy: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateStats<T, const N: usize> {
+    pub state: [T; N],
+    pub weight: u64,
+    pub entropy: f64,
+}
+
+impl<T, const N: usize> Stats<T, N> {
+    /// Converts every word, e.g. to decode words of a storage backend.
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Stats<U, N> {
+        Stats {
+            size: self.size,
+            total_weight: self.total_weight,
+            top_words: self
+                .top_words
+                .into_iter()
+                .map(|(word, weight)| (f(word), weight))
+                .collect(),
+            top_states: self
+                .top_states
+                .into_iter()
+                .map(|state| StateStats {
+                    state: state.state.map(&f),
+                    weight: state.weight,
+                    entropy: state.entropy,
+                })
+                .collect(),
+            mean_entropy: self.mean_entropy,
+        }
+    }
+
+    pub fn branching_factor(&self) -> f64 {
+        if self.size.states == 0 {
+            return 0.0;
+        }
+        self.size.transitions as f64 / self.size.states as f64
+    }
+}
+
+/// Shannon entropy in bits of a distribution given by its weights.
+pub fn entropy(weights: impl IntoIterator<Item = u32>) -> f64 {
+    let weights: Vec<_> = weights.into_iter().map(f64::from).collect();
+    let total: f64 = weights.iter().sum();
+    if total == 0.0 {
+        return 0.0;
+    }
+    weights
+        .into_iter()
+        .filter(|&weight| weight > 0.0)
+        .map(|weight| {
+            let p = weight / total;
+            p * (1.0 / p).log2()
+        })
+        .sum()
+}
+
+/// Keeps `n` items with the highest weight, heaviest first.
+pub fn top<T>(items: impl IntoIterator<Item = T>, n: usize, weight: impl Fn(&T) -> u64) -> Vec<T> {
+    let mut items: Vec<_> = items.into_iter().collect();
+    items.sort_by_key(|item| std::cmp::Reverse(weight(item)));
+    items.truncate(n);
+    items
+}
+
+impl<T, const N: usize> Display for Stats<T, N>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Words: {}", self.size.words)?;
+        writeln!(f, "States: {}", self.size.states)?;
+        writeln!(f, "Transitions: {}", self.size.transitions)?;
+        writeln!(f, "Total weight: {}", self.total_weight)?;
+        writeln!(f, "Branching factor: {:.2}", self.branching_factor())?;
+        writeln!(f, "Mean entropy: {:.2} bits", self.mean_entropy)?;
+        if !self.top_words.is_empty() {
+            writeln!(f, "Top words:")?;
+            for (word, weight) in &self.top_words {
+                writeln!(f, "  {:>8}  {}", weight, word)?;
+            }
+        }
+        if !self.top_states.is_empty() {
+            writeln!(f, "Top states:")?;
+            for state in &self.top_states {
+                let words: Vec<_> = state.state.iter().map(ToString::to_string).collect();
+                writeln!(
+                    f,
+                    "  {:>8}  {:.2} bits  {}",
+                    state.weight,
+                    state.entropy,
+                    words.join(" ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entropy, top};
+
+    #[test]
+    fn entropy_of_single_outcome_is_zero() {
+        assert_eq!(entropy([7]), 0.0);
+        assert!(entropy([7]).is_sign_positive());
+    }
+
+    #[test]
+    fn entropy_of_uniform_distribution() {
+        assert!((entropy([3, 3, 3, 3]) - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn entropy_of_empty_distribution_is_zero() {
+        assert_eq!(entropy([]), 0.0);
+    }
+
+    #[test]
+    fn top_keeps_heaviest_items() {
+        let items = vec![("a", 1), ("b", 5), ("c", 3)];
+
+        assert_eq!(top(items, 2, |(_, w)| *w), vec![("b", 5), ("c", 3)]);
+    }
+}
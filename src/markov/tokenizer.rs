@@ -0,0 +1,44 @@
+/// Describes how messages are split into tokens and joined back. It is
+/// recorded in persisted chains, because chains built with different
+/// tokenizers are not interchangeable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Tokenizer {
+    Whitespace,
+}
+
+impl Tokenizer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tokenizer::Whitespace => "whitespace",
+        }
+    }
+
+    pub fn tokenize<'a>(&self, text: &'a str) -> impl Iterator<Item = &'a str> {
+        match self {
+            Tokenizer::Whitespace => text.split_whitespace(),
+        }
+    }
+
+    pub fn join(&self, tokens: &[String]) -> String {
+        match self {
+            Tokenizer::Whitespace => tokens.join(" "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tokenizer;
+
+    #[test]
+    fn whitespace_round_trip() {
+        let tokenizer = Tokenizer::Whitespace;
+        let tokens: Vec<_> = tokenizer
+            .tokenize("  hello \t world\n")
+            .map(str::to_string)
+            .collect();
+
+        assert_eq!(tokens, ["hello", "world"]);
+        assert_eq!(tokenizer.join(&tokens), "hello world");
+    }
+}
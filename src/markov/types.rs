@@ -1,4 +1,7 @@
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Link<T, const N: usize> {
@@ -13,3 +16,42 @@ impl<T, const N: usize> Link<T, N> {
 }
 
 pub type WeightMap<T> = HashMap<T, u32>;
+
+/// Token of a chain learned from sequences of `T`, e.g. the words of messages.
+/// Every sequence is preceded by `Start` and followed by `End`, so that
+/// generated sequences begin and end where learned ones did.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum Token<T> {
+    Start,
+    End,
+    Word(T),
+}
+
+impl<T> Token<T> {
+    pub fn word(self) -> Option<T> {
+        match self {
+            Token::Word(word) => Some(word),
+            Token::Start | Token::End => None,
+        }
+    }
+}
+
+impl<T> Display for Token<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Start => write!(f, "<start>"),
+            Token::End => write!(f, "<end>"),
+            Token::Word(word) => word.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Size {
+    pub words: u64,
+    pub states: u64,
+    pub transitions: u64,
+}